@@ -0,0 +1,96 @@
+//! Range scan throughput for several `GenServerParams::lookup_range_buffer`
+//! sizes:
+//!
+//! ```text
+//! cargo bench --features standalone --bench lookup_range
+//! ```
+//!
+//! Every size gets a fresh wheel file filled with `ITEMS_COUNT` entries and a
+//! gen_server started with that buffer; only the scan itself is timed.
+
+use std::{
+    env,
+    fs,
+    process,
+    path::{
+        PathBuf,
+    },
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+use futures::{
+    StreamExt,
+};
+
+use blockwheel_kv_ero::{
+    kv_util::{
+        key_from_slice,
+        value_from_slice,
+    },
+    standalone,
+};
+
+const ITEMS_COUNT: usize = 100_000;
+const INSERT_BATCH_SIZE: usize = 1_000;
+const VALUE_SIZE: usize = 64;
+const BUFFER_SIZES: &[usize] = &[1, 16, 64, 256, 1024];
+
+#[tokio::main]
+async fn main() {
+    for &lookup_range_buffer in BUFFER_SIZES {
+        let wheel_filename = env::temp_dir()
+            .join(format!("blockwheel_kv_ero_bench_{}_{}", process::id(), lookup_range_buffer));
+        let elapsed = run(wheel_filename.clone(), lookup_range_buffer).await;
+        if let Err(error) = fs::remove_file(&wheel_filename) {
+            eprintln!("failed to remove {:?}: {:?}", wheel_filename, error);
+        }
+        println!(
+            "lookup_range_buffer = {:>4}: {} items in {:?}, {:.0} items/s",
+            lookup_range_buffer,
+            ITEMS_COUNT,
+            elapsed,
+            ITEMS_COUNT as f64 / elapsed.as_secs_f64(),
+        );
+    }
+}
+
+async fn run(wheel_filename: PathBuf, lookup_range_buffer: usize) -> Duration {
+    let mut config = standalone::Config::with_wheel_files([wheel_filename]);
+    config.wheels[0].init_wheel_size_bytes = 256 * 1024 * 1024;
+    config.gen_server.lookup_range_buffer = lookup_range_buffer;
+    let node = standalone::Node::start(&config)
+        .expect("failed to start blockwheel_kv");
+    let mut pid = node.pid();
+
+    let value_bytes = vec![0xa5; VALUE_SIZE];
+    for batch_start in (0 .. ITEMS_COUNT).step_by(INSERT_BATCH_SIZE) {
+        let key_values = (batch_start .. ITEMS_COUNT.min(batch_start + INSERT_BATCH_SIZE))
+            .map(|index| (
+                key_from_slice(node.blocks_pool(), format!("key-{:010}", index).as_bytes()),
+                value_from_slice(node.blocks_pool(), &value_bytes),
+            ))
+            .collect();
+        pid.insert_batch(key_values).await
+            .expect("insert_batch failed");
+    }
+    pid.flush_all().await
+        .expect("flush_all failed");
+
+    let now = Instant::now();
+    let mut lookup_range = pid.lookup_range(..).await
+        .expect("lookup_range failed");
+    let mut items_count = 0;
+    while let Some(item) = lookup_range.next().await {
+        item.expect("lookup_range item failed");
+        items_count += 1;
+    }
+    let elapsed = now.elapsed();
+    assert_eq!(items_count, ITEMS_COUNT);
+
+    pid.shutdown().await
+        .expect("shutdown failed");
+    elapsed
+}
@@ -0,0 +1,33 @@
+use std::{
+    env,
+    process,
+};
+
+use blockwheel_kv_ero::{
+    cli,
+};
+
+#[tokio::main]
+async fn main() {
+    let args = match cli::parse_args(env::args().skip(1)) {
+        Ok(args) =>
+            args,
+        Err(error) => {
+            eprintln!("{:?}\n\n{}", error, cli::USAGE);
+            process::exit(2);
+        },
+    };
+
+    match cli::run(args).await {
+        Ok(()) =>
+            (),
+        Err(cli::Error::NotFound) => {
+            eprintln!("not found");
+            process::exit(1);
+        },
+        Err(error) => {
+            eprintln!("{:?}", error);
+            process::exit(1);
+        },
+    }
+}
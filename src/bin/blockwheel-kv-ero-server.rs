@@ -0,0 +1,129 @@
+use std::{
+    env,
+    process,
+};
+
+use futures::{
+    future,
+};
+
+use tokio::{
+    net::{
+        TcpListener,
+    },
+    signal,
+};
+
+use blockwheel_kv::{
+    Flushed,
+};
+
+use blockwheel_kv_ero::{
+    server,
+    standalone,
+};
+
+#[tokio::main]
+async fn main() {
+    let config_path = match env::args_os().nth(1) {
+        Some(config_path) =>
+            config_path,
+        None => {
+            eprintln!("usage: blockwheel-kv-ero-server <config.toml>");
+            process::exit(2);
+        },
+    };
+
+    let config = match standalone::load_config(&config_path) {
+        Ok(config) =>
+            config,
+        Err(error) => {
+            eprintln!("failed to load config: {:?}", error);
+            process::exit(1);
+        },
+    };
+
+    let node = match standalone::Node::start(&config) {
+        Ok(node) =>
+            node,
+        Err(error) => {
+            eprintln!("failed to start blockwheel_kv: {:?}", error);
+            process::exit(1);
+        },
+    };
+
+    #[cfg(feature = "http")]
+    if let Some(http_config) = &config.http {
+        let addr = match http_config.listen_addr.parse() {
+            Ok(addr) =>
+                addr,
+            Err(error) => {
+                eprintln!("invalid http listen address {}: {:?}", http_config.listen_addr, error);
+                process::exit(1);
+            },
+        };
+        let http_server = blockwheel_kv_ero::http::serve(addr, node.pid(), node.blocks_pool().clone());
+        tokio::spawn(async move {
+            if let Err(error) = http_server.await {
+                eprintln!("http gateway terminated: {:?}", error);
+            }
+        });
+    }
+
+    let listener = match TcpListener::bind(&config.server.listen_addr).await {
+        Ok(listener) =>
+            listener,
+        Err(error) => {
+            eprintln!("failed to listen on {}: {:?}", config.server.listen_addr, error);
+            process::exit(1);
+        },
+    };
+
+    tokio::select! {
+        () = server::serve(listener, node.pid(), node.blocks_pool().clone()) =>
+            (),
+        () = shutdown_signal() =>
+            eprintln!("shutting down"),
+    }
+
+    match node.pid().shutdown().await {
+        Ok(Flushed) =>
+            (),
+        Err(error) => {
+            eprintln!("failed to shut down gracefully: {:?}", error);
+            process::exit(1);
+        },
+    }
+}
+
+/// Resolves on Ctrl-C, or on SIGTERM on unix.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(error) = signal::ctrl_c().await {
+            eprintln!("failed to listen for Ctrl-C: {:?}", error);
+            future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match signal::unix::signal(signal::unix::SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                sigterm.recv().await;
+            },
+            Err(error) => {
+                eprintln!("failed to listen for SIGTERM: {:?}", error);
+                future::pending::<()>().await;
+            },
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = future::pending::<()>();
+
+    tokio::select! {
+        () = ctrl_c =>
+            (),
+        () = terminate =>
+            (),
+    }
+}
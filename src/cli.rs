@@ -0,0 +1,404 @@
+//! `bkv`: admin tool working directly on local wheel files.
+
+use std::{
+    ops::{
+        Bound,
+    },
+    path::{
+        PathBuf,
+    },
+};
+
+use futures::{
+    StreamExt,
+};
+
+use serde_json::{
+    json,
+};
+
+use crate::{
+    kv,
+    kv_util,
+    standalone,
+    Pid,
+    InfoError,
+    InsertError,
+    LookupError,
+    LookupRangeError,
+    RemoveError,
+    FlushError,
+    ShutdownError,
+};
+
+pub const USAGE: &str = "\
+usage: bkv (--config <config.toml> | --wheel <path> [--wheel <path> ...]) [--format utf8|hex|json] <command>
+
+commands:
+    get <key>
+    put <key> <value>
+    del <key>
+    scan [<from> [<to>]]     keys from `from` inclusive up to `to` exclusive
+    count [<from> [<to>]]
+    info
+    flush
+
+`--wheel` only opens existing wheel files, use a config to create new ones.
+With `--format hex` keys and values on the command line are hex too. In json
+output keys and values are strings if they are valid UTF-8 and `{\"hex\": ...}`
+objects otherwise.";
+
+#[derive(Debug)]
+pub enum Error {
+    Usage(String),
+    InvalidHex(String),
+    /// A `--wheel` file does not exist.
+    WheelNotFound(PathBuf),
+    NotFound,
+    Standalone(standalone::Error),
+    Info(InfoError),
+    Insert(InsertError),
+    Lookup(LookupError),
+    LookupRange(LookupRangeError),
+    Remove(RemoveError),
+    Flush(FlushError),
+    Shutdown(ShutdownError),
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Format {
+    Utf8,
+    Hex,
+    Json,
+}
+
+#[derive(Clone, Debug)]
+pub enum Command {
+    Get { key: String, },
+    Put { key: String, value: String, },
+    Del { key: String, },
+    Scan { from: Option<String>, to: Option<String>, },
+    Count { from: Option<String>, to: Option<String>, },
+    Info,
+    Flush,
+}
+
+#[derive(Clone, Debug)]
+pub struct Args {
+    pub config: Option<PathBuf>,
+    pub wheels: Vec<PathBuf>,
+    pub format: Format,
+    pub command: Command,
+}
+
+/// Parses command line arguments, without the program name.
+pub fn parse_args<I>(args: I) -> Result<Args, Error> where I: IntoIterator<Item = String> {
+    let mut config = None;
+    let mut wheels = Vec::new();
+    let mut format = Format::Utf8;
+    let mut positional = Vec::new();
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let mut option_value = |name: &str| args.next()
+            .ok_or_else(|| Error::Usage(format!("{} requires a value", name)));
+        match arg.as_str() {
+            "--config" =>
+                config = Some(PathBuf::from(option_value("--config")?)),
+            "--wheel" =>
+                wheels.push(PathBuf::from(option_value("--wheel")?)),
+            "--format" =>
+                format = match option_value("--format")?.as_str() {
+                    "utf8" =>
+                        Format::Utf8,
+                    "hex" =>
+                        Format::Hex,
+                    "json" =>
+                        Format::Json,
+                    other =>
+                        return Err(Error::Usage(format!("unknown format {}", other))),
+                },
+            _ =>
+                positional.push(arg),
+        }
+    }
+
+    match (&config, wheels.is_empty()) {
+        (None, true) =>
+            return Err(Error::Usage("either --config or --wheel is required".to_string())),
+        (Some(..), false) =>
+            return Err(Error::Usage("--config and --wheel cannot be used together".to_string())),
+        _ =>
+            (),
+    }
+
+    let mut positional = positional.into_iter();
+    let command_name = positional.next()
+        .ok_or_else(|| Error::Usage("command is missing".to_string()))?;
+    let mut operand = |name: &str| positional.next()
+        .ok_or_else(|| Error::Usage(format!("{} requires {}", command_name, name)));
+    let command = match command_name.as_str() {
+        "get" =>
+            Command::Get { key: operand("<key>")?, },
+        "put" => {
+            let key = operand("<key>")?;
+            let value = operand("<value>")?;
+            Command::Put { key, value, }
+        },
+        "del" =>
+            Command::Del { key: operand("<key>")?, },
+        "scan" | "count" => {
+            let from = positional.next();
+            let to = positional.next();
+            if command_name == "scan" {
+                Command::Scan { from, to, }
+            } else {
+                Command::Count { from, to, }
+            }
+        },
+        "info" =>
+            Command::Info,
+        "flush" =>
+            Command::Flush,
+        other =>
+            return Err(Error::Usage(format!("unknown command {}", other))),
+    };
+    if let Some(extra) = positional.next() {
+        return Err(Error::Usage(format!("unexpected argument {}", extra)));
+    }
+
+    Ok(Args { config, wheels, format, command, })
+}
+
+/// Opens the wheels, runs the command printing its result to stdout and
+/// shuts the gen_server down, flushing any changes. Must be called within a
+/// tokio runtime.
+pub async fn run(args: Args) -> Result<(), Error> {
+    let config = match &args.config {
+        Some(config_path) =>
+            standalone::load_config(config_path)
+                .map_err(Error::Standalone)?,
+        None => {
+            // a mistyped path would otherwise silently create a new empty wheel
+            if let Some(missing) = args.wheels.iter().find(|wheel| !wheel.is_file()) {
+                return Err(Error::WheelNotFound(missing.clone()));
+            }
+            standalone::Config::with_wheel_files(args.wheels.iter().cloned())
+        },
+    };
+    let node = standalone::Node::start(&config)
+        .map_err(Error::Standalone)?;
+    let mut pid = node.pid();
+
+    let command_result = run_command(&mut pid, &node, args.format, args.command).await;
+    let shutdown_result = pid.shutdown().await
+        .map_err(Error::Shutdown);
+    command_result?;
+    shutdown_result?;
+    Ok(())
+}
+
+async fn run_command(pid: &mut Pid, node: &standalone::Node, format: Format, command: Command) -> Result<(), Error> {
+    let blocks_pool = node.blocks_pool();
+    let parse_bytes = |arg: &str| match format {
+        Format::Hex =>
+            kv_util::hex_decode(arg)
+                .ok_or_else(|| Error::InvalidHex(arg.to_string())),
+        Format::Utf8 | Format::Json =>
+            Ok(arg.as_bytes().to_vec()),
+    };
+    let parse_key = |arg: &str| parse_bytes(arg)
+        .map(|bytes| kv_util::key_from_slice(blocks_pool, &bytes));
+    let parse_range = |from: Option<String>, to: Option<String>| -> Result<(Bound<kv::Key>, Bound<kv::Key>), Error> {
+        let range_from = match from {
+            Some(from) =>
+                Bound::Included(parse_key(&from)?),
+            None =>
+                Bound::Unbounded,
+        };
+        let range_to = match to {
+            Some(to) =>
+                Bound::Excluded(parse_key(&to)?),
+            None =>
+                Bound::Unbounded,
+        };
+        Ok((range_from, range_to))
+    };
+
+    match command {
+        Command::Get { key, } => {
+            let key = parse_key(&key)?;
+            match pid.lookup(key.clone()).await.map_err(Error::Lookup)? {
+                Some(kv::ValueCell { version, cell: kv::Cell::Value(value), }) =>
+                    match format {
+                        Format::Json =>
+                            println!("{}", entry_json(&key, &value, version)),
+                        Format::Utf8 | Format::Hex =>
+                            println!("{}", format_bytes(&value.value_bytes, format)),
+                    },
+                Some(kv::ValueCell { cell: kv::Cell::Tombstone, .. }) | None =>
+                    return Err(Error::NotFound),
+            }
+        },
+        Command::Put { key, value, } => {
+            let key = parse_key(&key)?;
+            let value = kv_util::value_from_slice(blocks_pool, &parse_bytes(&value)?);
+            let inserted = pid.insert(key, value).await
+                .map_err(Error::Insert)?;
+            print_version(inserted.version, format);
+        },
+        Command::Del { key, } => {
+            let key = parse_key(&key)?;
+            let removed = pid.remove(key).await
+                .map_err(Error::Remove)?;
+            print_version(removed.version, format);
+        },
+        Command::Scan { from, to, } => {
+            let mut lookup_range = pid.lookup_range(parse_range(from, to)?).await
+                .map_err(Error::LookupRange)?;
+            while let Some(item) = lookup_range.next().await {
+                let kv::KeyValuePair { key, value_cell, } = item
+                    .map_err(Error::LookupRange)?;
+                let value = match value_cell.cell {
+                    kv::Cell::Value(value) =>
+                        value,
+                    kv::Cell::Tombstone =>
+                        continue,
+                };
+                match format {
+                    Format::Json =>
+                        println!("{}", entry_json(&key, &value, value_cell.version)),
+                    Format::Utf8 | Format::Hex =>
+                        println!(
+                            "{}\t{}",
+                            format_bytes(&key.key_bytes, format),
+                            format_bytes(&value.value_bytes, format),
+                        ),
+                }
+            }
+        },
+        Command::Count { from, to, } => {
+            let count = pid.count_range(parse_range(from, to)?).await
+                .map_err(Error::LookupRange)?;
+            match format {
+                Format::Json =>
+                    println!("{}", json!({ "count": count })),
+                Format::Utf8 | Format::Hex =>
+                    println!("{}", count),
+            }
+        },
+        Command::Info => {
+            let info = pid.info().await
+                .map_err(Error::Info)?;
+            match format {
+                Format::Json =>
+                    println!("{}", json!({ "info": format!("{:?}", info) })),
+                Format::Utf8 | Format::Hex =>
+                    println!("{:#?}", info),
+            }
+        },
+        Command::Flush => {
+            pid.flush_all().await
+                .map_err(Error::Flush)?;
+            match format {
+                Format::Json =>
+                    println!("{}", json!({ "flushed": true })),
+                Format::Utf8 | Format::Hex =>
+                    println!("flushed"),
+            }
+        },
+    }
+    Ok(())
+}
+
+fn print_version(version: u64, format: Format) {
+    match format {
+        Format::Json =>
+            println!("{}", json!({ "version": version })),
+        Format::Utf8 | Format::Hex =>
+            println!("{}", version),
+    }
+}
+
+fn format_bytes(bytes: &[u8], format: Format) -> String {
+    match format {
+        Format::Hex =>
+            kv_util::hex_encode(bytes),
+        Format::Utf8 | Format::Json =>
+            String::from_utf8_lossy(bytes).into_owned(),
+    }
+}
+
+fn json_bytes(bytes: &[u8]) -> serde_json::Value {
+    match std::str::from_utf8(bytes) {
+        Ok(string) =>
+            json!(string),
+        Err(..) =>
+            json!({ "hex": kv_util::hex_encode(bytes) }),
+    }
+}
+
+fn entry_json(key: &kv::Key, value: &kv::Value, version: u64) -> serde_json::Value {
+    json!({
+        "key": json_bytes(&key.key_bytes),
+        "value": json_bytes(&value.value_bytes),
+        "version": version,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        env,
+        process,
+    };
+
+    use super::{
+        parse_args,
+        run,
+        Args,
+        Command,
+        Error,
+        Format,
+    };
+
+    fn parse(args: &[&str]) -> Result<Args, Error> {
+        parse_args(args.iter().map(|arg| arg.to_string()))
+    }
+
+    fn assert_usage_error(args: &[&str]) {
+        let result = parse(args);
+        assert!(matches!(result, Err(Error::Usage(..))), "{:?} is parsed as {:?}", args, result);
+    }
+
+    #[test]
+    fn args_are_parsed() {
+        let args = parse(&["--wheel", "a", "--wheel", "b", "--format", "hex", "scan", "00"]).unwrap();
+        assert_eq!(args.wheels.len(), 2);
+        assert!(args.config.is_none());
+        assert_eq!(args.format, Format::Hex);
+        assert!(matches!(args.command, Command::Scan { from: Some(..), to: None, }));
+    }
+
+    #[test]
+    fn usage_errors() {
+        assert_usage_error(&["get", "key"]);
+        assert_usage_error(&["--config", "bkv.toml", "--wheel", "a", "get", "key"]);
+        assert_usage_error(&["--wheel", "a"]);
+        assert_usage_error(&["--wheel", "a", "frobnicate"]);
+        assert_usage_error(&["--wheel", "a", "put", "key"]);
+        assert_usage_error(&["--wheel", "a", "get", "key", "extra"]);
+        assert_usage_error(&["--wheel", "a", "--format", "xml", "info"]);
+        assert_usage_error(&["--wheel"]);
+    }
+
+    #[tokio::test]
+    async fn missing_wheel_file_is_not_created() {
+        let wheel_filename = env::temp_dir()
+            .join(format!("blockwheel_kv_ero_cli_missing_{}", process::id()));
+        let args = parse(&["--wheel", wheel_filename.to_str().unwrap(), "info"]).unwrap();
+        let result = run(args).await;
+        assert!(matches!(&result, Err(Error::WheelNotFound(path)) if *path == wheel_filename), "{:?}", result);
+        assert!(!wheel_filename.exists());
+    }
+}
@@ -0,0 +1,554 @@
+//! [`RemotePid`]: client for [`crate::server`] mirroring the [`Pid`] API.
+//!
+//! Requests are spread round-robin over a small pool of connections and
+//! pipelined on each of them. A broken connection is reopened on the next
+//! request, and requests failed by connection problems are resent according
+//! to the [`RetryPolicy`].
+
+use std::{
+    io,
+    future::{
+        Future,
+    },
+    collections::{
+        HashMap,
+    },
+    ops::{
+        Bound,
+        RangeBounds,
+    },
+    sync::{
+        atomic::{
+            Ordering,
+            AtomicU64,
+            AtomicUsize,
+        },
+        Arc,
+        Mutex,
+    },
+    time::{
+        Duration,
+    },
+};
+
+use futures::{
+    channel::{
+        mpsc,
+        oneshot,
+    },
+    lock,
+    SinkExt,
+    StreamExt,
+};
+
+use futures_timer::{
+    Delay,
+};
+
+use tokio::{
+    net::{
+        TcpStream,
+    },
+};
+
+use alloc_pool::{
+    bytes::{
+        BytesPool,
+    },
+};
+
+use crate::{
+    kv,
+    kv_util,
+    wire,
+    Inserted,
+    Removed,
+    Flushed,
+    RetryPolicy,
+    KvStore,
+    StoreInfo,
+    BackendError,
+    InfoError,
+    InsertError,
+    LookupError,
+    LookupRange,
+    LookupRangeError,
+    ScanOrder,
+    RemoveError,
+    FlushError,
+    KeyValueStreamItem,
+};
+
+/// Maps a client failure onto any of the [`crate::Pid`] error enums. A
+/// `ReservedKey` reply maps onto the value given, or is unexpected if there is
+/// none.
+macro_rules! into_error {
+    ($error_type:ident, $error:expr, $reserved_key:expr) => {
+        match $error {
+            ClientError::Remote(error) =>
+                $error_type::Remote(error),
+            ClientError::Reply(wire::ErrorReply::GenServer) =>
+                $error_type::GenServer(ero::NoProcError),
+            ClientError::Reply(wire::ErrorReply::Backend(description)) =>
+                $error_type::Backend(BackendError::Remote(description)),
+            ClientError::Reply(wire::ErrorReply::Timeout) =>
+                $error_type::Timeout,
+            ClientError::Reply(wire::ErrorReply::Dropped { attempts, }) =>
+                $error_type::Dropped { attempts: attempts as usize, },
+            ClientError::Reply(wire::ErrorReply::ReservedKey) =>
+                $reserved_key,
+        }
+    };
+    ($error_type:ident, $error:expr) => {
+        into_error!($error_type, $error, $error_type::Remote(RemoteError::UnexpectedResponse))
+    };
+}
+
+/// Connection or protocol failure of a request sent through [`RemotePid`].
+#[derive(Debug)]
+pub enum RemoteError {
+    Connect(io::Error),
+    /// The connection broke before the response arrived.
+    ConnectionLost,
+    UnexpectedResponse,
+}
+
+#[derive(Clone, Debug)]
+pub struct RemotePidParams {
+    /// Number of connections opened to the server.
+    pub pool_size: usize,
+    /// Capacity of the channels carrying range lookup items to the consumer.
+    /// Once they are full the connection is not read any further, which also
+    /// holds up other requests pipelined on it, until the consumer catches up.
+    pub lookup_range_buffer: usize,
+    pub retry_policy: RetryPolicy,
+}
+
+impl Default for RemotePidParams {
+    fn default() -> Self {
+        Self {
+            pool_size: 4,
+            lookup_range_buffer: 64,
+            retry_policy: RetryPolicy {
+                max_attempts: Some(5),
+                backoff_initial: Duration::from_millis(50),
+                backoff_max: Duration::from_secs(2),
+            },
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct RemotePid {
+    shared: Arc<Shared>,
+    retry_policy: RetryPolicy,
+}
+
+struct Shared {
+    addr: String,
+    blocks_pool: BytesPool,
+    lookup_range_buffer: usize,
+    slots: Vec<lock::Mutex<Option<Connection>>>,
+    next_slot: AtomicUsize,
+    next_request_id: AtomicU64,
+}
+
+enum ClientError {
+    Remote(RemoteError),
+    Reply(wire::ErrorReply),
+}
+
+impl RemotePid {
+    /// Connections are opened lazily by the first requests.
+    pub fn new<A>(addr: A, blocks_pool: BytesPool) -> RemotePid where A: Into<String> {
+        Self::with_params(addr, RemotePidParams::default(), blocks_pool)
+    }
+
+    pub fn with_params<A>(addr: A, params: RemotePidParams, blocks_pool: BytesPool) -> RemotePid where A: Into<String> {
+        let slots = (0 .. params.pool_size.max(1))
+            .map(|_| lock::Mutex::new(None))
+            .collect();
+        RemotePid {
+            shared: Arc::new(Shared {
+                addr: addr.into(),
+                blocks_pool,
+                lookup_range_buffer: params.lookup_range_buffer,
+                slots,
+                next_slot: AtomicUsize::new(0),
+                next_request_id: AtomicU64::new(0),
+            }),
+            retry_policy: params.retry_policy,
+        }
+    }
+
+    pub fn retry_policy(&self) -> &RetryPolicy {
+        &self.retry_policy
+    }
+
+    pub fn set_retry_policy(&mut self, retry_policy: RetryPolicy) {
+        self.retry_policy = retry_policy;
+    }
+
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> RemotePid {
+        self.set_retry_policy(retry_policy);
+        self
+    }
+
+    /// Unlike [`crate::Pid::info`] returns a [`StoreInfo`], since
+    /// `blockwheel_kv::Info` cannot be rebuilt outside of `blockwheel_kv`.
+    pub async fn info(&mut self) -> Result<StoreInfo, InfoError> {
+        match self.request(wire::Request::Info).await {
+            Ok(wire::Response::Info { description, }) =>
+                Ok(StoreInfo { description, }),
+            Ok(..) =>
+                Err(InfoError::Remote(RemoteError::UnexpectedResponse)),
+            Err(error) =>
+                Err(into_error!(InfoError, error)),
+        }
+    }
+
+    pub async fn insert(&mut self, key: kv::Key, value: kv::Value) -> Result<Inserted, InsertError> {
+        let request = wire::Request::Insert {
+            key: key.key_bytes.to_vec(),
+            value: value.value_bytes.to_vec(),
+        };
+        match self.request(request).await {
+            Ok(wire::Response::Inserted { version, }) =>
+                Ok(Inserted { version, }),
+            Ok(..) =>
+                Err(InsertError::Remote(RemoteError::UnexpectedResponse)),
+            Err(error) =>
+                Err(into_error!(InsertError, error, InsertError::ReservedKey)),
+        }
+    }
+
+    pub async fn lookup(&mut self, key: kv::Key) -> Result<Option<kv::ValueCell<kv::Value>>, LookupError> {
+        match self.request(wire::Request::Lookup { key: key.key_bytes.to_vec(), }).await {
+            Ok(wire::Response::Lookup { cell, }) =>
+                Ok(cell.map(|cell| self.shared.value_cell(cell))),
+            Ok(..) =>
+                Err(LookupError::Remote(RemoteError::UnexpectedResponse)),
+            Err(error) =>
+                Err(into_error!(LookupError, error)),
+        }
+    }
+
+    /// Items are received in the background and handed over through the same
+    /// [`LookupRange`] stream [`crate::Pid::lookup_range`] returns; an error
+    /// in the middle of the range terminates it with
+    /// [`LookupRangeError::GenServer`].
+    pub async fn lookup_range<R>(&mut self, range: R) -> Result<LookupRange, LookupRangeError> where R: RangeBounds<kv::Key> {
+        let request = wire::Request::LookupRange {
+            range_from: wire_bound(range.start_bound()),
+            range_to: wire_bound(range.end_bound()),
+        };
+        let mut responses_rx = self.request_stream(request).await
+            .map_err(|error| into_error!(LookupRangeError, error))?;
+
+        // the first frame tells whether the lookup has started at all
+        let first_response = match responses_rx.next().await {
+            Some(wire::Response::Error(error_reply)) =>
+                return Err(into_error!(LookupRangeError, ClientError::Reply(error_reply))),
+            Some(response) =>
+                response,
+            None =>
+                return Err(LookupRangeError::Remote(RemoteError::ConnectionLost)),
+        };
+
+        let (mut key_values_tx, key_values_rx) = mpsc::channel(self.shared.lookup_range_buffer);
+        let shared = self.shared.clone();
+        tokio::spawn(async move {
+            let mut maybe_response = Some(first_response);
+            while let Some(response) = maybe_response {
+                let item = match response {
+                    wire::Response::RangeItem { key, cell, } =>
+                        KeyValueStreamItem::KeyValue(kv::KeyValuePair {
+                            key: kv_util::key_from_slice(&shared.blocks_pool, &key),
+                            value_cell: shared.value_cell(cell),
+                        }),
+                    wire::Response::RangeEnd =>
+                        KeyValueStreamItem::NoMore,
+                    other => {
+                        // dropping the sender terminates the stream with an error
+                        log::warn!("lookup range interrupted by {:?}", other);
+                        return;
+                    },
+                };
+                let no_more = matches!(item, KeyValueStreamItem::NoMore);
+                if key_values_tx.send(item).await.is_err() || no_more {
+                    return;
+                }
+                maybe_response = responses_rx.next().await;
+            }
+        });
+
+        Ok(LookupRange::new(key_values_rx, None, ScanOrder::Ascending))
+    }
+
+    pub async fn remove(&mut self, key: kv::Key) -> Result<Removed, RemoveError> {
+        match self.request(wire::Request::Remove { key: key.key_bytes.to_vec(), }).await {
+            Ok(wire::Response::Removed { version, }) =>
+                Ok(Removed { version, }),
+            Ok(..) =>
+                Err(RemoveError::Remote(RemoteError::UnexpectedResponse)),
+            Err(error) =>
+                Err(into_error!(RemoveError, error, RemoveError::ReservedKey)),
+        }
+    }
+
+    pub async fn flush_all(&mut self) -> Result<Flushed, FlushError> {
+        match self.request(wire::Request::FlushAll).await {
+            Ok(wire::Response::Flushed) =>
+                Ok(Flushed),
+            Ok(..) =>
+                Err(FlushError::Remote(RemoteError::UnexpectedResponse)),
+            Err(error) =>
+                Err(into_error!(FlushError, error)),
+        }
+    }
+
+    async fn request(&mut self, request: wire::Request) -> Result<wire::Response, ClientError> {
+        let shared = self.shared.clone();
+        let response = self
+            .with_retries(|| {
+                let shared = shared.clone();
+                let request = request.clone();
+                async move {
+                    let (reply_tx, reply_rx) = oneshot::channel();
+                    shared.send(request, ReplyTx::Single(reply_tx)).await?;
+                    reply_rx.await
+                        .map_err(|oneshot::Canceled| RemoteError::ConnectionLost)
+                }
+            })
+            .await?;
+        match response {
+            wire::Response::Error(error_reply) =>
+                Err(ClientError::Reply(error_reply)),
+            response =>
+                Ok(response),
+        }
+    }
+
+    /// Streams are only resent if they fail before being sent out.
+    async fn request_stream(&mut self, request: wire::Request) -> Result<mpsc::Receiver<wire::Response>, ClientError> {
+        let shared = self.shared.clone();
+        self
+            .with_retries(|| {
+                let shared = shared.clone();
+                let request = request.clone();
+                async move {
+                    let (responses_tx, responses_rx) = mpsc::channel(shared.lookup_range_buffer);
+                    shared.send(request, ReplyTx::Stream(responses_tx)).await?;
+                    Ok(responses_rx)
+                }
+            })
+            .await
+    }
+
+    async fn with_retries<T, F, R>(&self, mut attempt: F) -> Result<T, ClientError>
+    where F: FnMut() -> R,
+          R: std::future::Future<Output = Result<T, RemoteError>>,
+    {
+        let mut attempts = 0;
+        let mut backoff = self.retry_policy.backoff_initial.min(self.retry_policy.backoff_max);
+        loop {
+            attempts += 1;
+            let error = match attempt().await {
+                Ok(value) =>
+                    return Ok(value),
+                Err(error) =>
+                    error,
+            };
+            log::debug!("request attempt {} failed: {:?}", attempts, error);
+            if let Some(max_attempts) = self.retry_policy.max_attempts {
+                if attempts >= max_attempts {
+                    return Err(ClientError::Remote(error));
+                }
+            }
+            if !backoff.is_zero() {
+                Delay::new(backoff).await;
+                backoff = backoff.saturating_mul(2).min(self.retry_policy.backoff_max);
+            }
+        }
+    }
+}
+
+impl Shared {
+    async fn send(&self, request: wire::Request, reply_tx: ReplyTx) -> Result<(), RemoteError> {
+        let connection = self.connection().await?;
+        let request_id = self.next_request_id.fetch_add(1, Ordering::Relaxed);
+        {
+            let mut pending = connection.pending.lock().unwrap();
+            if pending.closed {
+                return Err(RemoteError::ConnectionLost);
+            }
+            pending.replies.insert(request_id, reply_tx);
+        }
+        let payload = wire::RequestFrame { request_id, request, }.encode();
+        if let Err(_send_error) = connection.frames_tx.unbounded_send(payload) {
+            connection.pending.lock().unwrap().replies.remove(&request_id);
+            return Err(RemoteError::ConnectionLost);
+        }
+        Ok(())
+    }
+
+    async fn connection(&self) -> Result<Connection, RemoteError> {
+        let slot_index = self.next_slot.fetch_add(1, Ordering::Relaxed) % self.slots.len();
+        let mut slot = self.slots[slot_index].lock().await;
+        if let Some(connection) = &*slot {
+            if connection.is_alive() {
+                return Ok(connection.clone());
+            }
+            log::debug!("connection {} to {} is broken, reconnecting", slot_index, self.addr);
+        }
+        let connection = Connection::open(&self.addr).await
+            .map_err(RemoteError::Connect)?;
+        *slot = Some(connection.clone());
+        Ok(connection)
+    }
+
+    fn value_cell(&self, cell: wire::Cell) -> kv::ValueCell<kv::Value> {
+        kv::ValueCell {
+            version: cell.version,
+            cell: match cell.value {
+                Some(value) =>
+                    kv::Cell::Value(kv_util::value_from_slice(&self.blocks_pool, &value)),
+                None =>
+                    kv::Cell::Tombstone,
+            },
+        }
+    }
+}
+
+enum ReplyTx {
+    Single(oneshot::Sender<wire::Response>),
+    /// Bounded, so the connection reader waits for a slow consumer instead of
+    /// buffering the rest of the range.
+    Stream(mpsc::Sender<wire::Response>),
+}
+
+#[derive(Default)]
+struct Pending {
+    replies: HashMap<u64, ReplyTx>,
+    closed: bool,
+}
+
+#[derive(Clone)]
+struct Connection {
+    frames_tx: mpsc::UnboundedSender<Vec<u8>>,
+    pending: Arc<Mutex<Pending>>,
+}
+
+impl Connection {
+    async fn open(addr: &str) -> std::io::Result<Connection> {
+        let stream = TcpStream::connect(addr).await?;
+        stream.set_nodelay(true)?;
+        let (mut reader, mut writer) = stream.into_split();
+        let (frames_tx, mut frames_rx) = mpsc::unbounded::<Vec<u8>>();
+        let pending = Arc::new(Mutex::new(Pending::default()));
+
+        tokio::spawn(async move {
+            while let Some(payload) = frames_rx.next().await {
+                if let Err(error) = wire::write_frame(&mut writer, &payload).await {
+                    log::warn!("failed to write request frame: {:?}", error);
+                    break;
+                }
+            }
+        });
+
+        let reader_pending = pending.clone();
+        tokio::spawn(async move {
+            loop {
+                let payload = match wire::read_frame(&mut reader).await {
+                    Ok(Some(payload)) =>
+                        payload,
+                    Ok(None) =>
+                        break,
+                    Err(error) => {
+                        log::warn!("failed to read response frame: {:?}", error);
+                        break;
+                    },
+                };
+                match wire::ResponseFrame::decode(&payload) {
+                    Ok(response_frame) =>
+                        dispatch(&reader_pending, response_frame).await,
+                    Err(error) => {
+                        log::warn!("malformed response frame, closing connection: {:?}", error);
+                        break;
+                    },
+                }
+            }
+            // dropping pending replies wakes up everybody waiting on this connection
+            let mut pending = reader_pending.lock().unwrap();
+            pending.closed = true;
+            pending.replies.clear();
+        });
+
+        Ok(Connection { frames_tx, pending, })
+    }
+
+    fn is_alive(&self) -> bool {
+        !self.frames_tx.is_closed() && !self.pending.lock().unwrap().closed
+    }
+}
+
+/// Hands a response over to its request. Waits while a stream consumer is
+/// behind, with the pending replies unlocked so requests may still be sent.
+async fn dispatch(pending: &Mutex<Pending>, response_frame: wire::ResponseFrame) {
+    let wire::ResponseFrame { request_id, response, } = response_frame;
+    let maybe_reply_tx = pending.lock().unwrap().replies.remove(&request_id);
+    match maybe_reply_tx {
+        Some(ReplyTx::Single(reply_tx)) =>
+            if let Err(_send_error) = reply_tx.send(response) {
+                log::debug!("client is gone before response {} arrived", request_id);
+            },
+        Some(ReplyTx::Stream(mut responses_tx)) => {
+            let is_last = matches!(response, wire::Response::RangeEnd | wire::Response::Error(..));
+            if responses_tx.send(response).await.is_ok() && !is_last {
+                pending.lock().unwrap().replies.insert(request_id, ReplyTx::Stream(responses_tx));
+            }
+        },
+        None =>
+            log::warn!("response for unknown request id {}", request_id),
+    }
+}
+
+impl KvStore for RemotePid {
+    fn info(&mut self) -> impl Future<Output = Result<StoreInfo, InfoError>> + Send {
+        RemotePid::info(self)
+    }
+
+    fn insert(&mut self, key: kv::Key, value: kv::Value) -> impl Future<Output = Result<Inserted, InsertError>> + Send {
+        RemotePid::insert(self, key, value)
+    }
+
+    fn lookup(&mut self, key: kv::Key) -> impl Future<Output = Result<Option<kv::ValueCell<kv::Value>>, LookupError>> + Send {
+        RemotePid::lookup(self, key)
+    }
+
+    fn lookup_range<R>(&mut self, range: R) -> impl Future<Output = Result<LookupRange, LookupRangeError>> + Send
+    where R: RangeBounds<kv::Key>
+    {
+        // owned bounds, so the future does not hold `range` which may not be `Send`
+        RemotePid::lookup_range(self, (range.start_bound().cloned(), range.end_bound().cloned()))
+    }
+
+    fn remove(&mut self, key: kv::Key) -> impl Future<Output = Result<Removed, RemoveError>> + Send {
+        RemotePid::remove(self, key)
+    }
+
+    fn flush_all(&mut self) -> impl Future<Output = Result<Flushed, FlushError>> + Send {
+        RemotePid::flush_all(self)
+    }
+}
+
+fn wire_bound(bound: Bound<&kv::Key>) -> Bound<Vec<u8>> {
+    match bound {
+        Bound::Included(key) =>
+            Bound::Included(key.key_bytes.to_vec()),
+        Bound::Excluded(key) =>
+            Bound::Excluded(key.key_bytes.to_vec()),
+        Bound::Unbounded =>
+            Bound::Unbounded,
+    }
+}
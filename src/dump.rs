@@ -0,0 +1,497 @@
+//! Logical backup format written by [`Pid::export`] and loaded by
+//! [`Pid::import`].
+//!
+//! Layout, all integers big-endian:
+//!
+//! * header: magic `BKVDUMP\0`, `u16` format version, `u8` flags (bit 0 set
+//!   if blocks are deflate compressed)
+//! * any number of entry blocks: tag `1`, `u32` entries count, `u32`
+//!   uncompressed payload length, `u32` stored payload length, `u32` crc32
+//!   of the uncompressed payload, then the stored payload
+//! * trailer: tag `0`, `u64` total entries count
+//!
+//! An uncompressed payload is a sequence of entries, each a `u32` key length,
+//! the key, a `u64` version, a `u32` value length and the value. Versions are
+//! informational: imported entries get new versions. Tombstones are not
+//! exported.
+
+use std::{
+    io,
+    ops::{
+        RangeBounds,
+    },
+};
+
+use futures::{
+    io::{
+        AsyncRead,
+        AsyncWrite,
+        AsyncReadExt,
+        AsyncWriteExt,
+    },
+    StreamExt,
+};
+
+use alloc_pool::{
+    bytes::{
+        BytesPool,
+    },
+};
+
+use crate::{
+    kv,
+    kv_util,
+    Pid,
+    InsertError,
+    LookupRangeError,
+};
+
+pub const MAGIC: &[u8; 8] = b"BKVDUMP\0";
+pub const FORMAT_VERSION: u16 = 1;
+
+const FLAG_DEFLATE: u8 = 0x01;
+const TAG_TRAILER: u8 = 0;
+const TAG_BLOCK: u8 = 1;
+
+/// Uncompressed payloads bigger than this are never written, and rejected as
+/// corrupted on import, so a block held in memory stays small.
+pub const MAX_PAYLOAD_SIZE: usize = 16 * 1024 * 1024;
+
+/// Entries submitted by a single [`Pid::insert_batch`] on import. Each batch
+/// is written twice, once as its intent, so it is kept well below a block.
+pub const IMPORT_BATCH_ENTRIES: usize = 1024;
+
+/// Deflate may grow an incompressible payload a little.
+const MAX_STORED_SIZE: usize = MAX_PAYLOAD_SIZE + MAX_PAYLOAD_SIZE / 64;
+
+/// Key length, version and value length.
+const MIN_ENTRY_SIZE: usize = 4 + 8 + 4;
+
+#[derive(Clone, Debug)]
+pub struct ExportOptions {
+    /// Deflate compress blocks, requires the `compression` feature.
+    pub compress: bool,
+    /// Uncompressed payload size after which a block is written out, from 1
+    /// up to [`MAX_PAYLOAD_SIZE`].
+    pub block_size_bytes: usize,
+}
+
+impl Default for ExportOptions {
+    fn default() -> Self {
+        Self {
+            compress: false,
+            block_size_bytes: 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ExportError {
+    LookupRange(LookupRangeError),
+    Write(io::Error),
+    CompressionUnsupported,
+    InvalidBlockSize(usize),
+    /// An entry does not fit in a block of [`MAX_PAYLOAD_SIZE`].
+    EntryTooLarge { entry_size: usize, },
+}
+
+#[derive(Debug)]
+pub enum ImportError {
+    Read(io::Error),
+    InvalidMagic,
+    UnsupportedVersion(u16),
+    UnknownBlockTag(u8),
+    CompressionUnsupported,
+    Decompress(io::Error),
+    ChecksumMismatch { block_index: usize, },
+    CorruptedBlock { block_index: usize, },
+    EntriesCountMismatch { expected: u64, imported: u64, },
+    Insert(InsertError),
+}
+
+pub(crate) async fn export<R, W>(pid: &mut Pid, range: R, mut writer: W, options: ExportOptions) -> Result<u64, ExportError>
+where R: RangeBounds<kv::Key>,
+      W: AsyncWrite + Unpin,
+{
+    if options.compress && !cfg!(feature = "compression") {
+        return Err(ExportError::CompressionUnsupported);
+    }
+    if options.block_size_bytes == 0 || options.block_size_bytes > MAX_PAYLOAD_SIZE {
+        return Err(ExportError::InvalidBlockSize(options.block_size_bytes));
+    }
+
+    let mut header = Vec::with_capacity(MAGIC.len() + 3);
+    header.extend_from_slice(MAGIC);
+    header.extend_from_slice(&FORMAT_VERSION.to_be_bytes());
+    header.push(if options.compress { FLAG_DEFLATE } else { 0 });
+    writer.write_all(&header).await
+        .map_err(ExportError::Write)?;
+
+    let mut lookup_range = pid.lookup_range(range).await
+        .map_err(ExportError::LookupRange)?;
+    let mut payload = Vec::new();
+    let mut block_entries: u32 = 0;
+    let mut total_entries: u64 = 0;
+    while let Some(item) = lookup_range.next().await {
+        let kv::KeyValuePair { key, value_cell, } = item
+            .map_err(ExportError::LookupRange)?;
+        let value = match value_cell.cell {
+            kv::Cell::Value(value) =>
+                value,
+            kv::Cell::Tombstone =>
+                continue,
+        };
+        let entry_size = MIN_ENTRY_SIZE + key.key_bytes.len() + value.value_bytes.len();
+        if entry_size > MAX_PAYLOAD_SIZE {
+            return Err(ExportError::EntryTooLarge { entry_size, });
+        }
+        if payload.len() + entry_size > MAX_PAYLOAD_SIZE {
+            write_block(&mut writer, &payload, block_entries, options.compress).await?;
+            payload.clear();
+            block_entries = 0;
+        }
+        put_bytes(&mut payload, &key.key_bytes);
+        payload.extend_from_slice(&value_cell.version.to_be_bytes());
+        put_bytes(&mut payload, &value.value_bytes);
+        block_entries += 1;
+        total_entries += 1;
+        if payload.len() >= options.block_size_bytes {
+            write_block(&mut writer, &payload, block_entries, options.compress).await?;
+            payload.clear();
+            block_entries = 0;
+        }
+    }
+    if block_entries > 0 {
+        write_block(&mut writer, &payload, block_entries, options.compress).await?;
+    }
+
+    let mut trailer = vec![TAG_TRAILER];
+    trailer.extend_from_slice(&total_entries.to_be_bytes());
+    writer.write_all(&trailer).await
+        .map_err(ExportError::Write)?;
+    writer.flush().await
+        .map_err(ExportError::Write)?;
+    Ok(total_entries)
+}
+
+async fn write_block<W>(writer: &mut W, payload: &[u8], entries: u32, compress: bool) -> Result<(), ExportError> where W: AsyncWrite + Unpin {
+    let stored = if compress {
+        deflate(payload).map_err(ExportError::Write)?
+    } else {
+        payload.to_vec()
+    };
+    let mut block_header = vec![TAG_BLOCK];
+    block_header.extend_from_slice(&entries.to_be_bytes());
+    block_header.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    block_header.extend_from_slice(&(stored.len() as u32).to_be_bytes());
+    block_header.extend_from_slice(&crc32(payload).to_be_bytes());
+    writer.write_all(&block_header).await
+        .map_err(ExportError::Write)?;
+    writer.write_all(&stored).await
+        .map_err(ExportError::Write)
+}
+
+/// Every block is decoded as a whole first, then submitted with one
+/// [`Pid::insert_batch`] per [`IMPORT_BATCH_ENTRIES`] entries.
+pub(crate) async fn import<R>(pid: &mut Pid, reader: R, blocks_pool: &BytesPool) -> Result<u64, ImportError> where R: AsyncRead + Unpin {
+    let mut blocks = BlockReader::open(reader).await?;
+    while let Some(block) = blocks.next_block().await? {
+        let mut key_values = Vec::with_capacity(block.entries);
+        decode_block(&block, |key_bytes, value_bytes| key_values.push((
+            kv_util::key_from_slice(blocks_pool, key_bytes),
+            kv_util::value_from_slice(blocks_pool, value_bytes),
+        )))?;
+        let mut key_values = key_values.into_iter();
+        loop {
+            let chunk: Vec<_> = key_values.by_ref().take(IMPORT_BATCH_ENTRIES).collect();
+            if chunk.is_empty() {
+                break;
+            }
+            pid.insert_batch(chunk).await
+                .map_err(ImportError::Insert)?;
+        }
+    }
+    Ok(blocks.entries_read)
+}
+
+/// Reads the whole dump checking everything [`Pid::import`] checks, without
+/// importing anything, and returns how many entries it holds. Run it before
+/// an import to make sure the dump is not going to be restored partially.
+pub async fn verify<R>(reader: R) -> Result<u64, ImportError> where R: AsyncRead + Unpin {
+    let mut blocks = BlockReader::open(reader).await?;
+    while let Some(block) = blocks.next_block().await? {
+        decode_block(&block, |_key_bytes, _value_bytes| ())?;
+    }
+    Ok(blocks.entries_read)
+}
+
+/// Block with its checksum verified, entries are not decoded yet.
+struct Block {
+    index: usize,
+    entries: usize,
+    payload: Vec<u8>,
+}
+
+struct BlockReader<R> {
+    reader: R,
+    compressed: bool,
+    blocks_read: usize,
+    entries_read: u64,
+}
+
+impl<R> BlockReader<R> where R: AsyncRead + Unpin {
+    async fn open(mut reader: R) -> Result<BlockReader<R>, ImportError> {
+        let mut header = [0; 11];
+        reader.read_exact(&mut header).await
+            .map_err(ImportError::Read)?;
+        if &header[.. 8] != MAGIC {
+            return Err(ImportError::InvalidMagic);
+        }
+        let format_version = u16::from_be_bytes([header[8], header[9]]);
+        if format_version != FORMAT_VERSION {
+            return Err(ImportError::UnsupportedVersion(format_version));
+        }
+        let compressed = header[10] & FLAG_DEFLATE != 0;
+        if compressed && !cfg!(feature = "compression") {
+            return Err(ImportError::CompressionUnsupported);
+        }
+        Ok(BlockReader { reader, compressed, blocks_read: 0, entries_read: 0, })
+    }
+
+    /// Returns `None` once the trailer is reached and matches the entries read.
+    async fn next_block(&mut self) -> Result<Option<Block>, ImportError> {
+        let block_index = self.blocks_read;
+        let mut tag = [0; 1];
+        self.reader.read_exact(&mut tag).await
+            .map_err(ImportError::Read)?;
+        match tag[0] {
+            TAG_TRAILER => {
+                let mut total_entries = [0; 8];
+                self.reader.read_exact(&mut total_entries).await
+                    .map_err(ImportError::Read)?;
+                let expected = u64::from_be_bytes(total_entries);
+                if expected != self.entries_read {
+                    return Err(ImportError::EntriesCountMismatch { expected, imported: self.entries_read, });
+                }
+                return Ok(None);
+            },
+            TAG_BLOCK =>
+                (),
+            tag =>
+                return Err(ImportError::UnknownBlockTag(tag)),
+        }
+
+        let mut block_header = [0; 16];
+        self.reader.read_exact(&mut block_header).await
+            .map_err(ImportError::Read)?;
+        let field = |offset: usize| u32::from_be_bytes(block_header[offset .. offset + 4].try_into().unwrap());
+        let entries = field(0) as usize;
+        let payload_len = field(4) as usize;
+        let stored_len = field(8) as usize;
+        let checksum = field(12);
+        // the header is not covered by the checksum, so check it before allocating anything
+        if payload_len > MAX_PAYLOAD_SIZE || stored_len > MAX_STORED_SIZE || entries > payload_len / MIN_ENTRY_SIZE {
+            return Err(ImportError::CorruptedBlock { block_index, });
+        }
+
+        let mut stored = vec![0; stored_len];
+        self.reader.read_exact(&mut stored).await
+            .map_err(ImportError::Read)?;
+        let payload = if self.compressed {
+            inflate(&stored, payload_len)
+                .map_err(ImportError::Decompress)?
+        } else {
+            stored
+        };
+        if payload.len() != payload_len || crc32(&payload) != checksum {
+            return Err(ImportError::ChecksumMismatch { block_index, });
+        }
+
+        self.blocks_read += 1;
+        self.entries_read += entries as u64;
+        Ok(Some(Block { index: block_index, entries, payload, }))
+    }
+}
+
+/// Calls `entry` with the key and value of every entry of `block`.
+fn decode_block<F>(block: &Block, mut entry: F) -> Result<(), ImportError> where F: FnMut(&[u8], &[u8]) {
+    fn take<'a>(input: &mut &'a [u8], length: usize) -> Option<&'a [u8]> {
+        if input.len() < length {
+            return None;
+        }
+        let (head, tail) = input.split_at(length);
+        *input = tail;
+        Some(head)
+    }
+
+    fn take_bytes<'a>(input: &mut &'a [u8]) -> Option<&'a [u8]> {
+        let length = u32::from_be_bytes(take(input, 4)?.try_into().ok()?) as usize;
+        take(input, length)
+    }
+
+    fn take_entry<'a>(input: &mut &'a [u8]) -> Option<(&'a [u8], &'a [u8])> {
+        let key_bytes = take_bytes(input)?;
+        let _version = take(input, 8)?;
+        let value_bytes = take_bytes(input)?;
+        Some((key_bytes, value_bytes))
+    }
+
+    let mut payload = &block.payload[..];
+    for _ in 0 .. block.entries {
+        let (key_bytes, value_bytes) = take_entry(&mut payload)
+            .ok_or(ImportError::CorruptedBlock { block_index: block.index, })?;
+        entry(key_bytes, value_bytes);
+    }
+    if !payload.is_empty() {
+        return Err(ImportError::CorruptedBlock { block_index: block.index, });
+    }
+    Ok(())
+}
+
+fn put_bytes(payload: &mut Vec<u8>, bytes: &[u8]) {
+    payload.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+    payload.extend_from_slice(bytes);
+}
+
+#[cfg(feature = "compression")]
+fn deflate(payload: &[u8]) -> io::Result<Vec<u8>> {
+    use std::io::Write;
+
+    let mut encoder = flate2::write::DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(payload)?;
+    encoder.finish()
+}
+
+#[cfg(not(feature = "compression"))]
+fn deflate(_payload: &[u8]) -> io::Result<Vec<u8>> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "built without the compression feature"))
+}
+
+#[cfg(feature = "compression")]
+fn inflate(stored: &[u8], payload_len: usize) -> io::Result<Vec<u8>> {
+    use std::io::Read;
+
+    let mut payload = Vec::with_capacity(payload_len);
+    flate2::read::DeflateDecoder::new(stored)
+        .take(payload_len as u64 + 1)
+        .read_to_end(&mut payload)?;
+    Ok(payload)
+}
+
+#[cfg(not(feature = "compression"))]
+fn inflate(_stored: &[u8], _payload_len: usize) -> io::Result<Vec<u8>> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "built without the compression feature"))
+}
+
+/// CRC-32 (IEEE 802.3) as used by zlib and gzip.
+fn crc32(bytes: &[u8]) -> u32 {
+    const TABLE: [u32; 256] = {
+        let mut table = [0; 256];
+        let mut index = 0;
+        while index < 256 {
+            let mut crc = index as u32;
+            let mut bit = 0;
+            while bit < 8 {
+                crc = if crc & 1 != 0 { 0xedb88320 ^ (crc >> 1) } else { crc >> 1 };
+                bit += 1;
+            }
+            table[index] = crc;
+            index += 1;
+        }
+        table
+    };
+
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc = TABLE[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        crc32,
+        put_bytes,
+        verify,
+        write_block,
+        ImportError,
+        FORMAT_VERSION,
+        MAGIC,
+        MAX_PAYLOAD_SIZE,
+        TAG_TRAILER,
+    };
+
+    fn block_payload(entries: &[(&str, &str)]) -> Vec<u8> {
+        let mut payload = Vec::new();
+        for (version, (key, value)) in entries.iter().enumerate() {
+            put_bytes(&mut payload, key.as_bytes());
+            payload.extend_from_slice(&(version as u64).to_be_bytes());
+            put_bytes(&mut payload, value.as_bytes());
+        }
+        payload
+    }
+
+    async fn make_dump(blocks: &[&[(&str, &str)]], total_entries: u64) -> Vec<u8> {
+        let mut dump = MAGIC.to_vec();
+        dump.extend_from_slice(&FORMAT_VERSION.to_be_bytes());
+        dump.push(0);
+        for entries in blocks {
+            write_block(&mut dump, &block_payload(entries), entries.len() as u32, false).await.unwrap();
+        }
+        dump.push(TAG_TRAILER);
+        dump.extend_from_slice(&total_entries.to_be_bytes());
+        dump
+    }
+
+    #[test]
+    fn crc32_matches_reference_values() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"a"), 0xe8b7be43);
+        assert_eq!(crc32(b"123456789"), 0xcbf43926);
+        assert_eq!(crc32(b"The quick brown fox jumps over the lazy dog"), 0x414fa339);
+    }
+
+    #[tokio::test]
+    async fn verify_counts_entries() {
+        let dump = make_dump(&[&[("a", "1"), ("b", "")], &[("", "3")]], 3).await;
+        assert!(matches!(verify(&dump[..]).await, Ok(3)));
+
+        let empty = make_dump(&[], 0).await;
+        assert!(matches!(verify(&empty[..]).await, Ok(0)));
+    }
+
+    #[tokio::test]
+    async fn verify_rejects_damaged_dumps() {
+        let dump = make_dump(&[&[("a", "1"), ("b", "2")]], 2).await;
+
+        let mut invalid_magic = dump.clone();
+        invalid_magic[0] = b'X';
+        assert!(matches!(verify(&invalid_magic[..]).await, Err(ImportError::InvalidMagic)));
+
+        assert!(matches!(verify(&dump[.. dump.len() - 1]).await, Err(ImportError::Read(..))));
+
+        // entries count in the block header is not covered by the checksum
+        let mut huge_entries = dump.clone();
+        huge_entries[12 .. 16].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert!(matches!(verify(&huge_entries[..]).await, Err(ImportError::CorruptedBlock { block_index: 0, })));
+
+        let mut oversized_payload = dump.clone();
+        oversized_payload[16 .. 20].copy_from_slice(&(MAX_PAYLOAD_SIZE as u32 + 1).to_be_bytes());
+        assert!(matches!(verify(&oversized_payload[..]).await, Err(ImportError::CorruptedBlock { block_index: 0, })));
+
+        let mut fewer_entries = dump.clone();
+        fewer_entries[12 .. 16].copy_from_slice(&1_u32.to_be_bytes());
+        assert!(matches!(verify(&fewer_entries[..]).await, Err(ImportError::CorruptedBlock { block_index: 0, })));
+
+        let mut flipped_payload = dump.clone();
+        flipped_payload[30] ^= 0x01;
+        assert!(matches!(verify(&flipped_payload[..]).await, Err(ImportError::ChecksumMismatch { block_index: 0, })));
+
+        let wrong_total = make_dump(&[&[("a", "1")]], 2).await;
+        assert!(matches!(
+            verify(&wrong_total[..]).await,
+            Err(ImportError::EntriesCountMismatch { expected: 2, imported: 1, }),
+        ));
+    }
+}
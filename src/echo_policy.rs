@@ -11,8 +11,8 @@ pub struct EchoPolicy;
 
 impl blockwheel_kv::EchoPolicy for EchoPolicy {
     type Info = komm::Rueckkopplung<ftd_sklave::Order, proto::RequestInfoReplyTx>;
-    type Insert = komm::Rueckkopplung<ftd_sklave::Order, proto::RequestInsertReplyTx>;
+    type Insert = komm::Rueckkopplung<ftd_sklave::Order, ftd_sklave::InsertStamp>;
     type LookupRange = komm::Rueckkopplung<ftd_sklave::Order, ftd_sklave::LookupKind>;
-    type Remove = komm::Rueckkopplung<ftd_sklave::Order, proto::RequestRemoveReplyTx>;
+    type Remove = komm::Rueckkopplung<ftd_sklave::Order, ftd_sklave::RemoveStamp>;
     type Flush = komm::Rueckkopplung<ftd_sklave::Order, proto::RequestFlushReplyTx>;
 }
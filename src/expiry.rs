@@ -0,0 +1,143 @@
+use std::{
+    collections::{
+        BTreeMap,
+        BTreeSet,
+    },
+    sync::{
+        Mutex,
+        MutexGuard,
+    },
+    time::{
+        SystemTime,
+    },
+};
+
+use crate::{
+    kv,
+};
+
+/// Expiry deadlines of keys inserted with a ttl.
+///
+/// Every deadline is also persisted as a reserved record next to the value
+/// (see [`crate::reserved`]), and the index is loaded from those records
+/// each time the gen_server starts. Deadlines are wall clock times, so they
+/// hold across process restarts.
+#[derive(Default)]
+pub struct ExpiryIndex {
+    deadlines: BTreeMap<kv::Key, SystemTime>,
+    queue: BTreeSet<(SystemTime, kv::Key)>,
+}
+
+/// Locks the index shared between the busyloop and its lookup tasks. A task
+/// panicking while holding the lock leaves the index consistent, since every
+/// method below updates it in a single step, so poisoning is ignored.
+pub fn lock(expiry: &Mutex<ExpiryIndex>) -> MutexGuard<'_, ExpiryIndex> {
+    match expiry.lock() {
+        Ok(index) =>
+            index,
+        Err(poisoned) =>
+            poisoned.into_inner(),
+    }
+}
+
+impl ExpiryIndex {
+    pub fn set(&mut self, key: kv::Key, deadline: SystemTime) {
+        self.clear(&key);
+        self.queue.insert((deadline, key.clone()));
+        self.deadlines.insert(key, deadline);
+    }
+
+    /// Drops the deadline of `key`, called on every plain write to it.
+    /// Returns `false` if there was no deadline.
+    pub fn clear(&mut self, key: &kv::Key) -> bool {
+        match self.deadlines.remove(key) {
+            Some(deadline) => {
+                self.queue.remove(&(deadline, key.clone()));
+                true
+            },
+            None =>
+                false,
+        }
+    }
+
+    pub fn has_deadline(&self, key: &kv::Key) -> bool {
+        self.deadlines.contains_key(key)
+    }
+
+    pub fn is_expired(&self, key: &kv::Key, now: SystemTime) -> bool {
+        matches!(self.deadlines.get(key), Some(deadline) if *deadline <= now)
+    }
+
+    pub fn next_deadline(&self) -> Option<SystemTime> {
+        self.queue.iter().next().map(|(deadline, _key)| *deadline)
+    }
+
+    /// Removes and returns all keys expired at `now` with their deadlines,
+    /// which are [`set`](ExpiryIndex::set) back for keys that fail to be
+    /// removed.
+    pub fn take_expired(&mut self, now: SystemTime) -> Vec<(kv::Key, SystemTime)> {
+        let mut expired = Vec::new();
+        while let Some((deadline, _key)) = self.queue.iter().next() {
+            if *deadline > now {
+                break;
+            }
+            let (deadline, key) = self.queue.pop_first().unwrap();
+            self.deadlines.remove(&key);
+            expired.push((key, deadline));
+        }
+        expired
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        time::{
+            Duration,
+            UNIX_EPOCH,
+        },
+    };
+
+    use alloc_pool::{
+        bytes::{
+            BytesPool,
+        },
+    };
+
+    use crate::{
+        kv_util,
+    };
+
+    use super::{
+        ExpiryIndex,
+    };
+
+    #[test]
+    fn expired_keys_can_be_set_back() {
+        let blocks_pool = BytesPool::new();
+        let key_a = kv_util::key_from_slice(&blocks_pool, b"a");
+        let key_b = kv_util::key_from_slice(&blocks_pool, b"b");
+        let deadline_a = UNIX_EPOCH + Duration::from_secs(10);
+        let deadline_b = UNIX_EPOCH + Duration::from_secs(20);
+        let now = UNIX_EPOCH + Duration::from_secs(15);
+
+        let mut index = ExpiryIndex::default();
+        index.set(key_a.clone(), deadline_a);
+        index.set(key_b.clone(), deadline_b);
+        assert!(index.is_expired(&key_a, now));
+
+        let expired = index.take_expired(now);
+        assert_eq!(expired.len(), 1);
+        assert_eq!(&expired[0].0.key_bytes[..], b"a");
+        assert_eq!(expired[0].1, deadline_a);
+        assert!(!index.has_deadline(&key_a));
+        assert_eq!(index.next_deadline(), Some(deadline_b));
+
+        // the removal failed: the key stays hidden and is swept again
+        for (key, deadline) in expired {
+            index.set(key, deadline);
+        }
+        assert!(index.is_expired(&key_a, now));
+        assert_eq!(index.next_deadline(), Some(deadline_a));
+    }
+}
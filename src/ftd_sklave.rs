@@ -1,3 +1,10 @@
+use std::{
+    sync::{
+        Arc,
+        Mutex,
+    },
+};
+
 use futures::{
     channel::{
         oneshot,
@@ -15,6 +22,12 @@ use crate::{
     Inserted,
     Removed,
     Flushed,
+    CompareAndSwapOutcome,
+    WriteBatchOutcome,
+    watch::{
+        WatchEvent,
+        WatchSender,
+    },
 };
 
 pub type SklaveJob = arbeitssklave::SklaveJob<Welt, Order>;
@@ -22,12 +35,12 @@ pub type SklaveJob = arbeitssklave::SklaveJob<Welt, Order>;
 pub enum Order {
     InfoCancel(komm::UmschlagAbbrechen<proto::RequestInfoReplyTx>),
     Info(komm::Umschlag<Info, proto::RequestInfoReplyTx>),
-    InsertCancel(komm::UmschlagAbbrechen<proto::RequestInsertReplyTx>),
-    Insert(komm::Umschlag<Inserted, proto::RequestInsertReplyTx>),
+    InsertCancel(komm::UmschlagAbbrechen<InsertStamp>),
+    Insert(komm::Umschlag<Inserted, InsertStamp>),
     LookupRangeCancel(komm::UmschlagAbbrechen<LookupKind>),
     LookupRange(komm::Umschlag<komm::Streamzeug<kv::KeyValuePair<kv::Value>>, LookupKind>),
-    RemoveCancel(komm::UmschlagAbbrechen<proto::RequestRemoveReplyTx>),
-    Remove(komm::Umschlag<Removed, proto::RequestRemoveReplyTx>),
+    RemoveCancel(komm::UmschlagAbbrechen<RemoveStamp>),
+    Remove(komm::Umschlag<Removed, RemoveStamp>),
     FlushCancel(komm::UmschlagAbbrechen<proto::RequestFlushReplyTx>),
     Flushed(komm::Umschlag<Flushed, proto::RequestFlushReplyTx>),
 }
@@ -35,8 +48,114 @@ pub enum Order {
 #[derive(Default)]
 pub struct Welt;
 
+pub struct InsertStamp {
+    pub kind: InsertKind,
+    pub watch: Option<WatchInsert>,
+}
+
+pub struct RemoveStamp {
+    pub kind: RemoveKind,
+    pub watch: Option<WatchRemove>,
+}
+
+/// Watch subscribers to notify once the insert is done.
+pub struct WatchInsert {
+    pub key: kv::Key,
+    pub value: kv::Value,
+    pub events_txs: Vec<WatchSender>,
+}
+
+/// Watch subscribers to notify once the remove is done.
+pub struct WatchRemove {
+    pub key: kv::Key,
+    pub events_txs: Vec<WatchSender>,
+}
+
+pub enum InsertKind {
+    Single(proto::RequestInsertReplyTx),
+    Batch(InsertKindBatch),
+    Conditional(proto::RequestCompareAndSwapReplyTx),
+    WriteBatch(WriteBatchSlot),
+    Merged(MergeReply),
+    /// Bookkeeping record under the reserved key prefix, nobody to reply to.
+    Reserved,
+}
+
+pub enum RemoveKind {
+    Single(proto::RequestRemoveReplyTx),
+    Conditional(proto::RequestCompareAndSwapReplyTx),
+    WriteBatch(WriteBatchSlot),
+    Merged(MergeReply),
+    Expired,
+    /// Bookkeeping record under the reserved key prefix, nobody to reply to.
+    Reserved,
+}
+
+pub struct MergeReply {
+    pub value: Option<kv::Value>,
+    pub reply_tx: proto::RequestMergeReplyTx,
+}
+
+pub struct WriteBatchSlot {
+    pub index: usize,
+    pub gather: Arc<Gather<WriteBatchOutcome>>,
+}
+
+pub struct InsertKindBatch {
+    pub index: usize,
+    pub gather: Arc<Gather<Inserted>>,
+}
+
+/// Collects replies for a batch of orders and sends them to the client in
+/// their original order once the last one has arrived.
+pub struct Gather<T> {
+    state: Mutex<GatherState<T>>,
+}
+
+struct GatherState<T> {
+    items: Vec<Option<T>>,
+    pending: usize,
+    reply_tx: Option<oneshot::Sender<Vec<T>>>,
+}
+
+impl<T> Gather<T> {
+    pub fn new(count: usize, reply_tx: oneshot::Sender<Vec<T>>) -> Gather<T> {
+        Gather {
+            state: Mutex::new(GatherState {
+                items: (0 .. count).map(|_| None).collect(),
+                pending: count,
+                reply_tx: Some(reply_tx),
+            }),
+        }
+    }
+
+    /// Returns `false` if this was the last item and the client is gone.
+    fn put(&self, index: usize, item: T) -> bool {
+        let mut state = match self.state.lock() {
+            Ok(state) =>
+                state,
+            Err(poisoned) =>
+                poisoned.into_inner(),
+        };
+        assert!(state.items[index].is_none());
+        state.items[index] = Some(item);
+        state.pending -= 1;
+        if state.pending > 0 {
+            return true;
+        }
+
+        let items = state.items
+            .drain(..)
+            .map(Option::unwrap)
+            .collect();
+        let reply_tx = state.reply_tx.take().unwrap();
+        reply_tx.send(items).is_ok()
+    }
+}
+
 pub enum LookupKind {
     Single(LookupKindSingle),
+    Many(LookupKindMany),
     Range(LookupKindRange),
 }
 
@@ -45,6 +164,12 @@ pub struct LookupKindSingle {
     pub feedback_tx: oneshot::Sender<komm::StreamId>,
 }
 
+pub struct LookupKindMany {
+    pub index: usize,
+    pub gather: Arc<Gather<Option<kv::ValueCell<kv::Value>>>>,
+    pub feedback_tx: oneshot::Sender<komm::StreamId>,
+}
+
 pub struct LookupKindRange {
     pub kv_items_stream_tx: oneshot::Sender<komm::Streamzeug<kv::KeyValuePair<kv::Value>>>,
 }
@@ -58,12 +183,8 @@ pub fn job<J>(sklave_job: SklaveJob, thread_pool: &edeltraud::Handle<J>) {
 #[derive(Debug)]
 pub enum Error {
     ReceiveOrder(arbeitssklave::Error),
-    GenServerIsLostOnRequestInfo,
-    GenServerIsLostOnRequestInsert,
-    GenServerIsLostOnRequestRemove,
-    GenServerIsLostOnRequestFlush,
     GenServerIsLostOnRequestLookupSingle,
-    GenServerIsLostOnRequestLookupRange,
+    GenServerIsLostOnRequestLookupMany,
 }
 
 fn run_job<J>(mut sklave_job: SklaveJob, _thread_pool: &edeltraud::Handle<J>) -> Result<(), Error> {
@@ -82,19 +203,21 @@ fn run_job<J>(mut sklave_job: SklaveJob, _thread_pool: &edeltraud::Handle<J>) ->
                     befehle = mehr_befehle;
                     match befehl {
                         Order::InfoCancel(komm::UmschlagAbbrechen { .. }) =>
-                            return Err(Error::GenServerIsLostOnRequestInfo),
+                            log::debug!("RequestInfo is canceled by blockwheel_kv"),
                         Order::Info(komm::Umschlag { inhalt: info, stamp: reply_tx, }) =>
                             if let Err(_send_error) = reply_tx.send(info) {
                                 log::debug!("client is gone during RequestInfo");
                             },
                         Order::InsertCancel(komm::UmschlagAbbrechen { .. }) =>
-                            return Err(Error::GenServerIsLostOnRequestInsert),
-                        Order::Insert(komm::Umschlag { inhalt: inserted, stamp: reply_tx, }) =>
-                            if let Err(_send_error) = reply_tx.send(inserted) {
-                                log::debug!("client is gone during RequestInsert");
-                            },
+                            log::debug!("RequestInsert is canceled by blockwheel_kv"),
+                        Order::Insert(komm::Umschlag { inhalt: inserted, stamp: InsertStamp { kind, watch, }, }) => {
+                            if let Some(WatchInsert { key, value, events_txs, }) = watch {
+                                notify_watchers(events_txs, WatchEvent::Inserted { key, value, inserted: inserted.clone(), });
+                            }
+                            reply_inserted(kind, inserted);
+                        },
                         Order::LookupRangeCancel(komm::UmschlagAbbrechen { .. }) =>
-                            return Err(Error::GenServerIsLostOnRequestLookupRange),
+                            log::debug!("RequestLookupRange is canceled by blockwheel_kv"),
                         Order::LookupRange(komm::Umschlag {
                             stamp: LookupKind::Single(LookupKindSingle {
                                 reply_tx,
@@ -126,6 +249,39 @@ fn run_job<J>(mut sklave_job: SklaveJob, _thread_pool: &edeltraud::Handle<J>) ->
                                 return Err(Error::GenServerIsLostOnRequestLookupSingle);
                             }
                         },
+                        Order::LookupRange(komm::Umschlag {
+                            stamp: LookupKind::Many(LookupKindMany {
+                                index,
+                                gather,
+                                feedback_tx,
+                            }),
+                            inhalt: komm::Streamzeug::NichtMehr(mehr),
+                        }) => {
+                            if !gather.put(index, None) {
+                                log::debug!("client is gone during RequestLookupMany");
+                            }
+                            if let Err(_send_error) = feedback_tx.send(mehr.stream_id().clone()) {
+                                return Err(Error::GenServerIsLostOnRequestLookupMany);
+                            }
+                        },
+                        Order::LookupRange(komm::Umschlag {
+                            stamp: LookupKind::Many(LookupKindMany {
+                                index,
+                                gather,
+                                feedback_tx,
+                            }),
+                            inhalt: komm::Streamzeug::Zeug {
+                                zeug: key_value_pair,
+                                mehr,
+                            },
+                        }) => {
+                            if !gather.put(index, Some(key_value_pair.value_cell)) {
+                                log::debug!("client is gone during RequestLookupMany");
+                            }
+                            if let Err(_send_error) = feedback_tx.send(mehr.stream_id().clone()) {
+                                return Err(Error::GenServerIsLostOnRequestLookupMany);
+                            }
+                        },
                         Order::LookupRange(komm::Umschlag {
                             stamp: LookupKind::Range(LookupKindRange { kv_items_stream_tx, }),
                             inhalt: streamzeug,
@@ -134,13 +290,15 @@ fn run_job<J>(mut sklave_job: SklaveJob, _thread_pool: &edeltraud::Handle<J>) ->
                                 log::debug!("lookup range process is gone during RequestLookupRange");
                             },
                         Order::RemoveCancel(komm::UmschlagAbbrechen { .. }) =>
-                            return Err(Error::GenServerIsLostOnRequestRemove),
-                        Order::Remove(komm::Umschlag { inhalt: removed, stamp: reply_tx, }) =>
-                            if let Err(_send_error) = reply_tx.send(removed) {
-                                log::debug!("client is gone during RequestRemove");
-                            },
+                            log::debug!("RequestRemove is canceled by blockwheel_kv"),
+                        Order::Remove(komm::Umschlag { inhalt: removed, stamp: RemoveStamp { kind, watch, }, }) => {
+                            if let Some(WatchRemove { key, events_txs, }) = watch {
+                                notify_watchers(events_txs, WatchEvent::Removed { key, removed: removed.clone(), });
+                            }
+                            reply_removed(kind, removed);
+                        },
                         Order::FlushCancel(komm::UmschlagAbbrechen { .. }) =>
-                            return Err(Error::GenServerIsLostOnRequestFlush),
+                            log::debug!("RequestFlush is canceled by blockwheel_kv"),
                         Order::Flushed(komm::Umschlag { inhalt: Flushed, stamp: reply_tx, }) =>
                             if let Err(_send_error) = reply_tx.send(Flushed) {
                                 log::debug!("client is gone during RequestFlush");
@@ -156,6 +314,66 @@ fn run_job<J>(mut sklave_job: SklaveJob, _thread_pool: &edeltraud::Handle<J>) ->
     }
 }
 
+fn reply_inserted(kind: InsertKind, inserted: Inserted) {
+    match kind {
+        InsertKind::Single(reply_tx) =>
+            if let Err(_send_error) = reply_tx.send(inserted) {
+                log::debug!("client is gone during RequestInsert");
+            },
+        InsertKind::Batch(InsertKindBatch { index, gather, }) =>
+            if !gather.put(index, inserted) {
+                log::debug!("client is gone during RequestInsertBatch");
+            },
+        InsertKind::Conditional(reply_tx) =>
+            if let Err(_send_error) = reply_tx.send(CompareAndSwapOutcome::Swapped) {
+                log::debug!("client is gone during RequestCompareAndSwap");
+            },
+        InsertKind::WriteBatch(WriteBatchSlot { index, gather, }) =>
+            if !gather.put(index, WriteBatchOutcome::Inserted(inserted)) {
+                log::debug!("client is gone during RequestApplyBatch");
+            },
+        InsertKind::Merged(merge_reply) =>
+            reply_merged(merge_reply),
+        InsertKind::Reserved =>
+            log::debug!("reserved record is inserted"),
+    }
+}
+
+fn reply_removed(kind: RemoveKind, removed: Removed) {
+    match kind {
+        RemoveKind::Single(reply_tx) =>
+            if let Err(_send_error) = reply_tx.send(removed) {
+                log::debug!("client is gone during RequestRemove");
+            },
+        RemoveKind::Conditional(reply_tx) =>
+            if let Err(_send_error) = reply_tx.send(CompareAndSwapOutcome::Swapped) {
+                log::debug!("client is gone during RequestCompareAndSwap");
+            },
+        RemoveKind::WriteBatch(WriteBatchSlot { index, gather, }) =>
+            if !gather.put(index, WriteBatchOutcome::Removed(removed)) {
+                log::debug!("client is gone during RequestApplyBatch");
+            },
+        RemoveKind::Merged(merge_reply) =>
+            reply_merged(merge_reply),
+        RemoveKind::Expired =>
+            log::debug!("expired key is removed"),
+        RemoveKind::Reserved =>
+            log::debug!("reserved record is removed"),
+    }
+}
+
+fn reply_merged(MergeReply { value, reply_tx, }: MergeReply) {
+    if let Err(_send_error) = reply_tx.send(Ok(value)) {
+        log::debug!("client is gone during RequestMerge");
+    }
+}
+
+fn notify_watchers(events_txs: Vec<WatchSender>, event: WatchEvent) {
+    for events_tx in events_txs {
+        events_tx.send(event.clone());
+    }
+}
+
 impl From<komm::UmschlagAbbrechen<proto::RequestInfoReplyTx>> for Order {
     fn from(v: komm::UmschlagAbbrechen<proto::RequestInfoReplyTx>) -> Order {
         Order::InfoCancel(v)
@@ -168,14 +386,14 @@ impl From<komm::Umschlag<Info, proto::RequestInfoReplyTx>> for Order {
     }
 }
 
-impl From<komm::UmschlagAbbrechen<proto::RequestInsertReplyTx>> for Order {
-    fn from(v: komm::UmschlagAbbrechen<proto::RequestInsertReplyTx>) -> Order {
+impl From<komm::UmschlagAbbrechen<InsertStamp>> for Order {
+    fn from(v: komm::UmschlagAbbrechen<InsertStamp>) -> Order {
         Order::InsertCancel(v)
     }
 }
 
-impl From<komm::Umschlag<Inserted, proto::RequestInsertReplyTx>> for Order {
-    fn from(v: komm::Umschlag<Inserted, proto::RequestInsertReplyTx>) -> Order {
+impl From<komm::Umschlag<Inserted, InsertStamp>> for Order {
+    fn from(v: komm::Umschlag<Inserted, InsertStamp>) -> Order {
         Order::Insert(v)
     }
 }
@@ -192,14 +410,14 @@ impl From<komm::Umschlag<komm::Streamzeug<kv::KeyValuePair<kv::Value>>, LookupKi
     }
 }
 
-impl From<komm::UmschlagAbbrechen<proto::RequestRemoveReplyTx>> for Order {
-    fn from(v: komm::UmschlagAbbrechen<proto::RequestRemoveReplyTx>) -> Order {
+impl From<komm::UmschlagAbbrechen<RemoveStamp>> for Order {
+    fn from(v: komm::UmschlagAbbrechen<RemoveStamp>) -> Order {
         Order::RemoveCancel(v)
     }
 }
 
-impl From<komm::Umschlag<Removed, proto::RequestRemoveReplyTx>> for Order {
-    fn from(v: komm::Umschlag<Removed, proto::RequestRemoveReplyTx>) -> Order {
+impl From<komm::Umschlag<Removed, RemoveStamp>> for Order {
+    fn from(v: komm::Umschlag<Removed, RemoveStamp>) -> Order {
         Order::Remove(v)
     }
 }
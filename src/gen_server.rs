@@ -1,3 +1,21 @@
+use std::{
+    ops::{
+        Bound,
+        RangeBounds,
+    },
+    collections::{
+        VecDeque,
+    },
+    sync::{
+        Arc,
+        Mutex,
+    },
+    time::{
+        Duration,
+        SystemTime,
+    },
+};
+
 use futures::{
     channel::{
         mpsc,
@@ -5,16 +23,24 @@ use futures::{
     },
     stream::{
         self,
+        FusedStream,
         FuturesUnordered,
+        SelectAll,
     },
     future::{
+        self,
         Either,
+        FutureExt,
     },
     select,
     SinkExt,
     StreamExt,
 };
 
+use futures_timer::{
+    Delay,
+};
+
 use alloc_pool::{
     bytes::{
         BytesPool,
@@ -31,7 +57,6 @@ use ero::{
         SupervisorPid,
     },
     ErrorSeverity,
-    RestartStrategy,
 };
 
 use crate::{
@@ -39,33 +64,60 @@ use crate::{
     proto,
     wheels,
     version,
+    kv_util,
+    expiry,
+    reserved,
     ftd_sklave,
     echo_policy::{
         EchoPolicy,
     },
+    kv,
     Params,
+    CompareAndSwapOutcome,
+    Flushed,
+    GenServerParams,
+    ScanOrder,
     LookupRange,
+    LookupRangeKeys,
     KeyValueStreamItem,
+    WriteBatch,
+    WriteBatchOp,
+    watch::{
+        self,
+        WatchSender,
+    },
+    merge::{
+        self,
+        MergeOperator,
+    },
 };
 
+/// How many keys [`proto::RequestRemoveRange`] removes in a single batch.
+const REMOVE_RANGE_CHUNK_SIZE: usize = 1024;
+
+/// How long a shutdown waits for pending lookups before canceling them.
+const SHUTDOWN_DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+
 #[derive(Debug)]
 pub enum Error {
     Wheels(wheels::Error),
     BlockwheelKvVersklaven(blockwheel_kv::Error),
     FtdVersklaven(arbeitssklave::Error),
-    RequestInfoBefehl(blockwheel_kv::Error),
-    RequestInsertBefehl(blockwheel_kv::Error),
-    RequestLookupSingleBefehl(blockwheel_kv::Error),
-    RequestLookupRangeBefehl(blockwheel_kv::Error),
-    RequestRemoveBefehl(blockwheel_kv::Error),
-    RequestFlushBefehl(blockwheel_kv::Error),
-    LookupRangeNext(blockwheel_kv::Error),
     BlockwheelKvMeisterHasGoneDuringLookupSingle,
+    BlockwheelKvMeisterHasGoneDuringLookupMany,
     BlockwheelKvMeisterHasGoneDuringLookupRange,
+    BlockwheelKvMeisterHasGoneDuringLookupConditional,
+    BlockwheelKvMeisterHasGoneDuringLoadReserved,
+    LoadReserved(blockwheel_kv::Error),
+    LoadReservedInterrupted,
+    RecoverReserved(blockwheel_kv::Error),
+    Flush(blockwheel_kv::Error),
+    BlockwheelKvMeisterHasGoneDuringFlush,
 }
 
 pub async fn run<J>(
     fused_request_rx: stream::Fuse<mpsc::Receiver<proto::Request>>,
+    gen_server_params: GenServerParams,
     parent_supervisor: SupervisorPid,
     params: Params,
     blocks_pool: BytesPool,
@@ -83,9 +135,10 @@ where J: From<job::BlockwheelFsSklaveJob>,
         restart::restartable(
             ero::Params {
                 name: "blockwheel_kv".to_string(),
-                restart_strategy: RestartStrategy::InstantCrash,
+                restart_strategy: gen_server_params.restart_strategy.clone(),
             },
             State {
+                gen_server_params,
                 parent_supervisor,
                 params,
                 blocks_pool,
@@ -93,6 +146,7 @@ where J: From<job::BlockwheelFsSklaveJob>,
                 wheels,
                 thread_pool,
                 fused_request_rx,
+                expiry: Arc::new(Mutex::new(expiry::ExpiryIndex::default())),
             },
             |mut state| async move {
                 let child_supervisor_gen_server = state.parent_supervisor.child_supervisor();
@@ -110,6 +164,7 @@ where J: From<job::BlockwheelFsSklaveJob>,
 }
 
 struct State<J> {
+    gen_server_params: GenServerParams,
     parent_supervisor: SupervisorPid,
     params: Params,
     blocks_pool: BytesPool,
@@ -117,15 +172,55 @@ struct State<J> {
     wheels: wheels::Wheels,
     thread_pool: edeltraud::Handle<J>,
     fused_request_rx: stream::Fuse<mpsc::Receiver<proto::Request>>,
+    expiry: Arc<Mutex<expiry::ExpiryIndex>>,
 }
 
-impl<J> From<Error> for ErrorSeverity<State<J>, Error> {
-    fn from(error: Error) -> Self {
-        ErrorSeverity::Fatal(error)
+async fn busyloop_init<J>(supervisor_pid: SupervisorPid, state: State<J>) -> Result<(), ErrorSeverity<State<J>, Error>>
+where J: From<job::BlockwheelFsSklaveJob>,
+      J: From<job::BlockwheelKvLookupRangeMergeSklaveJob>,
+      J: From<job::BlockwheelKvPerformerSklaveJob>,
+      J: From<job::FtdSklaveJob>,
+      J: Send + 'static,
+{
+    let meisters = match start_meisters(&state) {
+        Ok(meisters) =>
+            meisters,
+        Err(error) => {
+            log::error!("failed to start blockwheel_kv: {:?}", error);
+            return Err(ErrorSeverity::Recoverable { state, });
+        },
+    };
+
+    let mut writer = Writer {
+        blockwheel_kv_meister: meisters.blockwheel_kv_meister.clone(),
+        ftd_sendegeraet: meisters.ftd_sendegeraet.clone(),
+        thread_pool: state.thread_pool.clone(),
+        blocks_pool: state.blocks_pool.clone(),
+        expiry: state.expiry.clone(),
+        next_batch_id: 0,
+    };
+    if let Err(error) = recover_reserved(&state, &meisters, &mut writer).await {
+        log::error!("failed to recover reserved records: {:?}", error);
+        return Err(ErrorSeverity::Recoverable { state, });
     }
+
+    busyloop(
+        supervisor_pid,
+        state,
+        meisters.blockwheel_kv_meister,
+        meisters.ftd_sklave_meister,
+        meisters.ftd_sendegeraet,
+        writer,
+    ).await
 }
 
-async fn busyloop_init<J>(supervisor_pid: SupervisorPid, state: State<J>) -> Result<(), ErrorSeverity<State<J>, Error>>
+struct Meisters {
+    blockwheel_kv_meister: blockwheel_kv::Meister<EchoPolicy>,
+    ftd_sklave_meister: arbeitssklave::Meister<ftd_sklave::Welt, ftd_sklave::Order>,
+    ftd_sendegeraet: komm::Sendegeraet<ftd_sklave::Order>,
+}
+
+fn start_meisters<J>(state: &State<J>) -> Result<Meisters, Error>
 where J: From<job::BlockwheelFsSklaveJob>,
       J: From<job::BlockwheelKvLookupRangeMergeSklaveJob>,
       J: From<job::BlockwheelKvPerformerSklaveJob>,
@@ -138,9 +233,9 @@ where J: From<job::BlockwheelFsSklaveJob>,
 
     let blockwheel_kv_meister =
         blockwheel_kv::Meister::versklaven(
-            state.params,
-            state.blocks_pool,
-            state.version_provider,
+            state.params.clone(),
+            state.blocks_pool.clone(),
+            state.version_provider.clone(),
             wheels,
             &state.thread_pool,
         )
@@ -154,82 +249,273 @@ where J: From<job::BlockwheelFsSklaveJob>,
         state.thread_pool.clone(),
     );
 
-    busyloop(
-        supervisor_pid,
+    Ok(Meisters {
         blockwheel_kv_meister,
         ftd_sklave_meister,
         ftd_sendegeraet,
-        state.fused_request_rx,
-        state.thread_pool,
-    ).await
+    })
+}
+
+/// Applies again the write batches a crash has cut short, removes their
+/// intents and loads the expiry index, see [`reserved`]. Runs before any
+/// request is served.
+async fn recover_reserved<J>(state: &State<J>, meisters: &Meisters, writer: &mut Writer<J>) -> Result<(), Error>
+where J: From<job::BlockwheelKvPerformerSklaveJob>,
+      J: From<job::BlockwheelKvLookupRangeMergeSklaveJob>,
+      J: Send + 'static,
+{
+    let records = load_reserved(
+        &meisters.blockwheel_kv_meister,
+        &meisters.ftd_sendegeraet,
+        &state.thread_pool,
+        &state.blocks_pool,
+    ).await?;
+    let recovered = reserved::parse(&state.blocks_pool, records);
+    writer.next_batch_id = recovered.next_batch_id;
+    let reapplied = !recovered.batches.is_empty();
+    for ops in recovered.batches {
+        log::info!("applying again a write batch of {} operations interrupted by a crash", ops.len());
+        for op in ops {
+            let write = match op {
+                WriteBatchOp::Insert { key, value, } =>
+                    Write::Insert { key, value, expires_at: None, stamp: reserved_insert_stamp(), },
+                WriteBatchOp::Remove { key, } =>
+                    Write::Remove { key, stamp: reserved_remove_stamp(), },
+            };
+            writer.submit_one(write)
+                .map_err(Error::RecoverReserved)?;
+        }
+    }
+    if reapplied {
+        // the intents stay until the batches are persisted again
+        writer.flush().await?;
+    }
+    for key in recovered.stale_keys {
+        writer.submit_one(Write::Remove { key, stamp: reserved_remove_stamp(), })
+            .map_err(Error::RecoverReserved)?;
+    }
+
+    let mut expiry = expiry::lock(&state.expiry);
+    *expiry = expiry::ExpiryIndex::default();
+    for (key, deadline) in recovered.expiries {
+        expiry.set(key, deadline);
+    }
+    Ok(())
+}
+
+/// Reads every record under the reserved key prefix.
+async fn load_reserved<J>(
+    blockwheel_kv_meister: &blockwheel_kv::Meister<EchoPolicy>,
+    ftd_sendegeraet: &komm::Sendegeraet<ftd_sklave::Order>,
+    thread_pool: &edeltraud::Handle<J>,
+    blocks_pool: &BytesPool,
+)
+    -> Result<Vec<(kv::Key, kv::Value)>, Error>
+where J: From<job::BlockwheelKvPerformerSklaveJob>,
+      J: From<job::BlockwheelKvLookupRangeMergeSklaveJob>,
+      J: Send + 'static,
+{
+    let (
+        mut kv_items_stream_tx,
+        mut kv_items_stream_rx,
+    ) = oneshot::channel();
+    let stream = blockwheel_kv_meister
+        .lookup_range(
+            reserved::bounds(blocks_pool),
+            ftd_sendegeraet.rueckkopplung(
+                ftd_sklave::LookupKind::Range(
+                    ftd_sklave::LookupKindRange { kv_items_stream_tx, },
+                ),
+            ),
+            thread_pool,
+        )
+        .map_err(Error::LoadReserved)?;
+
+    let mut records = Vec::new();
+    loop {
+        match kv_items_stream_rx.await {
+            Ok(komm::Streamzeug::Zeug {
+                zeug: kv::KeyValuePair { key, value_cell, },
+                mehr,
+            }) => {
+                (kv_items_stream_tx, kv_items_stream_rx) = oneshot::channel();
+                let stream_echo = ftd_sendegeraet
+                    .rueckkopplung(
+                        ftd_sklave::LookupKind::Range(
+                            ftd_sklave::LookupKindRange { kv_items_stream_tx, },
+                        ),
+                    );
+                if let Err(error) = stream.next(stream_echo, mehr.into()) {
+                    log::error!("lookup range next failed while loading reserved records: {:?}", error);
+                    return Err(Error::LoadReservedInterrupted);
+                }
+                if let kv::Cell::Value(value) = value_cell.cell {
+                    records.push((key, value));
+                }
+            },
+            Ok(komm::Streamzeug::NichtMehr(..)) =>
+                return Ok(records),
+            Err(oneshot::Canceled) =>
+                return Err(Error::BlockwheelKvMeisterHasGoneDuringLoadReserved),
+        }
+    }
 }
 
 async fn busyloop<J>(
     _supervisor_pid: SupervisorPid,
+    mut state: State<J>,
     blockwheel_kv_meister: blockwheel_kv::Meister<EchoPolicy>,
     _ftd_sklave_meister: arbeitssklave::Meister<ftd_sklave::Welt, ftd_sklave::Order>,
     ftd_sendegeraet: komm::Sendegeraet<ftd_sklave::Order>,
-    mut fused_request_rx: stream::Fuse<mpsc::Receiver<proto::Request>>,
-    thread_pool: edeltraud::Handle<J>,
+    mut writer: Writer<J>,
 )
     -> Result<(), ErrorSeverity<State<J>, Error>>
 where J: From<job::BlockwheelKvPerformerSklaveJob>,
       J: From<job::BlockwheelKvLookupRangeMergeSklaveJob>,
       J: Send + 'static,
 {
+    let thread_pool = state.thread_pool.clone();
     let mut lookup_tasks =
         FuturesUnordered::new();
+    let mut shutdown_requests = Vec::new();
+    // while a conditional write waits for its lookup, other writes are deferred
+    // so that nothing can sneak in between the check and the write
+    let mut pending_conditional: Option<PendingConditional> = None;
+    let mut conditional_lookup_rx = future::Fuse::terminated();
+    let mut deferred_requests = VecDeque::new();
+    let mut remove_range_tasks = FuturesUnordered::new();
+    let mut remove_range_chunks = SelectAll::new();
+    let mut watchers = Vec::new();
+    let expiry = state.expiry.clone();
+    let mut expiry_timer = future::Fuse::terminated();
+    let mut expiry_timer_deadline = None;
     loop {
-        enum Event<R, T> {
+        enum Event<R, T, C, S, B> {
             Request(R),
             Task(T),
+            ConditionalLookup(C),
+            RemoveRangeDone(S),
+            RemoveRangeChunk(B),
+            Expire,
+        }
+
+        // expired keys are removed with ordinary writes, so the sweep pauses
+        // while a conditional write is pending, and stops with the request channel
+        let next_deadline = if pending_conditional.is_none() && !state.fused_request_rx.is_terminated() {
+            expiry::lock(&expiry).next_deadline()
+        } else {
+            None
+        };
+        if next_deadline != expiry_timer_deadline {
+            expiry_timer = match next_deadline {
+                Some(deadline) =>
+                    Delay::new(deadline.duration_since(SystemTime::now()).unwrap_or(Duration::ZERO)).fuse(),
+                None =>
+                    future::Fuse::terminated(),
+            };
+            expiry_timer_deadline = next_deadline;
         }
 
-        let event = if lookup_tasks.is_empty() {
-            Event::Request(fused_request_rx.next().await)
+        let event = if pending_conditional.is_none() && !deferred_requests.is_empty() {
+            Event::Request(deferred_requests.pop_front())
         } else {
             select! {
-                result = fused_request_rx.next() =>
+                result = state.fused_request_rx.next() =>
                     Event::Request(result),
-                result = lookup_tasks.next() =>
-                    Event::Task(result.unwrap()),
+                result = lookup_tasks.select_next_some() =>
+                    Event::Task(result),
+                result = conditional_lookup_rx =>
+                    Event::ConditionalLookup(result),
+                result = remove_range_tasks.select_next_some() =>
+                    Event::RemoveRangeDone(result),
+                request_apply_batch = remove_range_chunks.select_next_some() =>
+                    Event::RemoveRangeChunk(request_apply_batch),
+                () = expiry_timer =>
+                    Event::Expire,
+                complete =>
+                    Event::Request(None),
             }
         };
 
         match event {
+            Event::Request(Some(request)) if pending_conditional.is_some() && request.is_write() =>
+                deferred_requests.push_back(request),
+            Event::Request(None) if pending_conditional.is_some() || !deferred_requests.is_empty() || !remove_range_tasks.is_empty() =>
+                (),
             Event::Request(None) =>
                 break,
-            Event::Request(Some(proto::Request::Info(proto::RequestInfo { reply_tx, }))) => {
-                blockwheel_kv_meister
+            Event::Request(Some(proto::Request::Info(proto::RequestInfo { reply_tx, error_tx, }))) => {
+                let befehl_result = blockwheel_kv_meister
                     .info(
                         ftd_sendegeraet.rueckkopplung(reply_tx),
                         &thread_pool,
-                    )
-                    .map_err(Error::RequestInfoBefehl)?;
+                    );
+                if let Err(error) = befehl_result {
+                    reply_backend_error(error_tx, error);
+                }
             },
             Event::Request(Some(proto::Request::Insert(proto::RequestInsert {
                 key,
                 value,
+                expires_at,
                 reply_tx,
+                error_tx,
             }))) => {
-                blockwheel_kv_meister
-                    .insert(
-                        key,
-                        value,
-                        ftd_sendegeraet.rueckkopplung(reply_tx),
-                        &thread_pool,
-                    )
-                    .map_err(Error::RequestInsertBefehl)?;
+                let stamp = insert_stamp(&mut watchers, ftd_sklave::InsertKind::Single(reply_tx), &key, &value);
+                let write_result = writer.submit(vec![Write::Insert { key, value, expires_at, stamp, }]).await;
+                if let Err(error) = write_result.or_else(|error| reply_write_error(error_tx, error)) {
+                    log::error!("insert is interrupted by a backend failure: {:?}", error);
+                    return Err(ErrorSeverity::Recoverable { state, });
+                }
+            },
+            Event::Request(Some(proto::Request::InsertBatch(proto::RequestInsertBatch {
+                key_values,
+                reply_tx,
+                error_tx,
+            }))) => {
+                if key_values.is_empty() {
+                    if let Err(_send_error) = reply_tx.send(Vec::new()) {
+                        log::debug!("client is gone during RequestInsertBatch");
+                    }
+                    continue;
+                }
+                let gather = Arc::new(ftd_sklave::Gather::new(key_values.len(), reply_tx));
+                let writes = key_values
+                    .into_iter()
+                    .enumerate()
+                    .map(|(index, (key, value))| {
+                        let stamp = insert_stamp(
+                            &mut watchers,
+                            ftd_sklave::InsertKind::Batch(
+                                ftd_sklave::InsertKindBatch { index, gather: gather.clone(), },
+                            ),
+                            &key,
+                            &value,
+                        );
+                        Write::Insert { key, value, expires_at: None, stamp, }
+                    })
+                    .collect();
+                let write_result = writer.submit(writes).await;
+                if let Err(error) = write_result.or_else(|error| reply_write_error(error_tx, error)) {
+                    log::error!("insert batch is interrupted by a backend failure: {:?}", error);
+                    return Err(ErrorSeverity::Recoverable { state, });
+                }
             },
             Event::Request(Some(
                 proto::Request::LookupRange(
                     proto::RequestLookupKind::Single(
-                        proto::RequestLookupKindSingle { key, reply_tx, },
+                        proto::RequestLookupKindSingle { key, reply_tx, error_tx, },
                     ),
                 ),
             )) => {
+                if reserved::is_reserved(&key) || expiry::lock(&expiry).is_expired(&key, SystemTime::now()) {
+                    if let Err(_send_error) = reply_tx.send(None) {
+                        log::debug!("client is gone during RequestLookup");
+                    }
+                    continue;
+                }
                 let (feedback_tx, feedback_rx) = oneshot::channel();
-                let stream = blockwheel_kv_meister
+                let befehl_result = blockwheel_kv_meister
                     .lookup_range(
                         key.clone() ..= key,
                         ftd_sendegeraet.rueckkopplung(
@@ -238,109 +524,1067 @@ where J: From<job::BlockwheelKvPerformerSklaveJob>,
                             ),
                         ),
                         &thread_pool,
-                    )
-                    .map_err(Error::RequestLookupSingleBefehl)?;
-                lookup_tasks.push(Either::Left(async move {
-                    match feedback_rx.await {
-                        Ok(ref stream_id) => {
-                            assert!(stream_id == stream.stream_id());
-                            Ok(())
-                        },
-                        Err(oneshot::Canceled) =>
-                            Err(Error::BlockwheelKvMeisterHasGoneDuringLookupSingle),
-                    }
-                }));
+                    );
+                let stream = match befehl_result {
+                    Ok(stream) =>
+                        stream,
+                    Err(error) => {
+                        reply_backend_error(error_tx, error);
+                        continue;
+                    },
+                };
+                let stream_id = stream.stream_id().clone();
+                lookup_tasks.push(Either::Left(lookup_single_task(
+                    stream,
+                    stream_id,
+                    feedback_rx,
+                    Error::BlockwheelKvMeisterHasGoneDuringLookupSingle,
+                )));
             },
             Event::Request(Some(
-                proto::Request::LookupRange(proto::RequestLookupKind::Range(
-                    proto::RequestLookupKindRange {
-                        range_from,
-                        range_to,
-                        reply_tx,
-                    },
-                )),
+                proto::Request::LookupRange(
+                    proto::RequestLookupKind::Many(
+                        proto::RequestLookupKindMany { keys, reply_tx, error_tx, },
+                    ),
+                ),
             )) => {
-                let blockwheel_kv_meister = blockwheel_kv_meister.clone();
-                let ftd_sendegeraet = ftd_sendegeraet.clone();
-                let thread_pool = thread_pool.clone();
-                lookup_tasks.push(Either::Right(async move {
-                    let (mut key_values_tx, key_values_rx) = mpsc::channel(0);
-                    if let Err(_send_error) = reply_tx.send(LookupRange { key_values_rx, }) {
-                        log::debug!("client has canceled lookup range request");
-                        return Ok(());
+                if keys.is_empty() {
+                    if let Err(_send_error) = reply_tx.send(Vec::new()) {
+                        log::debug!("client is gone during RequestLookupMany");
                     }
-
-                    let (
-                        mut kv_items_stream_tx,
-                        mut kv_items_stream_rx,
-                    ) = oneshot::channel();
-                    let stream = blockwheel_kv_meister
+                    continue;
+                }
+                let gather = Arc::new(ftd_sklave::Gather::new(keys.len(), reply_tx));
+                for (index, key) in keys.into_iter().enumerate() {
+                    if reserved::is_reserved(&key) || expiry::lock(&expiry).is_expired(&key, SystemTime::now()) {
+                        if !gather.put(index, None) {
+                            log::debug!("client is gone during RequestLookupMany");
+                        }
+                        continue;
+                    }
+                    let (feedback_tx, feedback_rx) = oneshot::channel();
+                    let befehl_result = blockwheel_kv_meister
                         .lookup_range(
-                            (range_from, range_to),
+                            key.clone() ..= key,
                             ftd_sendegeraet.rueckkopplung(
-                                ftd_sklave::LookupKind::Range(
-                                    ftd_sklave::LookupKindRange { kv_items_stream_tx, },
+                                ftd_sklave::LookupKind::Many(
+                                    ftd_sklave::LookupKindMany {
+                                        index,
+                                        gather: gather.clone(),
+                                        feedback_tx,
+                                    },
                                 ),
                             ),
                             &thread_pool,
-                        )
-                        .map_err(Error::RequestLookupRangeBefehl)?;
-                    loop {
-                        match kv_items_stream_rx.await {
-                            Ok(komm::Streamzeug::Zeug {
-                                zeug: key_value_pair,
-                                mehr,
-                            }) => {
-                                if let Err(_send_error) = key_values_tx.send(KeyValueStreamItem::KeyValue(key_value_pair)).await {
-                                    log::debug!("client has dropped kv items stream tx, canceling");
-                                    return Ok(());
+                        );
+                    let stream = match befehl_result {
+                        Ok(stream) =>
+                            stream,
+                        Err(error) => {
+                            // nothing is written, so the lookups already submitted
+                            // are just left to finish into the abandoned gather
+                            reply_backend_error(error_tx, error);
+                            break;
+                        },
+                    };
+                    let stream_id = stream.stream_id().clone();
+                    lookup_tasks.push(Either::Left(lookup_single_task(
+                        stream,
+                        stream_id,
+                        feedback_rx,
+                        Error::BlockwheelKvMeisterHasGoneDuringLookupMany,
+                    )));
+                }
+            },
+            Event::Request(Some(
+                proto::Request::LookupRange(proto::RequestLookupKind::Range(request_range)),
+            )) => {
+                lookup_tasks.push(Either::Right(lookup_range_task(
+                    blockwheel_kv_meister.clone(),
+                    ftd_sendegeraet.clone(),
+                    thread_pool.clone(),
+                    state.gen_server_params.lookup_range_buffer,
+                    expiry.clone(),
+                    request_range,
+                )));
+            },
+            Event::Request(Some(
+                proto::Request::LookupRange(proto::RequestLookupKind::Prefix(
+                    proto::RequestLookupKindPrefix {
+                        prefix,
+                        reply_tx,
+                        error_tx,
+                    },
+                )),
+            )) => {
+                let range_from = Bound::Included(kv_util::key_from_slice(&state.blocks_pool, &prefix));
+                let range_to = match kv_util::prefix_upper_bound(&prefix) {
+                    Some(upper_bound) =>
+                        Bound::Excluded(kv_util::key_from_slice(&state.blocks_pool, &upper_bound)),
+                    None =>
+                        Bound::Unbounded,
+                };
+                lookup_tasks.push(Either::Right(lookup_range_task(
+                    blockwheel_kv_meister.clone(),
+                    ftd_sendegeraet.clone(),
+                    thread_pool.clone(),
+                    state.gen_server_params.lookup_range_buffer,
+                    expiry.clone(),
+                    proto::RequestLookupKindRange {
+                        range_from,
+                        range_to,
+                        limit: None,
+                        order: ScanOrder::Ascending,
+                        reply_tx: proto::RangeReplyTx::KeyValues(reply_tx),
+                        error_tx,
+                    },
+                )));
+            },
+            Event::Request(Some(proto::Request::Remove(proto::RequestRemove { key, reply_tx, error_tx, }))) => {
+                let stamp = remove_stamp(&mut watchers, ftd_sklave::RemoveKind::Single(reply_tx), &key);
+                let write_result = writer.submit(vec![Write::Remove { key, stamp, }]).await;
+                if let Err(error) = write_result.or_else(|error| reply_write_error(error_tx, error)) {
+                    log::error!("remove is interrupted by a backend failure: {:?}", error);
+                    return Err(ErrorSeverity::Recoverable { state, });
+                }
+            },
+            Event::Request(Some(proto::Request::FlushAll(proto::RequestFlush { reply_tx, error_tx, }))) => {
+                let befehl_result = blockwheel_kv_meister
+                    .flush(
+                        ftd_sendegeraet.rueckkopplung(reply_tx),
+                        &thread_pool,
+                    );
+                if let Err(error) = befehl_result {
+                    reply_backend_error(error_tx, error);
+                }
+            },
+            Event::Request(Some(request @ (proto::Request::CompareAndSwap(..) | proto::Request::Merge(..)))) => {
+                let (key, action, error_tx) = match request {
+                    proto::Request::CompareAndSwap(proto::RequestCompareAndSwap {
+                        key,
+                        expected,
+                        new,
+                        reply_tx,
+                        error_tx,
+                    }) =>
+                        (key, ConditionalAction::CompareAndSwap { expected, new, reply_tx, }, error_tx),
+                    proto::Request::Merge(proto::RequestMerge {
+                        key,
+                        operator,
+                        operand,
+                        reply_tx,
+                        error_tx,
+                    }) =>
+                        (key, ConditionalAction::Merge { operator, operand, reply_tx, }, error_tx),
+                    _ =>
+                        unreachable!(),
+                };
+                let (lookup_reply_tx, lookup_reply_rx) = oneshot::channel();
+                let (feedback_tx, feedback_rx) = oneshot::channel();
+                let befehl_result = blockwheel_kv_meister
+                    .lookup_range(
+                        key.clone() ..= key.clone(),
+                        ftd_sendegeraet.rueckkopplung(
+                            ftd_sklave::LookupKind::Single(
+                                ftd_sklave::LookupKindSingle { reply_tx: lookup_reply_tx, feedback_tx, },
+                            ),
+                        ),
+                        &thread_pool,
+                    );
+                let stream = match befehl_result {
+                    Ok(stream) =>
+                        stream,
+                    Err(error) => {
+                        reply_backend_error(error_tx, error);
+                        continue;
+                    },
+                };
+                let stream_id = stream.stream_id().clone();
+                lookup_tasks.push(Either::Left(lookup_single_task(
+                    stream,
+                    stream_id,
+                    feedback_rx,
+                    Error::BlockwheelKvMeisterHasGoneDuringLookupConditional,
+                )));
+                pending_conditional = Some(PendingConditional { key, action, error_tx, });
+                conditional_lookup_rx = lookup_reply_rx.fuse();
+            },
+            Event::Request(Some(proto::Request::ApplyBatch(proto::RequestApplyBatch {
+                batch,
+                reply_tx,
+                error_tx,
+            }))) => {
+                if batch.is_empty() {
+                    if let Err(_send_error) = reply_tx.send(Vec::new()) {
+                        log::debug!("client is gone during RequestApplyBatch");
+                    }
+                    continue;
+                }
+                // all operations are submitted within this single step, so requests
+                // served afterwards observe the whole batch
+                let gather = Arc::new(ftd_sklave::Gather::new(batch.len(), reply_tx));
+                let writes = batch
+                    .into_ops()
+                    .into_iter()
+                    .enumerate()
+                    .map(|(index, op)| {
+                        let slot = ftd_sklave::WriteBatchSlot { index, gather: gather.clone(), };
+                        match op {
+                            WriteBatchOp::Insert { key, value, } => {
+                                let stamp = insert_stamp(&mut watchers, ftd_sklave::InsertKind::WriteBatch(slot), &key, &value);
+                                Write::Insert { key, value, expires_at: None, stamp, }
+                            },
+                            WriteBatchOp::Remove { key, } => {
+                                let stamp = remove_stamp(&mut watchers, ftd_sklave::RemoveKind::WriteBatch(slot), &key);
+                                Write::Remove { key, stamp, }
+                            },
+                        }
+                    })
+                    .collect();
+                let write_result = writer.submit(writes).await;
+                if let Err(error) = write_result.or_else(|error| reply_write_error(error_tx, error)) {
+                    log::error!("write batch is interrupted by a backend failure: {:?}", error);
+                    return Err(ErrorSeverity::Recoverable { state, });
+                }
+            },
+            Event::Request(Some(proto::Request::RemoveRange(request_remove_range))) => {
+                let (chunks_tx, chunks_rx) = mpsc::unbounded();
+                remove_range_chunks.push(chunks_rx);
+                remove_range_tasks.push(remove_range_task(
+                    blockwheel_kv_meister.clone(),
+                    ftd_sendegeraet.clone(),
+                    thread_pool.clone(),
+                    state.gen_server_params.lookup_range_buffer,
+                    expiry.clone(),
+                    request_remove_range,
+                    chunks_tx,
+                ));
+            },
+            Event::Request(Some(proto::Request::Watch(proto::RequestWatch {
+                range_from,
+                range_to,
+                reply_tx,
+            }))) => {
+                let (events_tx, events_rx) = watch::channel(state.gen_server_params.watch_buffer);
+                if let Err(_send_error) = reply_tx.send(events_rx) {
+                    log::debug!("client is gone during RequestWatch");
+                    continue;
+                }
+                watchers.push(Watcher { range_from, range_to, events_tx, });
+            },
+            Event::Request(Some(proto::Request::Shutdown(request_shutdown))) => {
+                // stop accepting new requests but keep serving the ones already queued
+                log::debug!("shutdown requested: closing request channel");
+                state.fused_request_rx.get_mut().close();
+                shutdown_requests.push(request_shutdown);
+            },
+            Event::ConditionalLookup(Ok(maybe_value_cell)) => {
+                let PendingConditional { key, action, error_tx, } =
+                    pending_conditional.take().unwrap();
+                let is_expired = expiry::lock(&expiry).is_expired(&key, SystemTime::now());
+                let current = maybe_value_cell
+                    .filter(|_value_cell| !is_expired)
+                    .and_then(|value_cell| match value_cell.cell {
+                        kv::Cell::Value(value) =>
+                            Some(value),
+                        kv::Cell::Tombstone =>
+                            None,
+                    });
+                let write = match action {
+                    ConditionalAction::CompareAndSwap { expected, new, reply_tx, } => {
+                        if current != expected {
+                            if let Err(_send_error) = reply_tx.send(CompareAndSwapOutcome::Mismatch { current, }) {
+                                log::debug!("client is gone during RequestCompareAndSwap");
+                            }
+                            continue;
+                        }
+                        match new {
+                            Some(value) =>
+                                ConditionalWrite::Insert { value, kind: ftd_sklave::InsertKind::Conditional(reply_tx), },
+                            None if current.is_none() => {
+                                // nothing to remove
+                                if let Err(_send_error) = reply_tx.send(CompareAndSwapOutcome::Swapped) {
+                                    log::debug!("client is gone during RequestCompareAndSwap");
                                 }
-                                (kv_items_stream_tx, kv_items_stream_rx) = oneshot::channel();
-                                let stream_echo = ftd_sendegeraet
-                                    .rueckkopplung(
-                                        ftd_sklave::LookupKind::Range(
-                                            ftd_sklave::LookupKindRange { kv_items_stream_tx, },
-                                        ),
-                                    );
-                                stream.next(stream_echo, mehr.into())
-                                    .map_err(Error::LookupRangeNext)?;
+                                continue;
                             },
-                            Ok(komm::Streamzeug::NichtMehr(..)) => {
-                                if let Err(_send_error) = key_values_tx.send(KeyValueStreamItem::NoMore).await {
-                                    log::debug!("client has dropped kv items stream tx, canceling");
+                            None =>
+                                ConditionalWrite::Remove { kind: ftd_sklave::RemoveKind::Conditional(reply_tx), },
+                        }
+                    },
+                    ConditionalAction::Merge { operator, operand, reply_tx, } => {
+                        let current_bytes = current.as_ref().map(|value| &value.value_bytes[..]);
+                        match merge::apply(&*operator, &key, current_bytes, &operand) {
+                            Ok(Some(value_bytes)) => {
+                                let value = kv_util::value_from_slice(&state.blocks_pool, &value_bytes);
+                                let merge_reply = ftd_sklave::MergeReply { value: Some(value.clone()), reply_tx, };
+                                ConditionalWrite::Insert { value, kind: ftd_sklave::InsertKind::Merged(merge_reply), }
+                            },
+                            Ok(None) if current.is_none() => {
+                                if let Err(_send_error) = reply_tx.send(Ok(None)) {
+                                    log::debug!("client is gone during RequestMerge");
+                                }
+                                continue;
+                            },
+                            Ok(None) => {
+                                let merge_reply = ftd_sklave::MergeReply { value: None, reply_tx, };
+                                ConditionalWrite::Remove { kind: ftd_sklave::RemoveKind::Merged(merge_reply), }
+                            },
+                            Err(rejected) => {
+                                if let Err(_send_error) = reply_tx.send(Err(rejected)) {
+                                    log::debug!("client is gone during RequestMerge");
                                 }
-                                return Ok(());
+                                continue;
                             },
-                            Err(oneshot::Canceled) =>
-                                return Err(Error::BlockwheelKvMeisterHasGoneDuringLookupRange),
                         }
-                    }
-                }));
+                    },
+                };
+                let write = match write {
+                    ConditionalWrite::Insert { value, kind, } => {
+                        let stamp = insert_stamp(&mut watchers, kind, &key, &value);
+                        Write::Insert { key, value, expires_at: None, stamp, }
+                    },
+                    ConditionalWrite::Remove { kind, } => {
+                        let stamp = remove_stamp(&mut watchers, kind, &key);
+                        Write::Remove { key, stamp, }
+                    },
+                };
+                let write_result = writer.submit(vec![write]).await;
+                if let Err(error) = write_result.or_else(|error| reply_write_error(error_tx, error)) {
+                    log::error!("conditional write is interrupted by a backend failure: {:?}", error);
+                    return Err(ErrorSeverity::Recoverable { state, });
+                }
             },
-            Event::Request(Some(proto::Request::Remove(proto::RequestRemove { key, reply_tx, }))) => {
-                blockwheel_kv_meister
-                    .remove(
-                        key,
-                        ftd_sendegeraet.rueckkopplung(reply_tx),
-                        &thread_pool,
-                    )
-                    .map_err(Error::RequestRemoveBefehl)?;
+            Event::RemoveRangeChunk(request_apply_batch) =>
+                // removes are submitted as a regular batch, right after the
+                // requests which are already waiting
+                deferred_requests.push_back(proto::Request::ApplyBatch(request_apply_batch)),
+            Event::RemoveRangeDone(Ok(())) =>
+                (),
+            Event::RemoveRangeDone(Err(error)) => {
+                log::error!("remove range task failed: {:?}", error);
+                return Err(ErrorSeverity::Recoverable { state, });
             },
-            Event::Request(Some(proto::Request::FlushAll(proto::RequestFlush { reply_tx, }))) => {
-                blockwheel_kv_meister
-                    .flush(
-                        ftd_sendegeraet.rueckkopplung(reply_tx),
-                        &thread_pool,
-                    )
-                    .map_err(Error::RequestFlushBefehl)?;
+            Event::Expire => {
+                expiry_timer_deadline = None;
+                let mut expired = expiry::lock(&expiry).take_expired(SystemTime::now()).into_iter();
+                while let Some((key, deadline)) = expired.next() {
+                    // the value goes first: if the expiry record outlives it, the
+                    // key is just swept again after a restart
+                    let expiry_key = reserved::expiry_key(&state.blocks_pool, &key);
+                    let stamp = remove_stamp(&mut watchers, ftd_sklave::RemoveKind::Expired, &key);
+                    let befehl_result = writer.submit_one(Write::Remove { key: key.clone(), stamp, })
+                        .and_then(|()| writer.submit_one(Write::Remove { key: expiry_key, stamp: reserved_remove_stamp(), }));
+                    if let Err(error) = befehl_result {
+                        // keys not removed stay hidden until the restart sweeps them again
+                        log::error!("failed to remove expired key: {:?}", error);
+                        let mut index = expiry::lock(&expiry);
+                        index.set(key, deadline);
+                        for (key, deadline) in expired {
+                            index.set(key, deadline);
+                        }
+                        return Err(ErrorSeverity::Recoverable { state, });
+                    }
+                }
+            },
+            Event::ConditionalLookup(Err(oneshot::Canceled)) => {
+                log::error!("blockwheel_kv meister has gone during conditional lookup");
+                return Err(ErrorSeverity::Recoverable { state, });
             },
             Event::Task(Ok(())) =>
                 (),
-            Event::Task(Err(error)) =>
-                return Err(ErrorSeverity::Fatal(error)),
+            Event::Task(Err(error)) => {
+                log::error!("lookup task failed: {:?}", error);
+                return Err(ErrorSeverity::Recoverable { state, });
+            },
+        }
+    }
+
+    if !shutdown_requests.is_empty() {
+        log::debug!("request channel is drained: waiting for {} pending lookup tasks", lookup_tasks.len());
+        let mut drain_timeout = Delay::new(SHUTDOWN_DRAIN_TIMEOUT).fuse();
+        loop {
+            select! {
+                task_result = lookup_tasks.select_next_some() =>
+                    if let Err(error) = task_result {
+                        log::error!("lookup task failed during shutdown: {:?}", error);
+                    },
+                () = drain_timeout => {
+                    // a client that never reads its range stream keeps the task blocked on a
+                    // full buffer: dropping the tasks closes such streams
+                    log::warn!("{} lookup tasks are still pending after {:?}: canceling them", lookup_tasks.len(), SHUTDOWN_DRAIN_TIMEOUT);
+                    break;
+                },
+                complete =>
+                    break,
+            }
+        }
+        drop(lookup_tasks);
+
+        log::debug!("flushing blockwheel_kv before shutdown");
+        // blockwheel_kv::Error cannot be cloned, so a failed flush is retried for every
+        // next requester: each one gets the error of its own attempt
+        let mut flushed = false;
+        for proto::RequestShutdown { reply_tx, error_tx, } in shutdown_requests {
+            if !flushed {
+                let (flush_tx, flush_rx) = oneshot::channel();
+                let befehl_result = blockwheel_kv_meister
+                    .flush(
+                        ftd_sendegeraet.rueckkopplung(flush_tx),
+                        &thread_pool,
+                    );
+                match befehl_result {
+                    Ok(()) =>
+                        match flush_rx.await {
+                            Ok(Flushed) =>
+                                flushed = true,
+                            Err(oneshot::Canceled) => {
+                                log::error!("blockwheel_kv flush is canceled during shutdown");
+                                continue;
+                            },
+                        },
+                    Err(error) => {
+                        reply_backend_error(error_tx, error);
+                        continue;
+                    },
+                }
+            }
+            if let Err(_send_error) = reply_tx.send(Flushed) {
+                log::debug!("client is gone during RequestShutdown");
+            }
         }
     }
 
     log::debug!("request channel is depleted: terminating busyloop");
     Ok(())
 }
+
+async fn lookup_range_task<J>(
+    blockwheel_kv_meister: blockwheel_kv::Meister<EchoPolicy>,
+    ftd_sendegeraet: komm::Sendegeraet<ftd_sklave::Order>,
+    thread_pool: edeltraud::Handle<J>,
+    lookup_range_buffer: usize,
+    expiry: Arc<Mutex<expiry::ExpiryIndex>>,
+    request_range: proto::RequestLookupKindRange,
+)
+    -> Result<(), Error>
+where J: From<job::BlockwheelKvPerformerSklaveJob>,
+      J: From<job::BlockwheelKvLookupRangeMergeSklaveJob>,
+      J: Send + 'static,
+{
+    let proto::RequestLookupKindRange {
+        range_from,
+        range_to,
+        limit,
+        order,
+        reply_tx,
+        error_tx,
+    } = request_range;
+
+    if limit == Some(0) {
+        if let Some(sink) = RangeSink::start(reply_tx, lookup_range_buffer, limit, order) {
+            sink.finish().await;
+        }
+        return Ok(());
+    }
+
+    let (
+        mut kv_items_stream_tx,
+        mut kv_items_stream_rx,
+    ) = oneshot::channel();
+    let befehl_result = blockwheel_kv_meister
+        .lookup_range(
+            (range_from, range_to),
+            ftd_sendegeraet.rueckkopplung(
+                ftd_sklave::LookupKind::Range(
+                    ftd_sklave::LookupKindRange { kv_items_stream_tx, },
+                ),
+            ),
+            &thread_pool,
+        );
+    let stream = match befehl_result {
+        Ok(stream) =>
+            stream,
+        Err(error) => {
+            reply_backend_error(error_tx, error);
+            return Ok(());
+        },
+    };
+
+    let mut sink = match RangeSink::start(reply_tx, lookup_range_buffer, limit, order) {
+        Some(sink) =>
+            sink,
+        None =>
+            return Ok(()),
+    };
+
+    // blockwheel_kv only iterates in ascending order, so a descending scan
+    // has to go through the whole range before emitting anything, keeping
+    // just the last `limit` items
+    let mut descending_items = VecDeque::new();
+    let mut items_sent = 0;
+    loop {
+        match kv_items_stream_rx.await {
+            Ok(komm::Streamzeug::Zeug {
+                zeug: key_value_pair,
+                mehr,
+            }) => {
+                // request the next item before handing the current one over,
+                // so blockwheel_kv keeps producing while the client consumes
+                (kv_items_stream_tx, kv_items_stream_rx) = oneshot::channel();
+                let stream_echo = ftd_sendegeraet
+                    .rueckkopplung(
+                        ftd_sklave::LookupKind::Range(
+                            ftd_sklave::LookupKindRange { kv_items_stream_tx, },
+                        ),
+                    );
+                if let Err(error) = stream.next(stream_echo, mehr.into()) {
+                    // dropping the sink makes the client stream end with an error
+                    log::warn!("lookup range next failed, interrupting stream: {:?}", error);
+                    return Ok(());
+                }
+                if !sink.accepts(&key_value_pair) || reserved::is_reserved(&key_value_pair.key) {
+                    continue;
+                }
+                let is_expired = expiry::lock(&expiry).is_expired(&key_value_pair.key, SystemTime::now());
+                if is_expired {
+                    continue;
+                }
+                match order {
+                    ScanOrder::Ascending => {
+                        if !sink.push(key_value_pair).await {
+                            return Ok(());
+                        }
+                        items_sent += 1;
+                        if limit == Some(items_sent) {
+                            sink.finish().await;
+                            return Ok(());
+                        }
+                    },
+                    ScanOrder::Descending => {
+                        if limit == Some(descending_items.len()) {
+                            descending_items.pop_front();
+                        }
+                        descending_items.push_back(key_value_pair);
+                    },
+                }
+            },
+            Ok(komm::Streamzeug::NichtMehr(..)) => {
+                for key_value_pair in descending_items.into_iter().rev() {
+                    if !sink.push(key_value_pair).await {
+                        return Ok(());
+                    }
+                }
+                sink.finish().await;
+                return Ok(());
+            },
+            Err(oneshot::Canceled) =>
+                return Err(Error::BlockwheelKvMeisterHasGoneDuringLookupRange),
+        }
+    }
+}
+
+/// Removes keys within the range of [`proto::RequestRemoveRange`] in chunks
+/// of at most [`REMOVE_RANGE_CHUNK_SIZE`]. Each chunk goes to the busyloop as
+/// a write batch through `chunks_tx`, and the scan goes on once the chunk is
+/// applied, so neither side holds more than one chunk of keys.
+async fn remove_range_task<J>(
+    blockwheel_kv_meister: blockwheel_kv::Meister<EchoPolicy>,
+    ftd_sendegeraet: komm::Sendegeraet<ftd_sklave::Order>,
+    thread_pool: edeltraud::Handle<J>,
+    lookup_range_buffer: usize,
+    expiry: Arc<Mutex<expiry::ExpiryIndex>>,
+    request_remove_range: proto::RequestRemoveRange,
+    chunks_tx: mpsc::UnboundedSender<proto::RequestApplyBatch>,
+)
+    -> Result<(), Error>
+where J: From<job::BlockwheelKvPerformerSklaveJob>,
+      J: From<job::BlockwheelKvLookupRangeMergeSklaveJob>,
+      J: Send + 'static,
+{
+    enum Outcome {
+        Removed(usize),
+        Failed(blockwheel_kv::Error),
+        Interrupted,
+    }
+
+    let proto::RequestRemoveRange {
+        range_from,
+        range_to,
+        reply_tx,
+        error_tx,
+    } = request_remove_range;
+
+    let (keys_reply_tx, keys_reply_rx) = oneshot::channel();
+    let (scan_error_tx, mut scan_error_rx) = oneshot::channel();
+    let scan = lookup_range_task(
+        blockwheel_kv_meister,
+        ftd_sendegeraet,
+        thread_pool,
+        lookup_range_buffer,
+        expiry,
+        proto::RequestLookupKindRange {
+            range_from,
+            range_to,
+            limit: None,
+            order: ScanOrder::Ascending,
+            reply_tx: proto::RangeReplyTx::Keys(keys_reply_tx),
+            error_tx: scan_error_tx,
+        },
+    );
+    let remove = async move {
+        let mut keys_stream = match keys_reply_rx.await {
+            Ok(keys_stream) =>
+                keys_stream,
+            Err(oneshot::Canceled) =>
+                return Outcome::Interrupted,
+        };
+        let mut removed = 0;
+        loop {
+            let mut batch = WriteBatch::new();
+            while batch.len() < REMOVE_RANGE_CHUNK_SIZE {
+                match keys_stream.next().await {
+                    Some(Ok(key)) => {
+                        batch.remove(key);
+                    },
+                    Some(Err(..)) =>
+                        return Outcome::Interrupted,
+                    None =>
+                        break,
+                }
+            }
+            if batch.is_empty() {
+                return Outcome::Removed(removed);
+            }
+            let (chunk_reply_tx, chunk_reply_rx) = oneshot::channel();
+            let (chunk_error_tx, chunk_error_rx) = oneshot::channel();
+            let request_apply_batch = proto::RequestApplyBatch {
+                batch,
+                reply_tx: chunk_reply_tx,
+                error_tx: chunk_error_tx,
+            };
+            if let Err(_send_error) = chunks_tx.unbounded_send(request_apply_batch) {
+                return Outcome::Interrupted;
+            }
+            match chunk_reply_rx.await {
+                Ok(outcomes) =>
+                    removed += outcomes.len(),
+                Err(oneshot::Canceled) =>
+                    return match chunk_error_rx.await {
+                        Ok(error) =>
+                            Outcome::Failed(error),
+                        Err(oneshot::Canceled) =>
+                            Outcome::Interrupted,
+                    },
+            }
+        }
+    };
+    let (scan_result, outcome) = future::join(scan, remove).await;
+    scan_result?;
+
+    match outcome {
+        Outcome::Removed(removed) =>
+            if let Err(_send_error) = reply_tx.send(removed) {
+                log::debug!("client is gone during RequestRemoveRange");
+            },
+        Outcome::Failed(error) =>
+            reply_backend_error(error_tx, error),
+        // the scan has failed, or the gen_server is going down
+        Outcome::Interrupted =>
+            if let Ok(Some(error)) = scan_error_rx.try_recv() {
+                reply_backend_error(error_tx, error);
+            },
+    }
+    Ok(())
+}
+
+/// Write submitted through [`Writer`].
+enum Write {
+    Insert {
+        key: kv::Key,
+        value: kv::Value,
+        expires_at: Option<SystemTime>,
+        stamp: ftd_sklave::InsertStamp,
+    },
+    Remove {
+        key: kv::Key,
+        stamp: ftd_sklave::RemoveStamp,
+    },
+}
+
+impl Write {
+    fn key(&self) -> &kv::Key {
+        match self {
+            Write::Insert { key, .. } | Write::Remove { key, .. } =>
+                key,
+        }
+    }
+
+    fn op(&self) -> WriteBatchOp {
+        match self {
+            Write::Insert { key, value, .. } =>
+                WriteBatchOp::Insert { key: key.clone(), value: value.clone(), },
+            Write::Remove { key, .. } =>
+                WriteBatchOp::Remove { key: key.clone(), },
+        }
+    }
+}
+
+#[derive(Debug)]
+enum WriteError {
+    /// Nothing is submitted, the error goes to the client.
+    NotSubmitted(blockwheel_kv::Error),
+    /// Only a part is submitted: the gen_server has to restart, so that the
+    /// batch intent is applied again before anything else is served.
+    Interrupted(blockwheel_kv::Error),
+    /// The intent or the batch is submitted but could not be flushed, handled
+    /// as `Interrupted`.
+    Unflushed(Error),
+}
+
+/// Submits writes to `blockwheel_kv` together with the expiry records of
+/// their keys. Whenever this takes more than one write they all go under a
+/// batch intent, see [`reserved`].
+struct Writer<J> {
+    blockwheel_kv_meister: blockwheel_kv::Meister<EchoPolicy>,
+    ftd_sendegeraet: komm::Sendegeraet<ftd_sklave::Order>,
+    thread_pool: edeltraud::Handle<J>,
+    blocks_pool: BytesPool,
+    expiry: Arc<Mutex<expiry::ExpiryIndex>>,
+    next_batch_id: u64,
+}
+
+impl<J> Writer<J>
+where J: From<job::BlockwheelKvPerformerSklaveJob>,
+      J: From<job::BlockwheelKvLookupRangeMergeSklaveJob>,
+      J: Send + 'static,
+{
+    /// Writes with a deadline set their expiry record, other writes remove
+    /// the record of their key if there is one.
+    async fn submit(&mut self, writes: Vec<Write>) -> Result<(), WriteError> {
+        let mut submissions = Vec::with_capacity(writes.len());
+        let mut deadlines = Vec::with_capacity(writes.len());
+        {
+            let expiry = expiry::lock(&self.expiry);
+            for write in writes {
+                let expires_at = match &write {
+                    Write::Insert { expires_at, .. } =>
+                        *expires_at,
+                    Write::Remove { .. } =>
+                        None,
+                };
+                let key = write.key();
+                match expires_at {
+                    Some(expires_at) => {
+                        let (record_key, record_value) = reserved::expiry_record(&self.blocks_pool, key, expires_at);
+                        submissions.push(Write::Insert {
+                            key: record_key,
+                            value: record_value,
+                            expires_at: None,
+                            stamp: reserved_insert_stamp(),
+                        });
+                    },
+                    None if expiry.has_deadline(key) =>
+                        submissions.push(Write::Remove {
+                            key: reserved::expiry_key(&self.blocks_pool, key),
+                            stamp: reserved_remove_stamp(),
+                        }),
+                    None =>
+                        (),
+                }
+                deadlines.push((key.clone(), expires_at));
+                submissions.push(write);
+            }
+        }
+
+        let submit_result = if submissions.len() == 1 {
+            self.submit_one(submissions.pop().unwrap())
+                .map_err(WriteError::NotSubmitted)
+        } else {
+            self.submit_atomically(submissions).await
+        };
+        if let Err(WriteError::NotSubmitted(..)) = submit_result {
+            return submit_result;
+        }
+        let mut expiry = expiry::lock(&self.expiry);
+        for (key, expires_at) in deadlines {
+            match expires_at {
+                Some(expires_at) =>
+                    expiry.set(key, expires_at),
+                None => {
+                    expiry.clear(&key);
+                },
+            }
+        }
+        submit_result
+    }
+
+    /// Each step is flushed before the next one starts, so a crash never
+    /// persists a part of the batch without its intent, nor removes the
+    /// intent of a batch that is not persisted yet.
+    async fn submit_atomically(&mut self, writes: Vec<Write>) -> Result<(), WriteError> {
+        let ops: Vec<_> = writes.iter().map(Write::op).collect();
+        let intent = reserved::BatchIntent::new(&self.blocks_pool, self.next_batch_id, &ops);
+        self.next_batch_id += 1;
+        // a failure here leaves the intent without its commit record, so the
+        // batch is not applied on recovery either
+        for (key, value) in intent.records {
+            self.submit_one(Write::Insert { key, value, expires_at: None, stamp: reserved_insert_stamp(), })
+                .map_err(WriteError::NotSubmitted)?;
+        }
+        self.flush().await
+            .map_err(WriteError::Unflushed)?;
+        for write in writes {
+            self.submit_one(write)
+                .map_err(WriteError::Interrupted)?;
+        }
+        self.flush().await
+            .map_err(WriteError::Unflushed)?;
+        for key in intent.keys {
+            self.submit_one(Write::Remove { key, stamp: reserved_remove_stamp(), })
+                .map_err(WriteError::Interrupted)?;
+        }
+        Ok(())
+    }
+
+    /// Waits until everything submitted so far is persisted.
+    async fn flush(&self) -> Result<(), Error> {
+        let (flush_tx, flush_rx) = oneshot::channel();
+        self.blockwheel_kv_meister
+            .flush(
+                self.ftd_sendegeraet.rueckkopplung(flush_tx),
+                &self.thread_pool,
+            )
+            .map_err(Error::Flush)?;
+        match flush_rx.await {
+            Ok(Flushed) =>
+                Ok(()),
+            Err(oneshot::Canceled) =>
+                Err(Error::BlockwheelKvMeisterHasGoneDuringFlush),
+        }
+    }
+
+    /// Submits a single write as it is.
+    fn submit_one(&self, write: Write) -> Result<(), blockwheel_kv::Error> {
+        match write {
+            Write::Insert { key, value, stamp, .. } =>
+                self.blockwheel_kv_meister
+                    .insert(
+                        key,
+                        value,
+                        self.ftd_sendegeraet.rueckkopplung(stamp),
+                        &self.thread_pool,
+                    ),
+            Write::Remove { key, stamp, } =>
+                self.blockwheel_kv_meister
+                    .remove(
+                        key,
+                        self.ftd_sendegeraet.rueckkopplung(stamp),
+                        &self.thread_pool,
+                    ),
+        }
+    }
+}
+
+/// Reports an error to the client if nothing is submitted. Otherwise passes
+/// it on: the gen_server restarts and completes the write from its intent,
+/// while the client resends the request after its reply is dropped.
+fn reply_write_error(error_tx: proto::RequestErrorTx, error: WriteError) -> Result<(), WriteError> {
+    match error {
+        WriteError::NotSubmitted(error) => {
+            reply_backend_error(error_tx, error);
+            Ok(())
+        },
+        error @ WriteError::Interrupted(..) | error @ WriteError::Unflushed(..) =>
+            Err(error),
+    }
+}
+
+struct PendingConditional {
+    key: kv::Key,
+    action: ConditionalAction,
+    error_tx: proto::RequestErrorTx,
+}
+
+/// Read-modify-write requests served through a pending conditional.
+enum ConditionalAction {
+    CompareAndSwap {
+        expected: Option<kv::Value>,
+        new: Option<kv::Value>,
+        reply_tx: proto::RequestCompareAndSwapReplyTx,
+    },
+    Merge {
+        operator: Arc<dyn MergeOperator>,
+        operand: Vec<u8>,
+        reply_tx: proto::RequestMergeReplyTx,
+    },
+}
+
+enum ConditionalWrite {
+    Insert {
+        value: kv::Value,
+        kind: ftd_sklave::InsertKind,
+    },
+    Remove {
+        kind: ftd_sklave::RemoveKind,
+    },
+}
+
+enum RangeSink {
+    KeyValues(mpsc::Sender<KeyValueStreamItem>),
+    Keys(mpsc::Sender<proto::KeyStreamItem>),
+    Count {
+        count: usize,
+        reply_tx: oneshot::Sender<usize>,
+    },
+}
+
+impl RangeSink {
+    fn start(
+        reply_tx: proto::RangeReplyTx,
+        lookup_range_buffer: usize,
+        limit: Option<usize>,
+        order: ScanOrder,
+    )
+        -> Option<RangeSink>
+    {
+        let maybe_sink = match reply_tx {
+            proto::RangeReplyTx::KeyValues(reply_tx) => {
+                let (key_values_tx, key_values_rx) = mpsc::channel(lookup_range_buffer);
+                reply_tx.send(LookupRange::new(key_values_rx, limit, order))
+                    .ok()
+                    .map(|()| RangeSink::KeyValues(key_values_tx))
+            },
+            proto::RangeReplyTx::Keys(reply_tx) => {
+                let (keys_tx, keys_rx) = mpsc::channel(lookup_range_buffer);
+                reply_tx.send(LookupRangeKeys::new(keys_rx))
+                    .ok()
+                    .map(|()| RangeSink::Keys(keys_tx))
+            },
+            proto::RangeReplyTx::Count(reply_tx) =>
+                Some(RangeSink::Count { count: 0, reply_tx, }),
+        };
+        if maybe_sink.is_none() {
+            log::debug!("client has canceled lookup range request");
+        }
+        maybe_sink
+    }
+
+    /// Keys-only and count-only lookups skip tombstones.
+    fn accepts(&self, key_value_pair: &kv::KeyValuePair<kv::Value>) -> bool {
+        match self {
+            RangeSink::KeyValues(..) =>
+                true,
+            RangeSink::Keys(..) | RangeSink::Count { .. } =>
+                matches!(key_value_pair.value_cell.cell, kv::Cell::Value(..)),
+        }
+    }
+
+    /// Returns `false` if the client is gone.
+    async fn push(&mut self, key_value_pair: kv::KeyValuePair<kv::Value>) -> bool {
+        let send_result = match self {
+            RangeSink::KeyValues(key_values_tx) =>
+                key_values_tx.send(KeyValueStreamItem::KeyValue(key_value_pair)).await,
+            RangeSink::Keys(keys_tx) =>
+                keys_tx.send(proto::KeyStreamItem::Key(key_value_pair.key)).await,
+            RangeSink::Count { count, .. } => {
+                *count += 1;
+                Ok(())
+            },
+        };
+        if let Err(_send_error) = send_result {
+            log::debug!("client has dropped kv items stream tx, canceling");
+            return false;
+        }
+        true
+    }
+
+    async fn finish(self) {
+        let client_is_gone = match self {
+            RangeSink::KeyValues(mut key_values_tx) =>
+                key_values_tx.send(KeyValueStreamItem::NoMore).await.is_err(),
+            RangeSink::Keys(mut keys_tx) =>
+                keys_tx.send(proto::KeyStreamItem::NoMore).await.is_err(),
+            RangeSink::Count { count, reply_tx, } =>
+                reply_tx.send(count).is_err(),
+        };
+        if client_is_gone {
+            log::debug!("client has dropped kv items stream tx, canceling");
+        }
+    }
+}
+
+struct Watcher {
+    range_from: Bound<kv::Key>,
+    range_to: Bound<kv::Key>,
+    events_tx: WatchSender,
+}
+
+fn watch_subscribers(watchers: &mut Vec<Watcher>, key: &kv::Key) -> Vec<WatchSender> {
+    watchers.retain(|watcher| !watcher.events_tx.is_closed());
+    watchers
+        .iter()
+        .filter(|watcher| (watcher.range_from.as_ref(), watcher.range_to.as_ref()).contains(key))
+        .map(|watcher| watcher.events_tx.clone())
+        .collect()
+}
+
+fn insert_stamp(
+    watchers: &mut Vec<Watcher>,
+    kind: ftd_sklave::InsertKind,
+    key: &kv::Key,
+    value: &kv::Value,
+)
+    -> ftd_sklave::InsertStamp
+{
+    let events_txs = watch_subscribers(watchers, key);
+    let watch = if events_txs.is_empty() {
+        None
+    } else {
+        Some(ftd_sklave::WatchInsert { key: key.clone(), value: value.clone(), events_txs, })
+    };
+    ftd_sklave::InsertStamp { kind, watch, }
+}
+
+fn remove_stamp(watchers: &mut Vec<Watcher>, kind: ftd_sklave::RemoveKind, key: &kv::Key) -> ftd_sklave::RemoveStamp {
+    let events_txs = watch_subscribers(watchers, key);
+    let watch = if events_txs.is_empty() {
+        None
+    } else {
+        Some(ftd_sklave::WatchRemove { key: key.clone(), events_txs, })
+    };
+    ftd_sklave::RemoveStamp { kind, watch, }
+}
+
+fn reserved_insert_stamp() -> ftd_sklave::InsertStamp {
+    ftd_sklave::InsertStamp { kind: ftd_sklave::InsertKind::Reserved, watch: None, }
+}
+
+fn reserved_remove_stamp() -> ftd_sklave::RemoveStamp {
+    ftd_sklave::RemoveStamp { kind: ftd_sklave::RemoveKind::Reserved, watch: None, }
+}
+
+fn reply_backend_error(error_tx: proto::RequestErrorTx, error: blockwheel_kv::Error) {
+    log::warn!("blockwheel_kv request failed: {:?}", error);
+    if let Err(_send_error) = error_tx.send(error) {
+        log::debug!("client is gone before backend error is reported");
+    }
+}
+
+async fn lookup_single_task<S>(
+    _stream: S,
+    stream_id: komm::StreamId,
+    feedback_rx: oneshot::Receiver<komm::StreamId>,
+    meister_gone_error: Error,
+)
+    -> Result<(), Error>
+{
+    match feedback_rx.await {
+        Ok(ref received_stream_id) => {
+            assert!(received_stream_id == &stream_id);
+            Ok(())
+        },
+        Err(oneshot::Canceled) =>
+            Err(meister_gone_error),
+    }
+}
@@ -0,0 +1,412 @@
+//! HTTP/JSON gateway over a [`Pid`], meant for inspecting and patching
+//! individual keys with curl.
+//!
+//! Routes:
+//!
+//! * `GET /kv/{key}`: `{"key", "value", "version"}`, `404` if absent
+//! * `PUT /kv/{key}` with the value as request body: `{"version"}`, `413` if
+//!   the body is larger than [`MAX_BODY_SIZE`]
+//! * `DELETE /kv/{key}`: `{"version"}`
+//! * `GET /range?from=&to=`: one JSON object per line (NDJSON), `from` is
+//!   inclusive, `to` exclusive, both optional
+//! * `POST /flush`: `{"flushed": true}`
+//! * `GET /info`: `{"info"}` with the debug representation of the info
+//!
+//! Keys in paths and queries are percent-decoded. With `?encoding=hex` keys,
+//! request bodies and returned keys and values are hex strings; by default
+//! they are UTF-8 and returned bytes which are not valid UTF-8 are replaced,
+//! so binary data should be accessed with `encoding=hex`.
+
+use std::{
+    convert::{
+        Infallible,
+    },
+    collections::{
+        HashMap,
+    },
+    net::{
+        SocketAddr,
+    },
+    ops::{
+        Bound,
+    },
+};
+
+use futures::{
+    future,
+    StreamExt,
+};
+
+use hyper::{
+    service::{
+        make_service_fn,
+        service_fn,
+    },
+    header,
+    Body,
+    Method,
+    Request,
+    Response,
+    Server,
+    StatusCode,
+};
+
+use serde_json::{
+    json,
+};
+
+use alloc_pool::{
+    bytes::{
+        BytesPool,
+    },
+};
+
+use crate::{
+    kv,
+    kv_util,
+    Pid,
+    InfoError,
+    InsertError,
+    LookupError,
+    LookupRangeError,
+    RemoveError,
+    FlushError,
+};
+
+/// Maps any of the [`Pid`] error enums onto an error response. Variants only
+/// some of them have are mapped by the extra arms given.
+macro_rules! error_response {
+    ($error_type:ident, $error:expr $(, $pattern:pat => $response:expr)*) => {
+        match $error {
+            error @ $error_type::GenServer(..) =>
+                json_response(StatusCode::SERVICE_UNAVAILABLE, json!({ "error": format!("{:?}", error) })),
+            error @ $error_type::Dropped { .. } =>
+                json_response(StatusCode::SERVICE_UNAVAILABLE, json!({ "error": format!("{:?}", error) })),
+            error @ $error_type::Timeout =>
+                json_response(StatusCode::GATEWAY_TIMEOUT, json!({ "error": format!("{:?}", error) })),
+            error @ $error_type::Backend(..) =>
+                json_response(StatusCode::INTERNAL_SERVER_ERROR, json!({ "error": format!("{:?}", error) })),
+            #[cfg(feature = "net")]
+            error @ $error_type::Remote(..) =>
+                json_response(StatusCode::INTERNAL_SERVER_ERROR, json!({ "error": format!("{:?}", error) })),
+            $($pattern =>
+                $response,)*
+        }
+    };
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Encoding {
+    Utf8,
+    Hex,
+}
+
+/// Request bodies larger than this are rejected with `413`. A hex encoded
+/// value takes twice its size.
+pub const MAX_BODY_SIZE: usize = 16 * 1024 * 1024;
+
+/// Serves the gateway on `addr` until the server fails.
+pub async fn serve(addr: SocketAddr, pid: Pid, blocks_pool: BytesPool) -> Result<(), hyper::Error> {
+    let make_service = make_service_fn(move |_connection| {
+        let pid = pid.clone();
+        let blocks_pool = blocks_pool.clone();
+        future::ok::<_, Infallible>(service_fn(move |request| {
+            handle(request, pid.clone(), blocks_pool.clone())
+        }))
+    });
+    Server::bind(&addr).serve(make_service).await
+}
+
+async fn handle(request: Request<Body>, mut pid: Pid, blocks_pool: BytesPool) -> Result<Response<Body>, Infallible> {
+    let query = parse_query(request.uri().query().unwrap_or(""));
+    let encoding = match query.get("encoding").map(Vec::as_slice) {
+        None | Some(b"utf8") =>
+            Encoding::Utf8,
+        Some(b"hex") =>
+            Encoding::Hex,
+        Some(..) =>
+            return Ok(bad_request("encoding should be either utf8 or hex")),
+    };
+    let path = request.uri().path().to_string();
+    let route = path.trim_start_matches('/');
+    let key_path = route.strip_prefix("kv/");
+
+    let response = match (request.method().clone(), route, key_path) {
+        (Method::GET, _, Some(key_path)) => {
+            let key = match decode_key(key_path, encoding, &blocks_pool) {
+                Ok(key) =>
+                    key,
+                Err(response) =>
+                    return Ok(response),
+            };
+            match pid.lookup(key.clone()).await {
+                Ok(Some(kv::ValueCell { version, cell: kv::Cell::Value(value), })) =>
+                    json_response(StatusCode::OK, json!({
+                        "key": encode_bytes(&key.key_bytes, encoding),
+                        "value": encode_bytes(&value.value_bytes, encoding),
+                        "version": version,
+                    })),
+                Ok(Some(kv::ValueCell { cell: kv::Cell::Tombstone, .. })) | Ok(None) =>
+                    json_response(StatusCode::NOT_FOUND, json!({ "error": "not found" })),
+                Err(error) =>
+                    error_response!(LookupError, error),
+            }
+        },
+        (Method::PUT, _, Some(key_path)) => {
+            let key = match decode_key(key_path, encoding, &blocks_pool) {
+                Ok(key) =>
+                    key,
+                Err(response) =>
+                    return Ok(response),
+            };
+            let body = match read_body(request).await {
+                Ok(body) =>
+                    body,
+                Err(response) =>
+                    return Ok(response),
+            };
+            let value_bytes = match encoding {
+                Encoding::Utf8 =>
+                    body.to_vec(),
+                Encoding::Hex =>
+                    match std::str::from_utf8(&body).ok().and_then(|hex| kv_util::hex_decode(hex.trim())) {
+                        Some(value_bytes) =>
+                            value_bytes,
+                        None =>
+                            return Ok(bad_request("request body is not valid hex")),
+                    },
+            };
+            let value = kv_util::value_from_slice(&blocks_pool, &value_bytes);
+            match pid.insert(key, value).await {
+                Ok(inserted) =>
+                    json_response(StatusCode::OK, json!({ "version": inserted.version })),
+                Err(error) =>
+                    error_response!(InsertError, error, InsertError::ReservedKey => bad_request("key is within the reserved keyspace")),
+            }
+        },
+        (Method::DELETE, _, Some(key_path)) => {
+            let key = match decode_key(key_path, encoding, &blocks_pool) {
+                Ok(key) =>
+                    key,
+                Err(response) =>
+                    return Ok(response),
+            };
+            match pid.remove(key).await {
+                Ok(removed) =>
+                    json_response(StatusCode::OK, json!({ "version": removed.version })),
+                Err(error) =>
+                    error_response!(RemoveError, error, RemoveError::ReservedKey => bad_request("key is within the reserved keyspace")),
+            }
+        },
+        (Method::GET, "range", None) => {
+            let bound = |name: &str, make_bound: fn(kv::Key) -> Bound<kv::Key>| match query.get(name) {
+                None =>
+                    Ok(Bound::Unbounded),
+                Some(bytes) =>
+                    key_from_bytes(bytes, encoding, &blocks_pool).map(make_bound),
+            };
+            let range_from = match bound("from", Bound::Included) {
+                Ok(range_from) =>
+                    range_from,
+                Err(response) =>
+                    return Ok(response),
+            };
+            let range_to = match bound("to", Bound::Excluded) {
+                Ok(range_to) =>
+                    range_to,
+                Err(response) =>
+                    return Ok(response),
+            };
+            match pid.lookup_range((range_from, range_to)).await {
+                Ok(lookup_range) => {
+                    let lines = lookup_range
+                        .filter_map(move |item| future::ready(ndjson_line(item, encoding)))
+                        .map(Ok::<_, Infallible>);
+                    Response::builder()
+                        .status(StatusCode::OK)
+                        .header(header::CONTENT_TYPE, "application/x-ndjson")
+                        .body(Body::wrap_stream(lines))
+                        .unwrap()
+                },
+                Err(error) =>
+                    error_response!(LookupRangeError, error),
+            }
+        },
+        (Method::POST, "flush", None) =>
+            match pid.flush_all().await {
+                Ok(..) =>
+                    json_response(StatusCode::OK, json!({ "flushed": true })),
+                Err(error) =>
+                    error_response!(FlushError, error),
+            },
+        (Method::GET, "info", None) =>
+            match pid.info().await {
+                Ok(info) =>
+                    json_response(StatusCode::OK, json!({ "info": format!("{:?}", info) })),
+                Err(error) =>
+                    error_response!(InfoError, error),
+            },
+        _ =>
+            json_response(StatusCode::NOT_FOUND, json!({ "error": "no such route" })),
+    };
+    Ok(response)
+}
+
+/// Live entries become `{"key", "value", "version"}` lines, tombstones are
+/// skipped, an error becomes a final `{"error"}` line.
+/// Reads the whole request body, up to [`MAX_BODY_SIZE`]. The declared
+/// content length is checked first, so an oversized body is usually
+/// rejected before any of it is read.
+async fn read_body(request: Request<Body>) -> Result<Vec<u8>, Response<Body>> {
+    let content_length = request.headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok());
+    if matches!(content_length, Some(length) if length > MAX_BODY_SIZE as u64) {
+        return Err(payload_too_large());
+    }
+    let mut body = request.into_body();
+    let mut bytes = Vec::new();
+    while let Some(chunk_result) = body.next().await {
+        let chunk = chunk_result
+            .map_err(|error| bad_request(&format!("failed to read request body: {}", error)))?;
+        if bytes.len() + chunk.len() > MAX_BODY_SIZE {
+            return Err(payload_too_large());
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+    Ok(bytes)
+}
+
+fn ndjson_line(item: Result<kv::KeyValuePair<kv::Value>, LookupRangeError>, encoding: Encoding) -> Option<Vec<u8>> {
+    let object = match item {
+        Ok(kv::KeyValuePair { key, value_cell: kv::ValueCell { version, cell: kv::Cell::Value(value), }, }) =>
+            json!({
+                "key": encode_bytes(&key.key_bytes, encoding),
+                "value": encode_bytes(&value.value_bytes, encoding),
+                "version": version,
+            }),
+        Ok(kv::KeyValuePair { value_cell: kv::ValueCell { cell: kv::Cell::Tombstone, .. }, .. }) =>
+            return None,
+        Err(error) =>
+            json!({ "error": format!("{:?}", error) }),
+    };
+    let mut line = object.to_string().into_bytes();
+    line.push(b'\n');
+    Some(line)
+}
+
+fn decode_key(key_path: &str, encoding: Encoding, blocks_pool: &BytesPool) -> Result<kv::Key, Response<Body>> {
+    match percent_decode(key_path) {
+        Some(bytes) =>
+            key_from_bytes(&bytes, encoding, blocks_pool),
+        None =>
+            Err(bad_request("key is not properly percent-encoded")),
+    }
+}
+
+fn key_from_bytes(bytes: &[u8], encoding: Encoding, blocks_pool: &BytesPool) -> Result<kv::Key, Response<Body>> {
+    match encoding {
+        Encoding::Utf8 =>
+            Ok(kv_util::key_from_slice(blocks_pool, bytes)),
+        Encoding::Hex =>
+            match std::str::from_utf8(bytes).ok().and_then(kv_util::hex_decode) {
+                Some(key_bytes) =>
+                    Ok(kv_util::key_from_slice(blocks_pool, &key_bytes)),
+                None =>
+                    Err(bad_request("key is not valid hex")),
+            },
+    }
+}
+
+fn encode_bytes(bytes: &[u8], encoding: Encoding) -> String {
+    match encoding {
+        Encoding::Utf8 =>
+            String::from_utf8_lossy(bytes).into_owned(),
+        Encoding::Hex =>
+            kv_util::hex_encode(bytes),
+    }
+}
+
+fn json_response(status: StatusCode, body: serde_json::Value) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+fn bad_request(message: &str) -> Response<Body> {
+    json_response(StatusCode::BAD_REQUEST, json!({ "error": message }))
+}
+
+fn payload_too_large() -> Response<Body> {
+    let message = format!("request body is larger than {} bytes", MAX_BODY_SIZE);
+    json_response(StatusCode::PAYLOAD_TOO_LARGE, json!({ "error": message }))
+}
+
+fn parse_query(query: &str) -> HashMap<String, Vec<u8>> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .filter_map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            let name = String::from_utf8(percent_decode(&name.replace('+', " "))?).ok()?;
+            let value = percent_decode(&value.replace('+', " "))?;
+            Some((name, value))
+        })
+        .collect()
+}
+
+fn percent_decode(input: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(input.len());
+    let mut input_bytes = input.bytes();
+    while let Some(byte) = input_bytes.next() {
+        if byte == b'%' {
+            let high = input_bytes.next()?;
+            let low = input_bytes.next()?;
+            let hex = [high, low];
+            let decoded = kv_util::hex_decode(std::str::from_utf8(&hex).ok()?)?;
+            bytes.extend_from_slice(&decoded);
+        } else {
+            bytes.push(byte);
+        }
+    }
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::{
+        header,
+        Body,
+        Request,
+        StatusCode,
+    };
+
+    use super::{
+        read_body,
+        MAX_BODY_SIZE,
+    };
+
+    #[tokio::test]
+    async fn oversized_bodies_are_rejected() {
+        let request = Request::new(Body::from(b"value".to_vec()));
+        assert_eq!(read_body(request).await.unwrap(), b"value");
+
+        let request = Request::builder()
+            .header(header::CONTENT_LENGTH, MAX_BODY_SIZE + 1)
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(read_body(request).await.unwrap_err().status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        // no content length: the body is cut off while it is read
+        let (mut body_tx, body) = Body::channel();
+        let request = Request::new(body);
+        tokio::spawn(async move {
+            let chunk = vec![0; MAX_BODY_SIZE / 2 + 1];
+            while body_tx.send_data(chunk.clone().into()).await.is_ok() {}
+        });
+        assert_eq!(read_body(request).await.unwrap_err().status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+}
@@ -0,0 +1,135 @@
+use alloc_pool::{
+    bytes::{
+        BytesPool,
+    },
+};
+
+use crate::{
+    kv,
+};
+
+pub fn key_from_slice(blocks_pool: &BytesPool, bytes: &[u8]) -> kv::Key {
+    let mut key_bytes = blocks_pool.lend();
+    key_bytes.extend_from_slice(bytes);
+    kv::Key { key_bytes: key_bytes.freeze(), }
+}
+
+pub fn value_from_slice(blocks_pool: &BytesPool, bytes: &[u8]) -> kv::Value {
+    let mut value_bytes = blocks_pool.lend();
+    value_bytes.extend_from_slice(bytes);
+    kv::Value { value_bytes: value_bytes.freeze(), }
+}
+
+/// Returns the smallest key greater than every key starting with `prefix`,
+/// or `None` if there is no such key (the prefix is empty or all `0xff`).
+pub fn prefix_upper_bound(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut upper_bound = prefix.to_vec();
+    while let Some(last_byte) = upper_bound.pop() {
+        if last_byte < 0xff {
+            upper_bound.push(last_byte + 1);
+            return Some(upper_bound);
+        }
+    }
+    None
+}
+
+pub fn hex_encode(bytes: &[u8]) -> String {
+    const DIGITS: &[u8; 16] = b"0123456789abcdef";
+    let mut hex = String::with_capacity(bytes.len() * 2);
+    for &byte in bytes {
+        hex.push(DIGITS[(byte >> 4) as usize] as char);
+        hex.push(DIGITS[(byte & 0x0f) as usize] as char);
+    }
+    hex
+}
+
+/// Returns `None` unless `hex` is an even number of hex digits.
+pub fn hex_decode(hex: &str) -> Option<Vec<u8>> {
+    fn digit(byte: u8) -> Option<u8> {
+        match byte {
+            b'0' ..= b'9' =>
+                Some(byte - b'0'),
+            b'a' ..= b'f' =>
+                Some(byte - b'a' + 10),
+            b'A' ..= b'F' =>
+                Some(byte - b'A' + 10),
+            _ =>
+                None,
+        }
+    }
+
+    let hex = hex.as_bytes();
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    hex.chunks(2)
+        .map(|pair| Some(digit(pair[0])? << 4 | digit(pair[1])?))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        prefix_upper_bound,
+        hex_encode,
+        hex_decode,
+    };
+
+    #[test]
+    fn prefix_upper_bound_increments_last_byte() {
+        assert_eq!(prefix_upper_bound(b"abc"), Some(b"abd".to_vec()));
+        assert_eq!(prefix_upper_bound(&[0x00]), Some(vec![0x01]));
+        assert_eq!(prefix_upper_bound(&[0x12, 0xfe]), Some(vec![0x12, 0xff]));
+    }
+
+    #[test]
+    fn prefix_upper_bound_carries_over_trailing_0xff() {
+        assert_eq!(prefix_upper_bound(&[0x12, 0xff]), Some(vec![0x13]));
+        assert_eq!(prefix_upper_bound(&[0x00, 0xff, 0xff, 0xff]), Some(vec![0x01]));
+        assert_eq!(prefix_upper_bound(&[0xfe, 0xff]), Some(vec![0xff]));
+    }
+
+    #[test]
+    fn prefix_upper_bound_is_unbounded_for_empty_and_all_0xff() {
+        assert_eq!(prefix_upper_bound(&[]), None);
+        assert_eq!(prefix_upper_bound(&[0xff]), None);
+        assert_eq!(prefix_upper_bound(&[0xff, 0xff, 0xff]), None);
+    }
+
+    #[test]
+    fn prefix_upper_bound_is_above_every_key_with_prefix() {
+        let prefix = [0x41, 0xff];
+        let upper_bound = prefix_upper_bound(&prefix).unwrap();
+        for suffix in [&[][..], &[0x00], &[0xff, 0xff, 0xff]] {
+            let key = [&prefix[..], suffix].concat();
+            assert!(key < upper_bound);
+        }
+        assert!(prefix.as_slice() < upper_bound.as_slice());
+        assert!(!upper_bound.starts_with(&prefix));
+    }
+
+    #[test]
+    fn hex_decode_accepts_both_cases() {
+        assert_eq!(hex_decode(""), Some(vec![]));
+        assert_eq!(hex_decode("00ff7f"), Some(vec![0x00, 0xff, 0x7f]));
+        assert_eq!(hex_decode("DeadBEEF"), Some(vec![0xde, 0xad, 0xbe, 0xef]));
+    }
+
+    #[test]
+    fn hex_decode_rejects_malformed_input() {
+        assert_eq!(hex_decode("abc"), None);
+        assert_eq!(hex_decode("0g"), None);
+        assert_eq!(hex_decode("0x12"), None);
+        assert_eq!(hex_decode(" 12"), None);
+        assert_eq!(hex_decode("é"), None);
+    }
+
+    #[test]
+    fn hex_roundtrip() {
+        let bytes: Vec<u8> = (0 ..= 255).collect();
+        let hex = hex_encode(&bytes);
+        assert_eq!(hex.len(), 512);
+        assert_eq!(&hex[.. 6], "000102");
+        assert_eq!(hex_decode(&hex), Some(bytes));
+    }
+}
@@ -1,9 +1,29 @@
 #![forbid(unsafe_code)]
 
 use std::{
+    future::{
+        Future,
+    },
+    pin::{
+        Pin,
+    },
+    task::{
+        Poll,
+        Context,
+    },
     ops::{
+        Bound,
+        RangeFull,
         RangeBounds,
     },
+    time::{
+        Instant,
+        Duration,
+        SystemTime,
+    },
+    sync::{
+        Arc,
+    },
 };
 
 use futures::{
@@ -11,11 +31,29 @@ use futures::{
         mpsc,
         oneshot,
     },
-    stream,
+    stream::{
+        self,
+        FusedStream,
+    },
+    future::{
+        self,
+        Either,
+    },
+    io::{
+        AsyncRead,
+        AsyncWrite,
+    },
+    ready,
+    pin_mut,
     SinkExt,
+    Stream,
     StreamExt,
 };
 
+use futures_timer::{
+    Delay,
+};
+
 use alloc_pool::{
     bytes::{
         BytesPool,
@@ -34,20 +72,122 @@ use blockwheel_kv::{
 
 pub mod job;
 pub mod wheels;
+pub mod watch;
+pub mod typed;
+pub mod merge;
+pub mod dump;
+
+#[cfg(feature = "standalone")]
+pub mod standalone;
+#[cfg(feature = "net")]
+pub mod wire;
+#[cfg(feature = "net")]
+pub mod server;
+#[cfg(feature = "net")]
+pub mod client;
+#[cfg(feature = "http")]
+pub mod http;
+#[cfg(feature = "cli")]
+pub mod cli;
+
+/// Byte helpers shared with the benchmarks, not part of the stable API.
+#[doc(hidden)]
+pub mod kv_util;
 
 mod proto;
+mod expiry;
 mod gen_server;
 mod ftd_sklave;
 mod echo_policy;
+mod reserved;
+
+/// Keys starting with this prefix hold the gen_server bookkeeping, such as
+/// write batch intents. Lookups and scans never return them, and writes to
+/// them are rejected with a `ReservedKey` error.
+pub const RESERVED_KEY_PREFIX: &[u8] = b"\xff\xff\xff\xff\xff\xff\xff\xffblockwheel-kv-ero\x00";
 
 pub struct GenServer {
     request_tx: mpsc::Sender<proto::Request>,
     fused_request_rx: stream::Fuse<mpsc::Receiver<proto::Request>>,
+    params: GenServerParams,
+}
+
+#[derive(Clone, Debug)]
+pub struct GenServerParams {
+    /// Capacity of the channel carrying range lookup items to the client.
+    ///
+    /// `blockwheel_kv` hands out one item per request, so a scan always has a
+    /// single item in flight; this only decides how far the scan may run
+    /// ahead of a slow client before it pauses.
+    pub lookup_range_buffer: usize,
+    /// What to do when the gen_server fails. On restart the wheels are
+    /// reopened from the same files, interrupted write batches are completed
+    /// and pending requests are dropped, so clients resend them according to
+    /// their [`RetryPolicy`].
+    ///
+    /// Restarts after a second by default. With
+    /// `ero::RestartStrategy::InstantCrash` any backend failure in the middle
+    /// of a write takes the store down for good.
+    pub restart_strategy: ero::RestartStrategy,
+    /// How many events may wait for a [`watch::Watch`] subscriber before it
+    /// is dropped with [`WatchError::Lagged`].
+    pub watch_buffer: usize,
+}
+
+impl Default for GenServerParams {
+    fn default() -> Self {
+        Self {
+            lookup_range_buffer: 64,
+            restart_strategy: ero::RestartStrategy::Delay {
+                restart_after: Duration::from_secs(1),
+            },
+            watch_buffer: 1024,
+        }
+    }
 }
 
 #[derive(Clone)]
 pub struct Pid {
     request_tx: mpsc::Sender<proto::Request>,
+    retry_policy: RetryPolicy,
+}
+
+/// Controls how a [`Pid`] resends a request whose reply has been dropped by
+/// the gen_server (for example because it restarted).
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    /// Total number of attempts including the first one, `None` for no limit.
+    pub max_attempts: Option<usize>,
+    /// Delay before the first resend, doubled after each further attempt.
+    /// Zero disables the backoff altogether: every resend happens right away.
+    pub backoff_initial: Duration,
+    /// Upper limit for the delay between resends, the first one included.
+    pub backoff_max: Duration,
+}
+
+impl RetryPolicy {
+    /// Resend without delay until the request succeeds.
+    pub fn unlimited() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: None,
+            backoff_initial: Duration::ZERO,
+            backoff_max: Duration::ZERO,
+        }
+    }
+
+    /// Never resend: a dropped request fails after the first attempt.
+    pub fn never() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: Some(1),
+            ..RetryPolicy::unlimited()
+        }
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self::unlimited()
+    }
 }
 
 impl Default for GenServer {
@@ -58,16 +198,22 @@ impl Default for GenServer {
 
 impl GenServer {
     pub fn new() -> GenServer {
+        Self::with_params(GenServerParams::default())
+    }
+
+    pub fn with_params(params: GenServerParams) -> GenServer {
         let (request_tx, request_rx) = mpsc::channel(0);
         GenServer {
             request_tx,
             fused_request_rx: request_rx.fuse(),
+            params,
         }
     }
 
     pub fn pid(&self) -> Pid {
         Pid {
             request_tx: self.request_tx.clone(),
+            retry_policy: RetryPolicy::default(),
         }
     }
 
@@ -88,6 +234,7 @@ impl GenServer {
     {
         gen_server::run(
             self.fused_request_rx,
+            self.params,
             parent_supervisor,
             params,
             blocks_pool,
@@ -98,38 +245,442 @@ impl GenServer {
     }
 }
 
+/// Failure reported by `blockwheel_kv`.
+#[derive(Debug)]
+pub enum BackendError {
+    /// Reported to a [`Pid`] by its gen_server.
+    Local(blockwheel_kv::Error),
+    /// Reported to a [`client::RemotePid`] by the server, in the debug
+    /// representation of `blockwheel_kv::Error`.
+    #[cfg(feature = "net")]
+    Remote(String),
+}
+
 #[derive(Debug)]
 pub enum InfoError {
     GenServer(ero::NoProcError),
+    Backend(BackendError),
+    Timeout,
+    Dropped { attempts: usize, },
+    /// Connection or protocol failure, only returned by [`client::RemotePid`].
+    #[cfg(feature = "net")]
+    Remote(client::RemoteError),
+}
+
+impl From<RequestError> for InfoError {
+    fn from(error: RequestError) -> Self {
+        match error {
+            RequestError::GenServer =>
+                InfoError::GenServer(ero::NoProcError),
+            RequestError::Backend(error) =>
+                InfoError::Backend(BackendError::Local(error)),
+            RequestError::Timeout =>
+                InfoError::Timeout,
+            RequestError::Dropped { attempts, } =>
+                InfoError::Dropped { attempts, },
+        }
+    }
 }
 
 #[derive(Debug)]
 pub enum InsertError {
     GenServer(ero::NoProcError),
+    Backend(BackendError),
+    Timeout,
+    Dropped { attempts: usize, },
+    /// The key is within [`RESERVED_KEY_PREFIX`], nothing is sent.
+    ReservedKey,
+    /// Connection or protocol failure, only returned by [`client::RemotePid`].
+    #[cfg(feature = "net")]
+    Remote(client::RemoteError),
+}
+
+impl From<RequestError> for InsertError {
+    fn from(error: RequestError) -> Self {
+        match error {
+            RequestError::GenServer =>
+                InsertError::GenServer(ero::NoProcError),
+            RequestError::Backend(error) =>
+                InsertError::Backend(BackendError::Local(error)),
+            RequestError::Timeout =>
+                InsertError::Timeout,
+            RequestError::Dropped { attempts, } =>
+                InsertError::Dropped { attempts, },
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum InsertWithTtlError {
+    GenServer(ero::NoProcError),
+    Backend(BackendError),
+    Timeout,
+    Dropped { attempts: usize, },
+    /// The key is within [`RESERVED_KEY_PREFIX`], nothing is sent.
+    ReservedKey,
+    /// The expiry deadline does not fit into the persisted representation.
+    TtlOutOfRange,
+    /// Connection or protocol failure, only returned by [`client::RemotePid`].
+    #[cfg(feature = "net")]
+    Remote(client::RemoteError),
+}
+
+impl From<RequestError> for InsertWithTtlError {
+    fn from(error: RequestError) -> Self {
+        match error {
+            RequestError::GenServer =>
+                InsertWithTtlError::GenServer(ero::NoProcError),
+            RequestError::Backend(error) =>
+                InsertWithTtlError::Backend(BackendError::Local(error)),
+            RequestError::Timeout =>
+                InsertWithTtlError::Timeout,
+            RequestError::Dropped { attempts, } =>
+                InsertWithTtlError::Dropped { attempts, },
+        }
+    }
 }
 
 #[derive(Debug)]
 pub enum LookupError {
     GenServer(ero::NoProcError),
+    Backend(BackendError),
+    Timeout,
+    Dropped { attempts: usize, },
+    /// Connection or protocol failure, only returned by [`client::RemotePid`].
+    #[cfg(feature = "net")]
+    Remote(client::RemoteError),
+}
+
+impl From<RequestError> for LookupError {
+    fn from(error: RequestError) -> Self {
+        match error {
+            RequestError::GenServer =>
+                LookupError::GenServer(ero::NoProcError),
+            RequestError::Backend(error) =>
+                LookupError::Backend(BackendError::Local(error)),
+            RequestError::Timeout =>
+                LookupError::Timeout,
+            RequestError::Dropped { attempts, } =>
+                LookupError::Dropped { attempts, },
+        }
+    }
 }
 
 #[derive(Debug)]
 pub enum LookupRangeError {
     GenServer(ero::NoProcError),
+    Backend(BackendError),
+    Timeout,
+    Dropped { attempts: usize, },
+    /// Connection or protocol failure, only returned by [`client::RemotePid`].
+    #[cfg(feature = "net")]
+    Remote(client::RemoteError),
+}
+
+impl From<RequestError> for LookupRangeError {
+    fn from(error: RequestError) -> Self {
+        match error {
+            RequestError::GenServer =>
+                LookupRangeError::GenServer(ero::NoProcError),
+            RequestError::Backend(error) =>
+                LookupRangeError::Backend(BackendError::Local(error)),
+            RequestError::Timeout =>
+                LookupRangeError::Timeout,
+            RequestError::Dropped { attempts, } =>
+                LookupRangeError::Dropped { attempts, },
+        }
+    }
 }
 
 #[derive(Debug)]
 pub enum RemoveError {
     GenServer(ero::NoProcError),
+    Backend(BackendError),
+    Timeout,
+    Dropped { attempts: usize, },
+    /// The key is within [`RESERVED_KEY_PREFIX`], nothing is sent.
+    ReservedKey,
+    /// Connection or protocol failure, only returned by [`client::RemotePid`].
+    #[cfg(feature = "net")]
+    Remote(client::RemoteError),
+}
+
+impl From<RequestError> for RemoveError {
+    fn from(error: RequestError) -> Self {
+        match error {
+            RequestError::GenServer =>
+                RemoveError::GenServer(ero::NoProcError),
+            RequestError::Backend(error) =>
+                RemoveError::Backend(BackendError::Local(error)),
+            RequestError::Timeout =>
+                RemoveError::Timeout,
+            RequestError::Dropped { attempts, } =>
+                RemoveError::Dropped { attempts, },
+        }
+    }
 }
 
 #[derive(Debug)]
 pub enum FlushError {
     GenServer(ero::NoProcError),
+    Backend(BackendError),
+    Timeout,
+    Dropped { attempts: usize, },
+    /// Connection or protocol failure, only returned by [`client::RemotePid`].
+    #[cfg(feature = "net")]
+    Remote(client::RemoteError),
+}
+
+impl From<RequestError> for FlushError {
+    fn from(error: RequestError) -> Self {
+        match error {
+            RequestError::GenServer =>
+                FlushError::GenServer(ero::NoProcError),
+            RequestError::Backend(error) =>
+                FlushError::Backend(BackendError::Local(error)),
+            RequestError::Timeout =>
+                FlushError::Timeout,
+            RequestError::Dropped { attempts, } =>
+                FlushError::Dropped { attempts, },
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ShutdownError {
+    GenServer(ero::NoProcError),
+    Backend(BackendError),
+    Timeout,
+    Dropped { attempts: usize, },
+    /// Connection or protocol failure, only returned by [`client::RemotePid`].
+    #[cfg(feature = "net")]
+    Remote(client::RemoteError),
+}
+
+impl From<RequestError> for ShutdownError {
+    fn from(error: RequestError) -> Self {
+        match error {
+            RequestError::GenServer =>
+                ShutdownError::GenServer(ero::NoProcError),
+            RequestError::Backend(error) =>
+                ShutdownError::Backend(BackendError::Local(error)),
+            RequestError::Timeout =>
+                ShutdownError::Timeout,
+            RequestError::Dropped { attempts, } =>
+                ShutdownError::Dropped { attempts, },
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum CompareAndSwapError {
+    GenServer(ero::NoProcError),
+    Backend(BackendError),
+    Timeout,
+    Dropped { attempts: usize, },
+    /// The key is within [`RESERVED_KEY_PREFIX`], nothing is sent.
+    ReservedKey,
+    /// Connection or protocol failure, only returned by [`client::RemotePid`].
+    #[cfg(feature = "net")]
+    Remote(client::RemoteError),
+}
+
+impl From<RequestError> for CompareAndSwapError {
+    fn from(error: RequestError) -> Self {
+        match error {
+            RequestError::GenServer =>
+                CompareAndSwapError::GenServer(ero::NoProcError),
+            RequestError::Backend(error) =>
+                CompareAndSwapError::Backend(BackendError::Local(error)),
+            RequestError::Timeout =>
+                CompareAndSwapError::Timeout,
+            RequestError::Dropped { attempts, } =>
+                CompareAndSwapError::Dropped { attempts, },
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ApplyBatchError {
+    GenServer(ero::NoProcError),
+    Backend(BackendError),
+    Timeout,
+    Dropped { attempts: usize, },
+    /// The key is within [`RESERVED_KEY_PREFIX`], nothing is sent.
+    ReservedKey,
+    /// Connection or protocol failure, only returned by [`client::RemotePid`].
+    #[cfg(feature = "net")]
+    Remote(client::RemoteError),
 }
 
+impl From<RequestError> for ApplyBatchError {
+    fn from(error: RequestError) -> Self {
+        match error {
+            RequestError::GenServer =>
+                ApplyBatchError::GenServer(ero::NoProcError),
+            RequestError::Backend(error) =>
+                ApplyBatchError::Backend(BackendError::Local(error)),
+            RequestError::Timeout =>
+                ApplyBatchError::Timeout,
+            RequestError::Dropped { attempts, } =>
+                ApplyBatchError::Dropped { attempts, },
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum WatchError {
+    GenServer(ero::NoProcError),
+    Timeout,
+    Dropped { attempts: usize, },
+    /// The subscriber has not kept up with the changes and is dropped.
+    Lagged,
+    /// Connection or protocol failure, only returned by [`client::RemotePid`].
+    #[cfg(feature = "net")]
+    Remote(client::RemoteError),
+}
+
+impl From<RequestError> for WatchError {
+    fn from(error: RequestError) -> Self {
+        match error {
+            RequestError::GenServer =>
+                WatchError::GenServer(ero::NoProcError),
+            RequestError::Backend(..) =>
+                unreachable!("RequestWatch is sent without an error_tx"),
+            RequestError::Timeout =>
+                WatchError::Timeout,
+            RequestError::Dropped { attempts, } =>
+                WatchError::Dropped { attempts, },
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum RemoveRangeError {
+    GenServer(ero::NoProcError),
+    Backend(BackendError),
+    Timeout,
+    Dropped { attempts: usize, },
+    /// The key is within [`RESERVED_KEY_PREFIX`], nothing is sent.
+    ReservedKey,
+    /// Connection or protocol failure, only returned by [`client::RemotePid`].
+    #[cfg(feature = "net")]
+    Remote(client::RemoteError),
+}
+
+impl From<RequestError> for RemoveRangeError {
+    fn from(error: RequestError) -> Self {
+        match error {
+            RequestError::GenServer =>
+                RemoveRangeError::GenServer(ero::NoProcError),
+            RequestError::Backend(error) =>
+                RemoveRangeError::Backend(BackendError::Local(error)),
+            RequestError::Timeout =>
+                RemoveRangeError::Timeout,
+            RequestError::Dropped { attempts, } =>
+                RemoveRangeError::Dropped { attempts, },
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum MergeError {
+    GenServer(ero::NoProcError),
+    Backend(BackendError),
+    Timeout,
+    /// The gen_server went away after the merge was submitted, so whether it
+    /// has been applied is unknown. Merges are sent once and never resent.
+    Dropped { attempts: usize, },
+    /// The key is within [`RESERVED_KEY_PREFIX`], nothing is sent.
+    ReservedKey,
+    Rejected(merge::MergeRejected),
+    /// Connection or protocol failure, only returned by [`client::RemotePid`].
+    #[cfg(feature = "net")]
+    Remote(client::RemoteError),
+}
+
+impl From<RequestError> for MergeError {
+    fn from(error: RequestError) -> Self {
+        match error {
+            RequestError::GenServer =>
+                MergeError::GenServer(ero::NoProcError),
+            RequestError::Backend(error) =>
+                MergeError::Backend(BackendError::Local(error)),
+            RequestError::Timeout =>
+                MergeError::Timeout,
+            RequestError::Dropped { attempts, } =>
+                MergeError::Dropped { attempts, },
+        }
+    }
+}
+
+/// A set of inserts and removes applied together by [`Pid::apply_batch`].
+#[derive(Clone, Debug, Default)]
+pub struct WriteBatch {
+    ops: Vec<WriteBatchOp>,
+}
+
+#[derive(Clone, Debug)]
+pub enum WriteBatchOp {
+    Insert { key: kv::Key, value: kv::Value, },
+    Remove { key: kv::Key, },
+}
+
+#[derive(Debug)]
+pub enum WriteBatchOutcome {
+    Inserted(Inserted),
+    Removed(Removed),
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, key: kv::Key, value: kv::Value) -> &mut Self {
+        self.ops.push(WriteBatchOp::Insert { key, value, });
+        self
+    }
+
+    pub fn remove(&mut self, key: kv::Key) -> &mut Self {
+        self.ops.push(WriteBatchOp::Remove { key, });
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    pub(crate) fn into_ops(self) -> Vec<WriteBatchOp> {
+        self.ops
+    }
+}
+
+#[derive(Debug)]
+pub enum CompareAndSwapOutcome {
+    /// The current value matched and the new one has been written.
+    Swapped,
+    /// The current value did not match, nothing has been written.
+    Mismatch { current: Option<kv::Value>, },
+}
+
+/// Stream of key-value pairs for a range lookup.
+///
+/// Ends cleanly after [`KeyValueStreamItem::NoMore`] is received. If the
+/// channel is closed before that, a single `LookupRangeError::GenServer` is
+/// yielded and the stream terminates.
 pub struct LookupRange {
     pub key_values_rx: mpsc::Receiver<KeyValueStreamItem>,
+    limit: Option<usize>,
+    order: ScanOrder,
+    items_count: usize,
+    last_key: Option<kv::Key>,
+    exhausted: bool,
+    finished: bool,
 }
 
 #[derive(Clone)]
@@ -138,124 +689,895 @@ pub enum KeyValueStreamItem {
     NoMore,
 }
 
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum ScanOrder {
+    #[default]
+    Ascending,
+    /// `blockwheel_kv` iterates in ascending order only, so a descending
+    /// scan reads through the whole range inside the gen_server before the
+    /// first item is sent. With a `limit` only that many items are kept,
+    /// otherwise the whole range is buffered.
+    Descending,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ScanOptions {
+    /// Maximum number of items to return.
+    pub limit: Option<usize>,
+    pub order: ScanOrder,
+    /// Continue a previous scan right after the last key it returned. The
+    /// scan keeps the order of the one the token came from, `order` is
+    /// ignored then.
+    pub resume_from: Option<ContinuationToken>,
+}
+
+/// Opaque cursor for continuing a scan, see [`LookupRange::continuation_token`].
+#[derive(Clone, Debug)]
+pub struct ContinuationToken {
+    last_key: kv::Key,
+    order: ScanOrder,
+}
+
+impl LookupRange {
+    pub(crate) fn new(
+        key_values_rx: mpsc::Receiver<KeyValueStreamItem>,
+        limit: Option<usize>,
+        order: ScanOrder,
+    )
+        -> LookupRange
+    {
+        LookupRange {
+            key_values_rx,
+            limit,
+            order,
+            items_count: 0,
+            last_key: None,
+            exhausted: false,
+            finished: false,
+        }
+    }
+
+    /// Returns a token to resume the scan after the last item yielded so far,
+    /// or `None` if the range is known to be exhausted or nothing has been
+    /// yielded yet.
+    pub fn continuation_token(&self) -> Option<ContinuationToken> {
+        if self.exhausted {
+            return None;
+        }
+        self.last_key
+            .as_ref()
+            .map(|last_key| ContinuationToken { last_key: last_key.clone(), order: self.order, })
+    }
+}
+
+/// Stream of live keys for a keys-only range lookup, see [`Pid::lookup_range_keys`].
+pub struct LookupRangeKeys {
+    keys_rx: mpsc::Receiver<proto::KeyStreamItem>,
+    finished: bool,
+}
+
+impl LookupRangeKeys {
+    pub(crate) fn new(keys_rx: mpsc::Receiver<proto::KeyStreamItem>) -> LookupRangeKeys {
+        LookupRangeKeys { keys_rx, finished: false, }
+    }
+}
+
+impl Stream for LookupRangeKeys {
+    type Item = Result<kv::Key, LookupRangeError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.finished {
+            return Poll::Ready(None);
+        }
+        match ready!(self.keys_rx.poll_next_unpin(cx)) {
+            Some(proto::KeyStreamItem::Key(key)) =>
+                Poll::Ready(Some(Ok(key))),
+            Some(proto::KeyStreamItem::NoMore) => {
+                self.finished = true;
+                Poll::Ready(None)
+            },
+            None => {
+                self.finished = true;
+                Poll::Ready(Some(Err(LookupRangeError::GenServer(ero::NoProcError))))
+            },
+        }
+    }
+}
+
+impl FusedStream for LookupRangeKeys {
+    fn is_terminated(&self) -> bool {
+        self.finished
+    }
+}
+
+impl Stream for LookupRange {
+    type Item = Result<kv::KeyValuePair<kv::Value>, LookupRangeError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.finished {
+            return Poll::Ready(None);
+        }
+        match ready!(self.key_values_rx.poll_next_unpin(cx)) {
+            Some(KeyValueStreamItem::KeyValue(key_value_pair)) => {
+                self.items_count += 1;
+                self.last_key = Some(key_value_pair.key.clone());
+                Poll::Ready(Some(Ok(key_value_pair)))
+            },
+            Some(KeyValueStreamItem::NoMore) => {
+                self.exhausted = self.limit != Some(self.items_count);
+                self.finished = true;
+                Poll::Ready(None)
+            },
+            None => {
+                self.finished = true;
+                Poll::Ready(Some(Err(LookupRangeError::GenServer(ero::NoProcError))))
+            },
+        }
+    }
+}
+
+impl FusedStream for LookupRange {
+    fn is_terminated(&self) -> bool {
+        self.finished
+    }
+}
+
 impl Pid {
+    pub fn retry_policy(&self) -> &RetryPolicy {
+        &self.retry_policy
+    }
+
+    pub fn set_retry_policy(&mut self, retry_policy: RetryPolicy) {
+        self.retry_policy = retry_policy;
+    }
+
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Pid {
+        self.set_retry_policy(retry_policy);
+        self
+    }
+
     pub async fn info(&mut self) -> Result<Info, InfoError> {
-        loop {
-            let (reply_tx, reply_rx) = oneshot::channel();
-            self.request_tx.send(proto::Request::Info(proto::RequestInfo { reply_tx, })).await
-                .map_err(|_send_error| InfoError::GenServer(ero::NoProcError))?;
-            match reply_rx.await {
-                Ok(info) =>
-                    return Ok(info),
-                Err(oneshot::Canceled) =>
-                    (),
-            }
-        }
+        self.info_request(None).await
+    }
+
+    pub async fn info_with_deadline(&mut self, deadline: Instant) -> Result<Info, InfoError> {
+        self.info_request(Some(deadline)).await
+    }
+
+    async fn info_request(&mut self, deadline: Option<Instant>) -> Result<Info, InfoError> {
+        self.request(deadline, |reply_tx, error_tx| proto::Request::Info(proto::RequestInfo { reply_tx, error_tx, }))
+            .await
+            .map_err(InfoError::from)
     }
 
     pub async fn insert(&mut self, key: kv::Key, value: kv::Value) -> Result<Inserted, InsertError> {
-        loop {
-            let (reply_tx, reply_rx) = oneshot::channel();
-            self.request_tx
-                .send(proto::Request::Insert(proto::RequestInsert {
-                    key: key.clone(),
-                    value: value.clone(),
-                    reply_tx,
-                }))
-                .await
-                .map_err(|_send_error| InsertError::GenServer(ero::NoProcError))?;
-
-            match reply_rx.await {
-                Ok(inserted) =>
-                    return Ok(inserted),
-                Err(oneshot::Canceled) =>
-                    (),
-            }
+        if reserved::is_reserved(&key) {
+            return Err(InsertError::ReservedKey);
+        }
+        self.insert_request(key, value, None, None).await
+            .map_err(InsertError::from)
+    }
+
+    pub async fn insert_with_deadline(&mut self, key: kv::Key, value: kv::Value, deadline: Instant) -> Result<Inserted, InsertError> {
+        if reserved::is_reserved(&key) {
+            return Err(InsertError::ReservedKey);
         }
+        self.insert_request(key, value, None, Some(deadline)).await
+            .map_err(InsertError::from)
+    }
+
+    /// Inserts `value` which expires after `ttl`: once expired it is hidden
+    /// from lookups and removed by the gen_server in background. Any later
+    /// write to `key` drops the expiry.
+    ///
+    /// The expiry deadline is a wall clock time persisted together with the
+    /// value, so it survives restarts. A `ttl` taking the deadline out of the
+    /// representable range is rejected with [`InsertWithTtlError::TtlOutOfRange`].
+    pub async fn insert_with_ttl(&mut self, key: kv::Key, value: kv::Value, ttl: Duration) -> Result<Inserted, InsertWithTtlError> {
+        if reserved::is_reserved(&key) {
+            return Err(InsertWithTtlError::ReservedKey);
+        }
+        let expires_at = SystemTime::now()
+            .checked_add(ttl)
+            .filter(|expires_at| reserved::deadline_millis(*expires_at).is_some())
+            .ok_or(InsertWithTtlError::TtlOutOfRange)?;
+        self.insert_request(key, value, Some(expires_at), None).await
+            .map_err(InsertWithTtlError::from)
+    }
+
+    async fn insert_request(
+        &mut self,
+        key: kv::Key,
+        value: kv::Value,
+        expires_at: Option<SystemTime>,
+        deadline: Option<Instant>,
+    )
+        -> Result<Inserted, RequestError>
+    {
+        self
+            .request(deadline, |reply_tx, error_tx| proto::Request::Insert(proto::RequestInsert {
+                key: key.clone(),
+                value: value.clone(),
+                expires_at,
+                reply_tx,
+                error_tx,
+            }))
+            .await
+    }
+
+    /// Submits all `key_values` in a single request and resolves once every
+    /// one of them is inserted. Results are returned in the input order.
+    ///
+    /// The batch is applied atomically in the same way as
+    /// [`apply_batch`](Pid::apply_batch): a failure in the middle never leaves
+    /// only a part of it applied.
+    pub async fn insert_batch(&mut self, key_values: Vec<(kv::Key, kv::Value)>) -> Result<Vec<Inserted>, InsertError> {
+        self.insert_batch_request(key_values, None).await
+    }
+
+    pub async fn insert_batch_with_deadline(
+        &mut self,
+        key_values: Vec<(kv::Key, kv::Value)>,
+        deadline: Instant,
+    )
+        -> Result<Vec<Inserted>, InsertError>
+    {
+        self.insert_batch_request(key_values, Some(deadline)).await
+    }
+
+    async fn insert_batch_request(
+        &mut self,
+        key_values: Vec<(kv::Key, kv::Value)>,
+        deadline: Option<Instant>,
+    )
+        -> Result<Vec<Inserted>, InsertError>
+    {
+        if key_values.iter().any(|(key, _value)| reserved::is_reserved(key)) {
+            return Err(InsertError::ReservedKey);
+        }
+        self
+            .request(deadline, |reply_tx, error_tx| proto::Request::InsertBatch(proto::RequestInsertBatch {
+                key_values: key_values.clone(),
+                reply_tx,
+                error_tx,
+            }))
+            .await
+            .map_err(InsertError::from)
     }
 
     pub async fn lookup(&mut self, key: kv::Key) -> Result<Option<kv::ValueCell<kv::Value>>, LookupError> {
-        loop {
-            let (reply_tx, reply_rx) = oneshot::channel();
-            self.request_tx
-                .send(proto::Request::LookupRange(
-                    proto::RequestLookupKind::Single(
-                        proto::RequestLookupKindSingle {
-                            key: key.clone(),
-                            reply_tx,
-                        },
-                    ),
-                ))
-                .await
-                .map_err(|_send_error| LookupError::GenServer(ero::NoProcError))?;
-
-            match reply_rx.await {
-                Ok(result) =>
-                    return Ok(result),
-                Err(oneshot::Canceled) =>
-                    (),
+        self.lookup_request(key, None).await
+    }
+
+    pub async fn lookup_with_deadline(
+        &mut self,
+        key: kv::Key,
+        deadline: Instant,
+    )
+        -> Result<Option<kv::ValueCell<kv::Value>>, LookupError>
+    {
+        self.lookup_request(key, Some(deadline)).await
+    }
+
+    async fn lookup_request(
+        &mut self,
+        key: kv::Key,
+        deadline: Option<Instant>,
+    )
+        -> Result<Option<kv::ValueCell<kv::Value>>, LookupError>
+    {
+        self
+            .request(deadline, |reply_tx, error_tx| proto::Request::LookupRange(
+                proto::RequestLookupKind::Single(
+                    proto::RequestLookupKindSingle {
+                        key: key.clone(),
+                        reply_tx,
+                        error_tx,
+                    },
+                ),
+            ))
+            .await
+            .map_err(LookupError::from)
+    }
+
+    /// Looks up all `keys` in parallel and returns the results in the input order.
+    ///
+    /// If the backend fails for any of the keys, the whole request fails with
+    /// [`LookupError::Backend`] and the lookups already made are discarded.
+    pub async fn lookup_many(&mut self, keys: Vec<kv::Key>) -> Result<Vec<Option<kv::ValueCell<kv::Value>>>, LookupError> {
+        self.lookup_many_request(keys, None).await
+    }
+
+    pub async fn lookup_many_with_deadline(
+        &mut self,
+        keys: Vec<kv::Key>,
+        deadline: Instant,
+    )
+        -> Result<Vec<Option<kv::ValueCell<kv::Value>>>, LookupError>
+    {
+        self.lookup_many_request(keys, Some(deadline)).await
+    }
+
+    async fn lookup_many_request(
+        &mut self,
+        keys: Vec<kv::Key>,
+        deadline: Option<Instant>,
+    )
+        -> Result<Vec<Option<kv::ValueCell<kv::Value>>>, LookupError>
+    {
+        self
+            .request(deadline, |reply_tx, error_tx| proto::Request::LookupRange(
+                proto::RequestLookupKind::Many(
+                    proto::RequestLookupKindMany {
+                        keys: keys.clone(),
+                        reply_tx,
+                        error_tx,
+                    },
+                ),
+            ))
+            .await
+            .map_err(LookupError::from)
+    }
+
+    pub async fn lookup_range<R>(&mut self, range: R) -> Result<LookupRange, LookupRangeError> where R: RangeBounds<kv::Key> {
+        self.lookup_range_request(range, ScanOptions::default(), None).await
+    }
+
+    /// The deadline bounds obtaining the [`LookupRange`] only, not consuming it.
+    pub async fn lookup_range_with_deadline<R>(
+        &mut self,
+        range: R,
+        deadline: Instant,
+    )
+        -> Result<LookupRange, LookupRangeError>
+    where R: RangeBounds<kv::Key>
+    {
+        self.lookup_range_request(range, ScanOptions::default(), Some(deadline)).await
+    }
+
+    pub async fn lookup_range_with_options<R>(
+        &mut self,
+        range: R,
+        options: ScanOptions,
+    )
+        -> Result<LookupRange, LookupRangeError>
+    where R: RangeBounds<kv::Key>
+    {
+        self.lookup_range_request(range, options, None).await
+    }
+
+    async fn lookup_range_request<R>(
+        &mut self,
+        range: R,
+        options: ScanOptions,
+        deadline: Option<Instant>,
+    )
+        -> Result<LookupRange, LookupRangeError>
+    where R: RangeBounds<kv::Key>
+    {
+        let mut range_from = range.start_bound().cloned();
+        let mut range_to = range.end_bound().cloned();
+        let mut order = options.order;
+        if let Some(ContinuationToken { last_key, order: token_order, }) = options.resume_from {
+            order = token_order;
+            match order {
+                ScanOrder::Ascending =>
+                    range_from = Bound::Excluded(last_key),
+                ScanOrder::Descending =>
+                    range_to = Bound::Excluded(last_key),
             }
         }
+        self
+            .request(deadline, |reply_tx, error_tx| proto::Request::LookupRange(
+                proto::RequestLookupKind::Range(
+                    proto::RequestLookupKindRange {
+                        range_from: range_from.clone(),
+                        range_to: range_to.clone(),
+                        limit: options.limit,
+                        order,
+                        reply_tx: proto::RangeReplyTx::KeyValues(reply_tx),
+                        error_tx,
+                    },
+                ),
+            ))
+            .await
+            .map_err(LookupRangeError::from)
     }
 
-    pub async fn lookup_range<R>(&mut self, range: R) -> Result<LookupRange, LookupRangeError> where R: RangeBounds<kv::Key> {
+    /// Like [`Pid::lookup_range`] but only yields the keys of live entries,
+    /// leaving values and tombstones inside the gen_server.
+    pub async fn lookup_range_keys<R>(&mut self, range: R) -> Result<LookupRangeKeys, LookupRangeError> where R: RangeBounds<kv::Key> {
+        let range_from = range.start_bound();
+        let range_to = range.end_bound();
+        self
+            .request(None, |reply_tx, error_tx| proto::Request::LookupRange(
+                proto::RequestLookupKind::Range(
+                    proto::RequestLookupKindRange {
+                        range_from: range_from.cloned(),
+                        range_to: range_to.cloned(),
+                        limit: None,
+                        order: ScanOrder::Ascending,
+                        reply_tx: proto::RangeReplyTx::Keys(reply_tx),
+                        error_tx,
+                    },
+                ),
+            ))
+            .await
+            .map_err(LookupRangeError::from)
+    }
+
+    /// Counts live entries in the range without sending them to the client.
+    pub async fn count_range<R>(&mut self, range: R) -> Result<usize, LookupRangeError> where R: RangeBounds<kv::Key> {
         let range_from = range.start_bound();
         let range_to = range.end_bound();
-        loop {
-            let (reply_tx, reply_rx) = oneshot::channel();
-            self.request_tx
-                .send(proto::Request::LookupRange(proto::RequestLookupKind::Range(
+        self
+            .request(None, |reply_tx, error_tx| proto::Request::LookupRange(
+                proto::RequestLookupKind::Range(
                     proto::RequestLookupKindRange {
                         range_from: range_from.cloned(),
                         range_to: range_to.cloned(),
+                        limit: None,
+                        order: ScanOrder::Ascending,
+                        reply_tx: proto::RangeReplyTx::Count(reply_tx),
+                        error_tx,
+                    },
+                ),
+            ))
+            .await
+            .map_err(LookupRangeError::from)
+    }
+
+    /// Scans all keys starting with `prefix` in ascending order.
+    pub async fn scan_prefix(&mut self, prefix: &[u8]) -> Result<LookupRange, LookupRangeError> {
+        self
+            .request(None, |reply_tx, error_tx| proto::Request::LookupRange(
+                proto::RequestLookupKind::Prefix(
+                    proto::RequestLookupKindPrefix {
+                        prefix: prefix.to_vec(),
                         reply_tx,
+                        error_tx,
                     },
-                )))
-                .await
-                .map_err(|_send_error| LookupRangeError::GenServer(ero::NoProcError))?;
-
-            match reply_rx.await {
-                Ok(result) =>
-                    return Ok(result),
-                Err(oneshot::Canceled) =>
-                    (),
-            }
-        }
+                ),
+            ))
+            .await
+            .map_err(LookupRangeError::from)
     }
 
     pub async fn remove(&mut self, key: kv::Key) -> Result<Removed, RemoveError> {
-        loop {
-            let (reply_tx, reply_rx) = oneshot::channel();
-            self.request_tx
-                .send(proto::Request::Remove(proto::RequestRemove {
-                    key: key.clone(),
-                    reply_tx,
-                }))
-                .await
-                .map_err(|_send_error| RemoveError::GenServer(ero::NoProcError))?;
-
-            match reply_rx.await {
-                Ok(result) =>
-                    return Ok(result),
-                Err(oneshot::Canceled) =>
-                    (),
-            }
+        self.remove_request(key, None).await
+    }
+
+    pub async fn remove_with_deadline(&mut self, key: kv::Key, deadline: Instant) -> Result<Removed, RemoveError> {
+        self.remove_request(key, Some(deadline)).await
+    }
+
+    async fn remove_request(&mut self, key: kv::Key, deadline: Option<Instant>) -> Result<Removed, RemoveError> {
+        if reserved::is_reserved(&key) {
+            return Err(RemoveError::ReservedKey);
         }
+        self
+            .request(deadline, |reply_tx, error_tx| proto::Request::Remove(proto::RequestRemove {
+                key: key.clone(),
+                reply_tx,
+                error_tx,
+            }))
+            .await
+            .map_err(RemoveError::from)
     }
 
     pub async fn flush_all(&mut self) -> Result<Flushed, FlushError> {
-        loop {
-            let (reply_tx, reply_rx) = oneshot::channel();
-            self.request_tx
-                .send(proto::Request::FlushAll(proto::RequestFlush { reply_tx, })).await
-                .map_err(|_send_error| FlushError::GenServer(ero::NoProcError))?;
-
-            match reply_rx.await {
-                Ok(Flushed) =>
-                    return Ok(Flushed),
-                Err(oneshot::Canceled) =>
-                    (),
+        self.flush_all_request(None).await
+    }
+
+    pub async fn flush_all_with_deadline(&mut self, deadline: Instant) -> Result<Flushed, FlushError> {
+        self.flush_all_request(Some(deadline)).await
+    }
+
+    async fn flush_all_request(&mut self, deadline: Option<Instant>) -> Result<Flushed, FlushError> {
+        self.request(deadline, |reply_tx, error_tx| proto::Request::FlushAll(proto::RequestFlush { reply_tx, error_tx, }))
+            .await
+            .map_err(FlushError::from)
+    }
+
+    /// Atomically replaces the value of `key` with `new` if it currently
+    /// equals `expected`. `None` stands for an absent key on both sides, so
+    /// `new: None` removes the key.
+    pub async fn compare_and_swap(
+        &mut self,
+        key: kv::Key,
+        expected: Option<kv::Value>,
+        new: Option<kv::Value>,
+    )
+        -> Result<CompareAndSwapOutcome, CompareAndSwapError>
+    {
+        if reserved::is_reserved(&key) {
+            return Err(CompareAndSwapError::ReservedKey);
+        }
+        self
+            .request(None, |reply_tx, error_tx| proto::Request::CompareAndSwap(proto::RequestCompareAndSwap {
+                key: key.clone(),
+                expected: expected.clone(),
+                new: new.clone(),
+                reply_tx,
+                error_tx,
+            }))
+            .await
+            .map_err(CompareAndSwapError::from)
+    }
+
+    /// Inserts `value` only if `key` is absent.
+    pub async fn insert_if_absent(&mut self, key: kv::Key, value: kv::Value) -> Result<CompareAndSwapOutcome, CompareAndSwapError> {
+        self.compare_and_swap(key, None, Some(value)).await
+    }
+
+    /// Applies all operations of `batch` atomically. Results are returned in
+    /// the batch order.
+    ///
+    /// The gen_server submits the operations back to back within one step, so
+    /// any request it serves afterwards observes all of them, while range
+    /// lookups already running keep reading the view `blockwheel_kv` took when
+    /// they started. The batch is preceded by a persisted intent which is
+    /// removed right after it: if a crash or a backend failure cuts the batch
+    /// short, the gen_server applies it again from the intent when it starts,
+    /// before serving any request. In the latter case the reply is dropped and
+    /// the request is resent according to the [`RetryPolicy`], which is safe
+    /// since applying the same batch twice has the same effect.
+    pub async fn apply_batch(&mut self, batch: WriteBatch) -> Result<Vec<WriteBatchOutcome>, ApplyBatchError> {
+        let has_reserved_key = batch.ops.iter().any(|op| match op {
+            WriteBatchOp::Insert { key, .. } | WriteBatchOp::Remove { key, } =>
+                reserved::is_reserved(key),
+        });
+        if has_reserved_key {
+            return Err(ApplyBatchError::ReservedKey);
+        }
+        self
+            .request(None, |reply_tx, error_tx| proto::Request::ApplyBatch(proto::RequestApplyBatch {
+                batch: batch.clone(),
+                reply_tx,
+                error_tx,
+            }))
+            .await
+            .map_err(ApplyBatchError::from)
+    }
+
+    /// Atomically updates `key` with `operator`, see [`merge::MergeOperator`],
+    /// and returns the resulting value (`None` if the key ends up removed).
+    ///
+    /// Unlike plain writes a merge is not idempotent, so it is sent exactly
+    /// once regardless of the retry policy: if the gen_server is restarted
+    /// before replying the merge fails with [`MergeError::Dropped`], and it
+    /// may or may not have been applied. Read the key back to find out.
+    pub async fn merge(
+        &mut self,
+        key: kv::Key,
+        operator: Arc<dyn merge::MergeOperator>,
+        operand: Vec<u8>,
+    )
+        -> Result<Option<kv::Value>, MergeError>
+    {
+        if reserved::is_reserved(&key) {
+            return Err(MergeError::ReservedKey);
+        }
+        self
+            .request_with_policy(None, &RetryPolicy::never(), |reply_tx, error_tx| proto::Request::Merge(proto::RequestMerge {
+                key: key.clone(),
+                operator: operator.clone(),
+                operand: operand.clone(),
+                reply_tx,
+                error_tx,
+            }))
+            .await
+            .map_err(MergeError::from)?
+            .map_err(MergeError::Rejected)
+    }
+
+    /// Adds `delta` to the counter stored as a big-endian `i64` under `key`
+    /// and returns the new value, see [`merge::Increment`].
+    ///
+    /// Like [`Pid::merge`] it is sent exactly once, so on
+    /// [`MergeError::Dropped`] the counter may or may not have been updated.
+    pub async fn increment(&mut self, key: kv::Key, delta: i64) -> Result<i64, MergeError> {
+        let maybe_value = self.merge(key, Arc::new(merge::Increment), delta.to_be_bytes().to_vec()).await?;
+        let value_bytes: &[u8] = match &maybe_value {
+            Some(value) =>
+                &value.value_bytes,
+            None =>
+                &[],
+        };
+        value_bytes.try_into()
+            .map(i64::from_be_bytes)
+            .map_err(|_error| MergeError::Rejected(merge::MergeRejected::new("counter is not an 8 byte integer")))
+    }
+
+    /// Removes every key within `range` and returns how many were removed.
+    ///
+    /// The range is scanned and removed in chunks of a bounded size, each
+    /// chunk being applied atomically like [`Pid::apply_batch`] does. The
+    /// removal as a whole is not atomic: other requests may observe the range
+    /// partly removed, and if the request fails some chunks may be removed
+    /// already. Resending it carries on with the keys left, which is what
+    /// happens on retries, so the count returned then covers the last attempt
+    /// only. Keys inserted into the range while it is being scanned may
+    /// survive.
+    pub async fn remove_range<R>(&mut self, range: R) -> Result<usize, RemoveRangeError> where R: RangeBounds<kv::Key> {
+        let range_from = range.start_bound();
+        let range_to = range.end_bound();
+        if bound_is_reserved(range_from) || bound_is_reserved(range_to) {
+            return Err(RemoveRangeError::ReservedKey);
+        }
+        self
+            .request(None, |reply_tx, error_tx| proto::Request::RemoveRange(proto::RequestRemoveRange {
+                range_from: range_from.cloned(),
+                range_to: range_to.cloned(),
+                reply_tx,
+                error_tx,
+            }))
+            .await
+            .map_err(RemoveRangeError::from)
+    }
+
+    /// Removes every key in the store, see [`Pid::remove_range`].
+    pub async fn clear(&mut self) -> Result<usize, RemoveRangeError> {
+        self.remove_range::<RangeFull>(..).await
+    }
+
+    /// Writes live entries within `range` to `writer` in the
+    /// [`dump`] format and returns how many were written.
+    pub async fn export<R, W>(&mut self, range: R, writer: W) -> Result<u64, dump::ExportError>
+    where R: RangeBounds<kv::Key>,
+          W: AsyncWrite + Unpin,
+    {
+        self.export_with_options(range, writer, dump::ExportOptions::default()).await
+    }
+
+    pub async fn export_with_options<R, W>(
+        &mut self,
+        range: R,
+        writer: W,
+        options: dump::ExportOptions,
+    )
+        -> Result<u64, dump::ExportError>
+    where R: RangeBounds<kv::Key>,
+          W: AsyncWrite + Unpin,
+    {
+        dump::export(self, range, writer, options).await
+    }
+
+    /// Loads a dump written by [`Pid::export`] with one batched insert per
+    /// [`dump::IMPORT_BATCH_ENTRIES`] entries and returns how many entries
+    /// were imported. Entries get new
+    /// versions. If the dump turns out to be damaged, blocks before the
+    /// damaged one stay imported: check it with [`dump::verify`] first to
+    /// avoid a partial restore.
+    pub async fn import<R>(&mut self, reader: R, blocks_pool: &BytesPool) -> Result<u64, dump::ImportError> where R: AsyncRead + Unpin {
+        dump::import(self, reader, blocks_pool).await
+    }
+
+    /// Subscribes to inserts and removes of keys within `range`, see
+    /// [`watch::Watch`]. Only changes made after the subscription is
+    /// registered are reported.
+    pub async fn watch<R>(&mut self, range: R) -> Result<watch::Watch, WatchError> where R: RangeBounds<kv::Key> {
+        let range_from = range.start_bound();
+        let range_to = range.end_bound();
+        self
+            .request(None, |reply_tx, _error_tx| proto::Request::Watch(proto::RequestWatch {
+                range_from: range_from.cloned(),
+                range_to: range_to.cloned(),
+                reply_tx,
+            }))
+            .await
+            .map_err(WatchError::from)
+    }
+
+    /// Stops the gen_server gracefully: no new requests are accepted, already
+    /// queued ones and pending lookups are completed, and everything is
+    /// flushed. Resolves once the flush is done.
+    pub async fn shutdown(&mut self) -> Result<Flushed, ShutdownError> {
+        self.shutdown_request(None).await
+    }
+
+    pub async fn shutdown_with_deadline(&mut self, deadline: Instant) -> Result<Flushed, ShutdownError> {
+        self.shutdown_request(Some(deadline)).await
+    }
+
+    async fn shutdown_request(&mut self, deadline: Option<Instant>) -> Result<Flushed, ShutdownError> {
+        self.request(deadline, |reply_tx, error_tx| proto::Request::Shutdown(proto::RequestShutdown { reply_tx, error_tx, }))
+            .await
+            .map_err(ShutdownError::from)
+    }
+
+    /// Sends a request built by `make_request` and waits for its reply,
+    /// resending it according to the retry policy if the reply is canceled
+    /// without a backend error being reported. Gives up with `RequestError::Timeout` once `deadline` passes.
+    async fn request<T, F>(&mut self, deadline: Option<Instant>, make_request: F) -> Result<T, RequestError>
+    where F: FnMut(oneshot::Sender<T>, proto::RequestErrorTx) -> proto::Request
+    {
+        let retry_policy = self.retry_policy.clone();
+        self.request_with_policy(deadline, &retry_policy, make_request).await
+    }
+
+    /// Same as [`Pid::request`] with `retry_policy` in place of the one
+    /// configured for this pid, for requests that must not be resent.
+    async fn request_with_policy<T, F>(
+        &mut self,
+        deadline: Option<Instant>,
+        retry_policy: &RetryPolicy,
+        mut make_request: F,
+    )
+        -> Result<T, RequestError>
+    where F: FnMut(oneshot::Sender<T>, proto::RequestErrorTx) -> proto::Request
+    {
+        let request_tx = &mut self.request_tx;
+        let exchange = async move {
+            let mut attempts = 0;
+            let mut backoff = retry_policy.backoff_initial.min(retry_policy.backoff_max);
+            loop {
+                attempts += 1;
+                let (reply_tx, reply_rx) = oneshot::channel();
+                let (error_tx, error_rx) = oneshot::channel();
+                request_tx.send(make_request(reply_tx, error_tx)).await
+                    .map_err(|_send_error| RequestError::GenServer)?;
+                match reply_rx.await {
+                    Ok(reply) =>
+                        return Ok(reply),
+                    Err(oneshot::Canceled) =>
+                        if let Ok(error) = error_rx.await {
+                            return Err(RequestError::Backend(error));
+                        },
+                }
+
+                if let Some(max_attempts) = retry_policy.max_attempts {
+                    if attempts >= max_attempts {
+                        return Err(RequestError::Dropped { attempts, });
+                    }
+                }
+                if !backoff.is_zero() {
+                    Delay::new(backoff).await;
+                    backoff = backoff.saturating_mul(2).min(retry_policy.backoff_max);
+                }
             }
+        };
+
+        match deadline {
+            None =>
+                exchange.await,
+            Some(deadline) => {
+                let timeout = Delay::new(deadline.saturating_duration_since(Instant::now()));
+                pin_mut!(exchange);
+                match future::select(exchange, timeout).await {
+                    Either::Left((result, _timeout)) =>
+                        result,
+                    Either::Right(((), _exchange)) =>
+                        Err(RequestError::Timeout),
+                }
+            },
         }
     }
 }
+
+/// [`Info`] as returned by [`KvStore::info`], the same for a [`Pid`] and a
+/// [`client::RemotePid`]. `blockwheel_kv` does not let an `Info` be rebuilt
+/// from its parts, so it is carried in its debug representation, which is
+/// also how the wire protocol sends it.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct StoreInfo {
+    pub description: String,
+}
+
+impl From<Info> for StoreInfo {
+    fn from(info: Info) -> StoreInfo {
+        StoreInfo { description: format!("{:?}", info), }
+    }
+}
+
+/// Operations served both in process by a [`Pid`] and over the network by a
+/// [`client::RemotePid`], so that code generic over a `KvStore` switches
+/// between the two by constructing the other one. The futures returned are
+/// `Send`, so they can be spawned on a multi-threaded runtime.
+pub trait KvStore {
+    fn info(&mut self) -> impl Future<Output = Result<StoreInfo, InfoError>> + Send;
+
+    fn insert(&mut self, key: kv::Key, value: kv::Value) -> impl Future<Output = Result<Inserted, InsertError>> + Send;
+
+    fn lookup(&mut self, key: kv::Key) -> impl Future<Output = Result<Option<kv::ValueCell<kv::Value>>, LookupError>> + Send;
+
+    fn lookup_range<R>(&mut self, range: R) -> impl Future<Output = Result<LookupRange, LookupRangeError>> + Send
+    where R: RangeBounds<kv::Key>;
+
+    fn remove(&mut self, key: kv::Key) -> impl Future<Output = Result<Removed, RemoveError>> + Send;
+
+    fn flush_all(&mut self) -> impl Future<Output = Result<Flushed, FlushError>> + Send;
+}
+
+impl KvStore for Pid {
+    async fn info(&mut self) -> Result<StoreInfo, InfoError> {
+        Pid::info(self).await
+            .map(StoreInfo::from)
+    }
+
+    fn insert(&mut self, key: kv::Key, value: kv::Value) -> impl Future<Output = Result<Inserted, InsertError>> + Send {
+        Pid::insert(self, key, value)
+    }
+
+    fn lookup(&mut self, key: kv::Key) -> impl Future<Output = Result<Option<kv::ValueCell<kv::Value>>, LookupError>> + Send {
+        Pid::lookup(self, key)
+    }
+
+    fn lookup_range<R>(&mut self, range: R) -> impl Future<Output = Result<LookupRange, LookupRangeError>> + Send
+    where R: RangeBounds<kv::Key>
+    {
+        // owned bounds, so the future does not hold `range` which may not be `Send`
+        Pid::lookup_range(self, (range.start_bound().cloned(), range.end_bound().cloned()))
+    }
+
+    fn remove(&mut self, key: kv::Key) -> impl Future<Output = Result<Removed, RemoveError>> + Send {
+        Pid::remove(self, key)
+    }
+
+    fn flush_all(&mut self) -> impl Future<Output = Result<Flushed, FlushError>> + Send {
+        Pid::flush_all(self)
+    }
+}
+
+/// Reserved keys are skipped by scans anyway, so a range is only rejected
+/// for a bound within [`RESERVED_KEY_PREFIX`].
+fn bound_is_reserved(bound: Bound<&kv::Key>) -> bool {
+    match bound {
+        Bound::Included(key) | Bound::Excluded(key) =>
+            reserved::is_reserved(key),
+        Bound::Unbounded =>
+            false,
+    }
+}
+
+enum RequestError {
+    GenServer,
+    Backend(blockwheel_kv::Error),
+    Timeout,
+    Dropped { attempts: usize, },
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc_pool::{
+        bytes::{
+            BytesPool,
+        },
+    };
+
+    use super::{
+        kv_util,
+        GenServer,
+        InsertError,
+        RemoveRangeError,
+        WriteBatch,
+        ApplyBatchError,
+        RESERVED_KEY_PREFIX,
+    };
+
+    #[tokio::test]
+    async fn reserved_keys_are_rejected() {
+        let blocks_pool = BytesPool::new();
+        let mut reserved_key_bytes = RESERVED_KEY_PREFIX.to_vec();
+        reserved_key_bytes.extend_from_slice(b"key");
+        let reserved_key = kv_util::key_from_slice(&blocks_pool, &reserved_key_bytes);
+        let value = kv_util::value_from_slice(&blocks_pool, b"value");
+
+        // the gen_server is not running: a request sent to it would never be replied
+        let gen_server = GenServer::new();
+        let mut pid = gen_server.pid();
+
+        let result = pid.insert(reserved_key.clone(), value.clone()).await;
+        assert!(matches!(result, Err(InsertError::ReservedKey)));
+
+        let mut batch = WriteBatch::new();
+        batch
+            .insert(kv_util::key_from_slice(&blocks_pool, b"key"), value)
+            .remove(reserved_key.clone());
+        let result = pid.apply_batch(batch).await;
+        assert!(matches!(result, Err(ApplyBatchError::ReservedKey)));
+
+        let result = pid.remove_range(reserved_key ..).await;
+        assert!(matches!(result, Err(RemoveRangeError::ReservedKey)));
+    }
+}
@@ -0,0 +1,199 @@
+//! Read-modify-write updates executed inside the gen_server, see
+//! [`crate::Pid::merge`].
+
+use std::{
+    panic::{
+        self,
+        AssertUnwindSafe,
+    },
+    collections::{
+        BTreeSet,
+    },
+};
+
+use crate::{
+    kv,
+};
+
+/// Computes the new value of a key from its current one and an operand.
+///
+/// The gen_server calls it between the lookup of the current value and the
+/// write of the result, with other writes held back, so concurrent merges to
+/// the same key never lose updates. It runs on the gen_server task and should
+/// be cheap. A panic is caught and rejects the update with a
+/// [`MergeRejected`].
+pub trait MergeOperator: Send + Sync {
+    /// `current` is `None` if the key is absent. Returning `Ok(None)` removes
+    /// the key.
+    fn merge(&self, key: &kv::Key, current: Option<&[u8]>, operand: &[u8]) -> Result<Option<Vec<u8>>, MergeRejected>;
+}
+
+/// Returned by a [`MergeOperator`] to refuse the update, the stored value is
+/// left untouched.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct MergeRejected {
+    pub reason: String,
+}
+
+impl MergeRejected {
+    pub fn new<S>(reason: S) -> MergeRejected where S: Into<String> {
+        MergeRejected { reason: reason.into(), }
+    }
+}
+
+/// Runs `operator` as the gen_server does: a panic rejects the update
+/// instead of taking the whole gen_server down.
+pub(crate) fn apply(
+    operator: &dyn MergeOperator,
+    key: &kv::Key,
+    current: Option<&[u8]>,
+    operand: &[u8],
+)
+    -> Result<Option<Vec<u8>>, MergeRejected>
+{
+    // the operator only gets shared references, so a panic leaves nothing
+    // half updated on this side
+    panic::catch_unwind(AssertUnwindSafe(|| operator.merge(key, current, operand)))
+        .unwrap_or_else(|_panic| Err(MergeRejected::new("merge operator panicked")))
+}
+
+fn decode_i64(bytes: &[u8]) -> Result<i64, MergeRejected> {
+    bytes.try_into()
+        .map(i64::from_be_bytes)
+        .map_err(|_error| MergeRejected::new(format!("expected an 8 byte integer, got {} bytes", bytes.len())))
+}
+
+/// Adds a big-endian `i64` operand to a big-endian `i64` value, an absent
+/// value counts as zero. An overflow rejects the update.
+pub struct Increment;
+
+impl MergeOperator for Increment {
+    fn merge(&self, _key: &kv::Key, current: Option<&[u8]>, operand: &[u8]) -> Result<Option<Vec<u8>>, MergeRejected> {
+        let delta = decode_i64(operand)?;
+        let value = current.map(decode_i64).transpose()?.unwrap_or(0);
+        let value = value.checked_add(delta)
+            .ok_or_else(|| MergeRejected::new("counter overflow"))?;
+        Ok(Some(value.to_be_bytes().to_vec()))
+    }
+}
+
+/// Keeps the greatest of the big-endian `i64` value and operand.
+pub struct Max;
+
+impl MergeOperator for Max {
+    fn merge(&self, _key: &kv::Key, current: Option<&[u8]>, operand: &[u8]) -> Result<Option<Vec<u8>>, MergeRejected> {
+        let candidate = decode_i64(operand)?;
+        let value = match current.map(decode_i64).transpose()? {
+            Some(value) =>
+                value.max(candidate),
+            None =>
+                candidate,
+        };
+        Ok(Some(value.to_be_bytes().to_vec()))
+    }
+}
+
+/// Appends the operand bytes to the value.
+pub struct Append;
+
+impl MergeOperator for Append {
+    fn merge(&self, _key: &kv::Key, current: Option<&[u8]>, operand: &[u8]) -> Result<Option<Vec<u8>>, MergeRejected> {
+        let mut value = current.map(<[u8]>::to_vec).unwrap_or_default();
+        value.extend_from_slice(operand);
+        Ok(Some(value))
+    }
+}
+
+/// Unites the value and the operand as sets of byte strings, both encoded
+/// with [`SetUnion::encode`].
+pub struct SetUnion;
+
+impl SetUnion {
+    /// Encodes members as a sorted sequence of `u32` big-endian length
+    /// prefixed byte strings.
+    pub fn encode<'a, I>(members: I) -> Vec<u8> where I: IntoIterator<Item = &'a [u8]> {
+        let members: BTreeSet<&[u8]> = members.into_iter().collect();
+        let mut bytes = Vec::new();
+        for member in members {
+            bytes.extend_from_slice(&(member.len() as u32).to_be_bytes());
+            bytes.extend_from_slice(member);
+        }
+        bytes
+    }
+
+    pub fn decode(mut bytes: &[u8]) -> Result<BTreeSet<&[u8]>, MergeRejected> {
+        let mut members = BTreeSet::new();
+        while !bytes.is_empty() {
+            if bytes.len() < 4 {
+                return Err(MergeRejected::new("truncated set member length"));
+            }
+            let (length_bytes, rest) = bytes.split_at(4);
+            let length = u32::from_be_bytes(length_bytes.try_into().unwrap()) as usize;
+            if rest.len() < length {
+                return Err(MergeRejected::new("truncated set member"));
+            }
+            let (member, rest) = rest.split_at(length);
+            members.insert(member);
+            bytes = rest;
+        }
+        Ok(members)
+    }
+}
+
+impl MergeOperator for SetUnion {
+    fn merge(&self, _key: &kv::Key, current: Option<&[u8]>, operand: &[u8]) -> Result<Option<Vec<u8>>, MergeRejected> {
+        let mut members = SetUnion::decode(operand)?;
+        if let Some(current) = current {
+            members.extend(SetUnion::decode(current)?);
+        }
+        Ok(Some(SetUnion::encode(members)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc_pool::{
+        bytes::{
+            BytesPool,
+        },
+    };
+
+    use crate::{
+        kv,
+        kv_util,
+    };
+
+    use super::{
+        apply,
+        Increment,
+        MergeOperator,
+        MergeRejected,
+    };
+
+    struct Panicking;
+
+    impl MergeOperator for Panicking {
+        fn merge(&self, _key: &kv::Key, _current: Option<&[u8]>, _operand: &[u8]) -> Result<Option<Vec<u8>>, MergeRejected> {
+            panic!("operator bug")
+        }
+    }
+
+    #[test]
+    fn increment_rejects_overflow() {
+        let blocks_pool = BytesPool::new();
+        let key = kv_util::key_from_slice(&blocks_pool, b"counter");
+        let current = i64::MAX.to_be_bytes();
+        let result = Increment.merge(&key, Some(&current), &1i64.to_be_bytes());
+        assert_eq!(result, Err(MergeRejected::new("counter overflow")));
+        let result = Increment.merge(&key, Some(&current), &(-1i64).to_be_bytes());
+        assert_eq!(result, Ok(Some((i64::MAX - 1).to_be_bytes().to_vec())));
+    }
+
+    #[test]
+    fn panicking_operator_is_rejected() {
+        let blocks_pool = BytesPool::new();
+        let key = kv_util::key_from_slice(&blocks_pool, b"key");
+        let result = apply(&Panicking, &key, None, b"operand");
+        assert_eq!(result, Err(MergeRejected::new("merge operator panicked")));
+    }
+}
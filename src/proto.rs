@@ -2,6 +2,12 @@ use std::{
     ops::{
         Bound,
     },
+    time::{
+        SystemTime,
+    },
+    sync::{
+        Arc,
+    },
 };
 
 use futures::{
@@ -16,57 +22,186 @@ use crate::{
     Inserted,
     Removed,
     Flushed,
+    ScanOrder,
+    CompareAndSwapOutcome,
     LookupRange,
+    LookupRangeKeys,
+    WriteBatch,
+    WriteBatchOutcome,
+    watch::{
+        Watch,
+    },
+    merge::{
+        MergeOperator,
+        MergeRejected,
+    },
 };
 
 pub enum Request {
     Info(RequestInfo),
     Insert(RequestInsert),
+    InsertBatch(RequestInsertBatch),
     LookupRange(RequestLookupKind),
     Remove(RequestRemove),
     FlushAll(RequestFlush),
+    CompareAndSwap(RequestCompareAndSwap),
+    ApplyBatch(RequestApplyBatch),
+    Watch(RequestWatch),
+    RemoveRange(RequestRemoveRange),
+    Merge(RequestMerge),
+    Shutdown(RequestShutdown),
+}
+
+impl Request {
+    /// Whether the request modifies data and so has to wait for a pending
+    /// conditional write to be submitted first.
+    pub fn is_write(&self) -> bool {
+        matches!(
+            self,
+            Request::Insert(..) |
+            Request::InsertBatch(..) |
+            Request::Remove(..) |
+            Request::CompareAndSwap(..) |
+            Request::ApplyBatch(..) |
+            Request::RemoveRange(..) |
+            Request::Merge(..)
+        )
+    }
 }
 
 pub type RequestInfoReplyTx = oneshot::Sender<Info>;
 pub type RequestInsertReplyTx = oneshot::Sender<Inserted>;
+pub type RequestInsertBatchReplyTx = oneshot::Sender<Vec<Inserted>>;
 pub type RequestRemoveReplyTx = oneshot::Sender<Removed>;
 pub type RequestFlushReplyTx = oneshot::Sender<Flushed>;
+pub type RequestCompareAndSwapReplyTx = oneshot::Sender<CompareAndSwapOutcome>;
+pub type RequestMergeReplyTx = oneshot::Sender<Result<Option<kv::Value>, MergeRejected>>;
+pub type RequestApplyBatchReplyTx = oneshot::Sender<Vec<WriteBatchOutcome>>;
+pub type RequestRemoveRangeReplyTx = oneshot::Sender<usize>;
+
+/// Receives the backend error if the request could not be submitted to
+/// `blockwheel_kv`; dropped without a value otherwise.
+pub type RequestErrorTx = oneshot::Sender<blockwheel_kv::Error>;
 
 pub enum RequestLookupKind {
     Single(RequestLookupKindSingle),
+    Many(RequestLookupKindMany),
     Range(RequestLookupKindRange),
+    Prefix(RequestLookupKindPrefix),
 }
 
 pub struct RequestLookupKindSingle {
     pub key: kv::Key,
     pub reply_tx: oneshot::Sender<Option<kv::ValueCell<kv::Value>>>,
+    pub error_tx: RequestErrorTx,
+}
+
+pub struct RequestLookupKindMany {
+    pub keys: Vec<kv::Key>,
+    pub reply_tx: oneshot::Sender<Vec<Option<kv::ValueCell<kv::Value>>>>,
+    pub error_tx: RequestErrorTx,
 }
 
 pub struct RequestLookupKindRange {
     pub range_from: Bound<kv::Key>,
     pub range_to: Bound<kv::Key>,
+    pub limit: Option<usize>,
+    pub order: ScanOrder,
+    pub reply_tx: RangeReplyTx,
+    pub error_tx: RequestErrorTx,
+}
+
+pub enum RangeReplyTx {
+    KeyValues(oneshot::Sender<LookupRange>),
+    Keys(oneshot::Sender<LookupRangeKeys>),
+    Count(oneshot::Sender<usize>),
+}
+
+pub enum KeyStreamItem {
+    Key(kv::Key),
+    NoMore,
+}
+
+pub struct RequestLookupKindPrefix {
+    pub prefix: Vec<u8>,
     pub reply_tx: oneshot::Sender<LookupRange>,
+    pub error_tx: RequestErrorTx,
 }
 
 #[derive(Debug)]
 pub struct RequestInfo {
     pub reply_tx: RequestInfoReplyTx,
+    pub error_tx: RequestErrorTx,
 }
 
 #[derive(Debug)]
 pub struct RequestInsert {
     pub key: kv::Key,
     pub value: kv::Value,
+    pub expires_at: Option<SystemTime>,
     pub reply_tx: RequestInsertReplyTx,
+    pub error_tx: RequestErrorTx,
+}
+
+#[derive(Debug)]
+pub struct RequestInsertBatch {
+    pub key_values: Vec<(kv::Key, kv::Value)>,
+    pub reply_tx: RequestInsertBatchReplyTx,
+    pub error_tx: RequestErrorTx,
 }
 
 #[derive(Debug)]
 pub struct RequestRemove {
     pub key: kv::Key,
     pub reply_tx: RequestRemoveReplyTx,
+    pub error_tx: RequestErrorTx,
 }
 
 #[derive(Debug)]
 pub struct RequestFlush {
     pub reply_tx: RequestFlushReplyTx,
+    pub error_tx: RequestErrorTx,
+}
+
+#[derive(Debug)]
+pub struct RequestCompareAndSwap {
+    pub key: kv::Key,
+    pub expected: Option<kv::Value>,
+    pub new: Option<kv::Value>,
+    pub reply_tx: RequestCompareAndSwapReplyTx,
+    pub error_tx: RequestErrorTx,
+}
+
+#[derive(Debug)]
+pub struct RequestApplyBatch {
+    pub batch: WriteBatch,
+    pub reply_tx: RequestApplyBatchReplyTx,
+    pub error_tx: RequestErrorTx,
+}
+
+pub struct RequestMerge {
+    pub key: kv::Key,
+    pub operator: Arc<dyn MergeOperator>,
+    pub operand: Vec<u8>,
+    pub reply_tx: RequestMergeReplyTx,
+    pub error_tx: RequestErrorTx,
+}
+
+pub struct RequestRemoveRange {
+    pub range_from: Bound<kv::Key>,
+    pub range_to: Bound<kv::Key>,
+    pub reply_tx: RequestRemoveRangeReplyTx,
+    pub error_tx: RequestErrorTx,
+}
+
+pub struct RequestWatch {
+    pub range_from: Bound<kv::Key>,
+    pub range_to: Bound<kv::Key>,
+    pub reply_tx: oneshot::Sender<Watch>,
+}
+
+#[derive(Debug)]
+pub struct RequestShutdown {
+    pub reply_tx: RequestFlushReplyTx,
+    pub error_tx: RequestErrorTx,
 }
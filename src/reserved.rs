@@ -0,0 +1,447 @@
+//! Records the gen_server keeps in `blockwheel_kv` next to user data, under
+//! keys starting with [`RESERVED_KEY_PREFIX`]. Lookups and scans skip them.
+//!
+//! A [`WriteBatch`](crate::WriteBatch) is made atomic across crashes with an
+//! intent: the encoded batch is inserted in parts, then a commit record, then
+//! the batch itself, then the commit record and the parts are removed. The
+//! intent is flushed before the batch is submitted, and the batch before the
+//! intent is removed: after a crash either the commit record is there and the
+//! batch is applied again from its parts on startup, or none of the batch is
+//! persisted.
+//!
+//! Keys inserted with a ttl have their deadline stored as an expiry record,
+//! in unix milliseconds, which is written together with the value and loaded
+//! back into [`crate::expiry::ExpiryIndex`] on startup.
+
+use std::{
+    ops::{
+        Bound,
+    },
+    collections::{
+        BTreeMap,
+    },
+    time::{
+        Duration,
+        SystemTime,
+        UNIX_EPOCH,
+    },
+};
+
+use alloc_pool::{
+    bytes::{
+        BytesPool,
+    },
+};
+
+use crate::{
+    kv,
+    kv_util,
+    WriteBatchOp,
+    RESERVED_KEY_PREFIX,
+};
+
+/// Part of an encoded batch, keyed by batch id and part index.
+const TAG_BATCH_PART: u8 = 0x01;
+/// Written once all parts of a batch are, keyed by batch id, holds the number
+/// of parts.
+const TAG_BATCH_COMMIT: u8 = 0x02;
+/// Expiry deadline of a user key, keyed by the user key.
+const TAG_EXPIRY: u8 = 0x03;
+
+/// Encoded batches are split into parts of this size, so an intent record
+/// fits into a `blockwheel_kv` block however large the batch is.
+const BATCH_PART_SIZE: usize = 16 * 1024;
+
+const OP_INSERT: u8 = 1;
+const OP_REMOVE: u8 = 2;
+
+pub fn is_reserved(key: &kv::Key) -> bool {
+    key.key_bytes.starts_with(RESERVED_KEY_PREFIX)
+}
+
+/// Bounds covering every reserved key.
+pub fn bounds(blocks_pool: &BytesPool) -> (Bound<kv::Key>, Bound<kv::Key>) {
+    let range_to = match kv_util::prefix_upper_bound(RESERVED_KEY_PREFIX) {
+        Some(upper_bound) =>
+            Bound::Excluded(kv_util::key_from_slice(blocks_pool, &upper_bound)),
+        None =>
+            Bound::Unbounded,
+    };
+    (Bound::Included(kv_util::key_from_slice(blocks_pool, RESERVED_KEY_PREFIX)), range_to)
+}
+
+/// Intent records of a single write batch.
+pub struct BatchIntent {
+    /// Inserted before the batch: all the parts, then the commit record.
+    pub records: Vec<(kv::Key, kv::Value)>,
+    /// Removed right after the batch: the commit record, then all the parts.
+    pub keys: Vec<kv::Key>,
+}
+
+impl BatchIntent {
+    pub fn new(blocks_pool: &BytesPool, batch_id: u64, ops: &[WriteBatchOp]) -> BatchIntent {
+        let payload = encode_ops(ops);
+        let mut records: Vec<_> = payload
+            .chunks(BATCH_PART_SIZE)
+            .enumerate()
+            .map(|(part_index, part)| (
+                batch_part_key(blocks_pool, batch_id, part_index as u32),
+                kv_util::value_from_slice(blocks_pool, part),
+            ))
+            .collect();
+        let parts_count = records.len() as u32;
+        let commit_key = batch_commit_key(blocks_pool, batch_id);
+        let mut keys = vec![commit_key.clone()];
+        keys.extend(records.iter().map(|(key, _value)| key.clone()));
+        records.push((commit_key, kv_util::value_from_slice(blocks_pool, &parts_count.to_be_bytes())));
+        BatchIntent { records, keys, }
+    }
+}
+
+/// Expiry deadline as stored in an expiry record, `None` if it cannot be
+/// represented.
+pub fn deadline_millis(deadline: SystemTime) -> Option<u64> {
+    let since_epoch = deadline.duration_since(UNIX_EPOCH).ok()?;
+    u64::try_from(since_epoch.as_millis()).ok()
+}
+
+fn deadline_from_millis(millis: u64) -> Option<SystemTime> {
+    UNIX_EPOCH.checked_add(Duration::from_millis(millis))
+}
+
+pub fn expiry_key(blocks_pool: &BytesPool, key: &kv::Key) -> kv::Key {
+    reserved_key(blocks_pool, TAG_EXPIRY, &key.key_bytes)
+}
+
+/// Expiry record of `key`, `deadline` should be checked with [`deadline_millis`] beforehand.
+pub fn expiry_record(blocks_pool: &BytesPool, key: &kv::Key, deadline: SystemTime) -> (kv::Key, kv::Value) {
+    let millis = deadline_millis(deadline).unwrap_or(u64::MAX);
+    (expiry_key(blocks_pool, key), kv_util::value_from_slice(blocks_pool, &millis.to_be_bytes()))
+}
+
+fn parse_deadline(value: &kv::Value) -> Option<SystemTime> {
+    let millis = <[u8; 8]>::try_from(&value.value_bytes[..]).ok()?;
+    deadline_from_millis(u64::from_be_bytes(millis))
+}
+
+/// Reserved records found on startup, see [`parse`].
+#[derive(Default)]
+pub struct Recovered {
+    /// Committed batches in the order they were submitted, to be applied again.
+    pub batches: Vec<Vec<WriteBatchOp>>,
+    /// Intent records to remove once the batches are applied again.
+    pub stale_keys: Vec<kv::Key>,
+    /// Expiry deadlines of user keys, as of after the batches are applied again.
+    pub expiries: Vec<(kv::Key, SystemTime)>,
+    /// Past the highest batch id found, so new batches never reuse the id of
+    /// an intent left behind.
+    pub next_batch_id: u64,
+}
+
+pub fn parse(blocks_pool: &BytesPool, records: Vec<(kv::Key, kv::Value)>) -> Recovered {
+    let mut parts = BTreeMap::new();
+    let mut commits = BTreeMap::new();
+    let mut commit_keys = Vec::new();
+    let mut part_keys = Vec::new();
+    let mut expiries = BTreeMap::new();
+    let mut next_batch_id = 0;
+    for (key, value) in records {
+        match parse_key(&key) {
+            Some(ReservedKey::BatchPart { batch_id, part_index, }) => {
+                next_batch_id = next_batch_id.max(batch_id.saturating_add(1));
+                parts.insert((batch_id, part_index), value);
+                part_keys.push(key);
+            },
+            Some(ReservedKey::BatchCommit { batch_id, }) => {
+                next_batch_id = next_batch_id.max(batch_id.saturating_add(1));
+                match <[u8; 4]>::try_from(&value.value_bytes[..]) {
+                    Ok(parts_count) => {
+                        commits.insert(batch_id, u32::from_be_bytes(parts_count));
+                    },
+                    Err(..) =>
+                        log::error!("malformed commit record of write batch {}, skipping", batch_id),
+                }
+                commit_keys.push(key);
+            },
+            Some(ReservedKey::Expiry { key_bytes, }) =>
+                match parse_deadline(&value) {
+                    Some(deadline) => {
+                        expiries.insert(key_bytes, deadline);
+                    },
+                    None =>
+                        log::error!("malformed expiry record {:?}, ignoring", key),
+                },
+            None =>
+                log::warn!("unknown reserved record {:?}, leaving it intact", key),
+        }
+    }
+
+    let mut recovered = Recovered { next_batch_id, ..Default::default() };
+    for (batch_id, parts_count) in commits {
+        let mut payload = Vec::new();
+        for part_index in 0 .. parts_count {
+            match parts.get(&(batch_id, part_index)) {
+                Some(part) =>
+                    payload.extend_from_slice(&part.value_bytes),
+                None => {
+                    payload.clear();
+                    break;
+                },
+            }
+        }
+        match decode_ops(blocks_pool, &payload) {
+            Some(ops) if !ops.is_empty() => {
+                // batches may carry expiry records too, and they are applied
+                // after the records above were read
+                for op in &ops {
+                    match op {
+                        WriteBatchOp::Insert { key, value, } =>
+                            if let Some(ReservedKey::Expiry { key_bytes, }) = parse_key(key) {
+                                if let Some(deadline) = parse_deadline(value) {
+                                    expiries.insert(key_bytes, deadline);
+                                }
+                            },
+                        WriteBatchOp::Remove { key, } =>
+                            if let Some(ReservedKey::Expiry { key_bytes, }) = parse_key(key) {
+                                expiries.remove(&key_bytes);
+                            },
+                    }
+                }
+                recovered.batches.push(ops);
+            },
+            _ =>
+                log::error!("write batch {} is committed but its intent is damaged, skipping", batch_id),
+        }
+    }
+    recovered.stale_keys = commit_keys;
+    recovered.stale_keys.extend(part_keys);
+    recovered.expiries = expiries
+        .into_iter()
+        .map(|(key_bytes, deadline)| (kv_util::key_from_slice(blocks_pool, &key_bytes), deadline))
+        .collect();
+    recovered
+}
+
+enum ReservedKey {
+    BatchPart { batch_id: u64, part_index: u32, },
+    BatchCommit { batch_id: u64, },
+    Expiry { key_bytes: Vec<u8>, },
+}
+
+fn parse_key(key: &kv::Key) -> Option<ReservedKey> {
+    let suffix = key.key_bytes.strip_prefix(RESERVED_KEY_PREFIX)?;
+    let (&tag, rest) = suffix.split_first()?;
+    match tag {
+        TAG_BATCH_PART if rest.len() == 12 =>
+            Some(ReservedKey::BatchPart {
+                batch_id: u64::from_be_bytes(rest[.. 8].try_into().ok()?),
+                part_index: u32::from_be_bytes(rest[8 ..].try_into().ok()?),
+            }),
+        TAG_BATCH_COMMIT if rest.len() == 8 =>
+            Some(ReservedKey::BatchCommit {
+                batch_id: u64::from_be_bytes(rest.try_into().ok()?),
+            }),
+        TAG_EXPIRY =>
+            Some(ReservedKey::Expiry { key_bytes: rest.to_vec(), }),
+        _ =>
+            None,
+    }
+}
+
+fn reserved_key(blocks_pool: &BytesPool, tag: u8, suffix: &[u8]) -> kv::Key {
+    let mut key_bytes = RESERVED_KEY_PREFIX.to_vec();
+    key_bytes.push(tag);
+    key_bytes.extend_from_slice(suffix);
+    kv_util::key_from_slice(blocks_pool, &key_bytes)
+}
+
+fn batch_part_key(blocks_pool: &BytesPool, batch_id: u64, part_index: u32) -> kv::Key {
+    let mut suffix = batch_id.to_be_bytes().to_vec();
+    suffix.extend_from_slice(&part_index.to_be_bytes());
+    reserved_key(blocks_pool, TAG_BATCH_PART, &suffix)
+}
+
+fn batch_commit_key(blocks_pool: &BytesPool, batch_id: u64) -> kv::Key {
+    reserved_key(blocks_pool, TAG_BATCH_COMMIT, &batch_id.to_be_bytes())
+}
+
+fn encode_ops(ops: &[WriteBatchOp]) -> Vec<u8> {
+    fn put_bytes(payload: &mut Vec<u8>, bytes: &[u8]) {
+        payload.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+        payload.extend_from_slice(bytes);
+    }
+
+    let mut payload = Vec::new();
+    for op in ops {
+        match op {
+            WriteBatchOp::Insert { key, value, } => {
+                payload.push(OP_INSERT);
+                put_bytes(&mut payload, &key.key_bytes);
+                put_bytes(&mut payload, &value.value_bytes);
+            },
+            WriteBatchOp::Remove { key, } => {
+                payload.push(OP_REMOVE);
+                put_bytes(&mut payload, &key.key_bytes);
+            },
+        }
+    }
+    payload
+}
+
+fn decode_ops(blocks_pool: &BytesPool, mut payload: &[u8]) -> Option<Vec<WriteBatchOp>> {
+    fn take_bytes<'a>(input: &mut &'a [u8]) -> Option<&'a [u8]> {
+        if input.len() < 4 {
+            return None;
+        }
+        let (length, rest) = input.split_at(4);
+        let length = u32::from_be_bytes(length.try_into().ok()?) as usize;
+        if rest.len() < length {
+            return None;
+        }
+        let (bytes, rest) = rest.split_at(length);
+        *input = rest;
+        Some(bytes)
+    }
+
+    let mut ops = Vec::new();
+    while let Some((&op_tag, rest)) = payload.split_first() {
+        payload = rest;
+        let key = kv_util::key_from_slice(blocks_pool, take_bytes(&mut payload)?);
+        match op_tag {
+            OP_INSERT => {
+                let value = kv_util::value_from_slice(blocks_pool, take_bytes(&mut payload)?);
+                ops.push(WriteBatchOp::Insert { key, value, });
+            },
+            OP_REMOVE =>
+                ops.push(WriteBatchOp::Remove { key, }),
+            _ =>
+                return None,
+        }
+    }
+    Some(ops)
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc_pool::{
+        bytes::{
+            BytesPool,
+        },
+    };
+
+    use crate::{
+        kv_util,
+        WriteBatchOp,
+    };
+
+    use std::{
+        time::{
+            Duration,
+            UNIX_EPOCH,
+        },
+    };
+
+    use super::{
+        is_reserved,
+        parse,
+        expiry_key,
+        expiry_record,
+        BatchIntent,
+        BATCH_PART_SIZE,
+    };
+
+    fn sample_ops(blocks_pool: &BytesPool) -> Vec<WriteBatchOp> {
+        vec![
+            WriteBatchOp::Insert {
+                key: kv_util::key_from_slice(blocks_pool, b"a"),
+                value: kv_util::value_from_slice(blocks_pool, &vec![0x5a; BATCH_PART_SIZE * 2]),
+            },
+            WriteBatchOp::Remove {
+                key: kv_util::key_from_slice(blocks_pool, b"b"),
+            },
+        ]
+    }
+
+    fn op_bytes(op: &WriteBatchOp) -> (Vec<u8>, Option<Vec<u8>>) {
+        match op {
+            WriteBatchOp::Insert { key, value, } =>
+                (key.key_bytes.to_vec(), Some(value.value_bytes.to_vec())),
+            WriteBatchOp::Remove { key, } =>
+                (key.key_bytes.to_vec(), None),
+        }
+    }
+
+    #[test]
+    fn committed_intent_is_recovered() {
+        let blocks_pool = BytesPool::new();
+        let ops = sample_ops(&blocks_pool);
+        let intent = BatchIntent::new(&blocks_pool, 7, &ops);
+        assert!(intent.records.len() > 2);
+        assert!(intent.records.iter().all(|(key, _value)| is_reserved(key)));
+        assert_eq!(intent.keys.len(), intent.records.len());
+        assert_eq!(&intent.keys[0].key_bytes[..], &intent.records.last().unwrap().0.key_bytes[..]);
+
+        let recovered = parse(&blocks_pool, intent.records);
+        assert_eq!(recovered.batches.len(), 1);
+        let recovered_ops: Vec<_> = recovered.batches[0].iter().map(op_bytes).collect();
+        let expected_ops: Vec<_> = ops.iter().map(op_bytes).collect();
+        assert_eq!(recovered_ops, expected_ops);
+        assert_eq!(recovered.stale_keys.len(), intent.keys.len());
+        assert_eq!(recovered.next_batch_id, 8);
+    }
+
+    #[test]
+    fn intent_without_commit_is_dropped() {
+        let blocks_pool = BytesPool::new();
+        let ops = sample_ops(&blocks_pool);
+        let mut intent = BatchIntent::new(&blocks_pool, 7, &ops);
+        intent.records.pop();
+        let parts_count = intent.records.len();
+
+        let recovered = parse(&blocks_pool, intent.records);
+        assert!(recovered.batches.is_empty());
+        assert_eq!(recovered.stale_keys.len(), parts_count);
+        assert_eq!(recovered.next_batch_id, 8);
+    }
+
+    #[test]
+    fn commit_with_missing_part_is_skipped() {
+        let blocks_pool = BytesPool::new();
+        let ops = sample_ops(&blocks_pool);
+        let mut intent = BatchIntent::new(&blocks_pool, 7, &ops);
+        intent.records.remove(1);
+
+        let recovered = parse(&blocks_pool, intent.records);
+        assert!(recovered.batches.is_empty());
+    }
+
+    #[test]
+    fn expiry_records_are_loaded_after_replayed_batches() {
+        let blocks_pool = BytesPool::new();
+        let deadline = UNIX_EPOCH + Duration::from_millis(1_700_000_000_123);
+        let key_a = kv_util::key_from_slice(&blocks_pool, b"a");
+        let key_b = kv_util::key_from_slice(&blocks_pool, b"b");
+        let key_c = kv_util::key_from_slice(&blocks_pool, b"c");
+
+        // "a" keeps its deadline, "b" loses it and "c" gets one within a
+        // committed batch
+        let mut records = vec![
+            expiry_record(&blocks_pool, &key_a, deadline),
+            expiry_record(&blocks_pool, &key_b, deadline),
+        ];
+        let (record_key_c, record_value_c) = expiry_record(&blocks_pool, &key_c, deadline);
+        let ops = vec![
+            WriteBatchOp::Remove { key: expiry_key(&blocks_pool, &key_b), },
+            WriteBatchOp::Insert { key: record_key_c, value: record_value_c, },
+        ];
+        records.extend(BatchIntent::new(&blocks_pool, 0, &ops).records);
+
+        let recovered = parse(&blocks_pool, records);
+        assert_eq!(recovered.batches.len(), 1);
+        let expiries: Vec<_> = recovered.expiries
+            .iter()
+            .map(|(key, deadline)| (key.key_bytes.to_vec(), *deadline))
+            .collect();
+        assert_eq!(expiries, vec![(b"a".to_vec(), deadline), (b"c".to_vec(), deadline)]);
+    }
+}
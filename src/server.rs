@@ -0,0 +1,293 @@
+//! TCP front end serving a [`Pid`] over the [`crate::wire`] protocol.
+
+use std::{
+    ops::{
+        Bound,
+    },
+    sync::{
+        Arc,
+    },
+    time::{
+        Duration,
+    },
+};
+
+use futures::{
+    channel::{
+        mpsc,
+    },
+    future,
+    SinkExt,
+    StreamExt,
+};
+
+use futures_timer::{
+    Delay,
+};
+
+use tokio::{
+    net::{
+        TcpListener,
+        TcpStream,
+    },
+    sync::{
+        Semaphore,
+        OwnedSemaphorePermit,
+    },
+};
+
+use alloc_pool::{
+    bytes::{
+        BytesPool,
+    },
+};
+
+use crate::{
+    kv,
+    kv_util,
+    wire,
+    Pid,
+    Flushed,
+    StoreInfo,
+    BackendError,
+    InfoError,
+    InsertError,
+    LookupError,
+    LookupRangeError,
+    RemoveError,
+    FlushError,
+};
+
+/// Maps any of the [`Pid`] error enums, which all share the same shape.
+/// Variants only some of them have are mapped by the extra arms given.
+macro_rules! error_reply {
+    ($error_type:ident, $error:expr $(, $pattern:pat => $reply:expr)*) => {
+        match $error {
+            $error_type::GenServer(..) =>
+                wire::ErrorReply::GenServer,
+            $error_type::Backend(BackendError::Local(error)) =>
+                wire::ErrorReply::Backend(format!("{:?}", error)),
+            $error_type::Backend(BackendError::Remote(description)) =>
+                wire::ErrorReply::Backend(description),
+            $error_type::Timeout =>
+                wire::ErrorReply::Timeout,
+            $error_type::Dropped { attempts, } =>
+                wire::ErrorReply::Dropped { attempts: attempts as u64, },
+            $error_type::Remote(error) =>
+                wire::ErrorReply::Backend(format!("{:?}", error)),
+            $($pattern =>
+                $reply,)*
+        }
+    };
+}
+
+/// How many response frames a connection may queue ahead of the socket.
+const RESPONSES_WINDOW: usize = 256;
+
+/// How many requests of a single connection may be processed at once, the
+/// connection is not read any further until one of them completes.
+const MAX_REQUESTS_IN_FLIGHT: usize = 128;
+
+/// How many connections are served at once, further ones wait in the listen
+/// backlog until one of them is closed.
+const MAX_CONNECTIONS: usize = 1024;
+
+/// Pause after a failed accept, which is usually caused by running out of
+/// file descriptors and is likely to fail again right away.
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_millis(100);
+
+/// Accepts connections on `listener` until dropped, each connection is served
+/// on its own task, up to [`MAX_CONNECTIONS`] at once. Failed accepts are
+/// logged and retried after a short pause.
+pub async fn serve(listener: TcpListener, pid: Pid, blocks_pool: BytesPool) {
+    let connections = Arc::new(Semaphore::new(MAX_CONNECTIONS));
+    loop {
+        let permit = match connections.clone().acquire_owned().await {
+            Ok(permit) =>
+                permit,
+            Err(error) => {
+                log::error!("connections semaphore is closed: {:?}", error);
+                return;
+            },
+        };
+        match listener.accept().await {
+            Ok((stream, peer_addr)) => {
+                log::debug!("accepted connection from {}", peer_addr);
+                let connection = serve_connection(stream, pid.clone(), blocks_pool.clone());
+                tokio::spawn(async move {
+                    connection.await;
+                    drop(permit);
+                });
+            },
+            Err(error) => {
+                log::error!("failed to accept connection: {:?}", error);
+                Delay::new(ACCEPT_ERROR_DELAY).await;
+            },
+        }
+    }
+}
+
+/// Serves requests from a single connection until the peer closes it. Requests
+/// are processed concurrently, up to [`MAX_REQUESTS_IN_FLIGHT`] at once, so
+/// responses may come out of order.
+pub async fn serve_connection(stream: TcpStream, pid: Pid, blocks_pool: BytesPool) {
+    let (mut reader, mut writer) = stream.into_split();
+    let (responses_tx, mut responses_rx) = mpsc::channel::<wire::ResponseFrame>(RESPONSES_WINDOW);
+    let requests_in_flight = Arc::new(Semaphore::new(MAX_REQUESTS_IN_FLIGHT));
+
+    let read_loop = async move {
+        loop {
+            let payload = match wire::read_frame(&mut reader).await {
+                Ok(Some(payload)) =>
+                    payload,
+                Ok(None) =>
+                    break,
+                Err(error) => {
+                    log::warn!("failed to read request frame: {:?}", error);
+                    break;
+                },
+            };
+            let request_frame = match wire::RequestFrame::decode(&payload) {
+                Ok(request_frame) =>
+                    request_frame,
+                Err(error) => {
+                    log::warn!("malformed request frame, closing connection: {:?}", error);
+                    break;
+                },
+            };
+            let permit = match requests_in_flight.clone().acquire_owned().await {
+                Ok(permit) =>
+                    permit,
+                Err(error) => {
+                    log::error!("requests semaphore is closed: {:?}", error);
+                    break;
+                },
+            };
+            tokio::spawn(serve_request(
+                request_frame,
+                pid.clone(),
+                blocks_pool.clone(),
+                responses_tx.clone(),
+                permit,
+            ));
+        }
+    };
+
+    let write_loop = async move {
+        while let Some(response_frame) = responses_rx.next().await {
+            if let Err(error) = wire::write_frame(&mut writer, &response_frame.encode()).await {
+                log::warn!("failed to write response frame: {:?}", error);
+                break;
+            }
+        }
+    };
+
+    future::join(read_loop, write_loop).await;
+}
+
+async fn serve_request(
+    request_frame: wire::RequestFrame,
+    mut pid: Pid,
+    blocks_pool: BytesPool,
+    mut responses_tx: mpsc::Sender<wire::ResponseFrame>,
+    _permit: OwnedSemaphorePermit,
+) {
+    let wire::RequestFrame { request_id, request, } = request_frame;
+    let key_from = |bytes: &[u8]| kv_util::key_from_slice(&blocks_pool, bytes);
+
+    let response = match request {
+        wire::Request::Info =>
+            match pid.info().await {
+                Ok(info) =>
+                    wire::Response::Info { description: StoreInfo::from(info).description, },
+                Err(error) =>
+                    wire::Response::Error(error_reply!(InfoError, error)),
+            },
+        wire::Request::Insert { key, value, } => {
+            let value = kv_util::value_from_slice(&blocks_pool, &value);
+            match pid.insert(key_from(&key), value).await {
+                Ok(inserted) =>
+                    wire::Response::Inserted { version: inserted.version, },
+                Err(error) =>
+                    wire::Response::Error(error_reply!(InsertError, error, InsertError::ReservedKey => wire::ErrorReply::ReservedKey)),
+            }
+        },
+        wire::Request::Lookup { key, } =>
+            match pid.lookup(key_from(&key)).await {
+                Ok(maybe_value_cell) =>
+                    wire::Response::Lookup { cell: maybe_value_cell.map(wire_cell), },
+                Err(error) =>
+                    wire::Response::Error(error_reply!(LookupError, error)),
+            },
+        wire::Request::LookupRange { range_from, range_to, } => {
+            let range_from = map_bound(range_from, key_from);
+            let range_to = map_bound(range_to, key_from);
+            let mut lookup_range = match pid.lookup_range((range_from, range_to)).await {
+                Ok(lookup_range) =>
+                    lookup_range,
+                Err(error) =>
+                    return send_response(&mut responses_tx, request_id, wire::Response::Error(
+                        error_reply!(LookupRangeError, error),
+                    )).await,
+            };
+            loop {
+                let response = match lookup_range.next().await {
+                    Some(Ok(kv::KeyValuePair { key, value_cell, })) =>
+                        wire::Response::RangeItem { key: key.key_bytes.to_vec(), cell: wire_cell(value_cell), },
+                    Some(Err(error)) =>
+                        break wire::Response::Error(error_reply!(LookupRangeError, error)),
+                    None =>
+                        break wire::Response::RangeEnd,
+                };
+                if responses_tx.send(wire::ResponseFrame { request_id, response, }).await.is_err() {
+                    log::debug!("connection is closed during lookup range");
+                    return;
+                }
+            }
+        },
+        wire::Request::Remove { key, } =>
+            match pid.remove(key_from(&key)).await {
+                Ok(removed) =>
+                    wire::Response::Removed { version: removed.version, },
+                Err(error) =>
+                    wire::Response::Error(error_reply!(RemoveError, error, RemoveError::ReservedKey => wire::ErrorReply::ReservedKey)),
+            },
+        wire::Request::FlushAll =>
+            match pid.flush_all().await {
+                Ok(Flushed) =>
+                    wire::Response::Flushed,
+                Err(error) =>
+                    wire::Response::Error(error_reply!(FlushError, error)),
+            },
+    };
+    send_response(&mut responses_tx, request_id, response).await
+}
+
+async fn send_response(responses_tx: &mut mpsc::Sender<wire::ResponseFrame>, request_id: u64, response: wire::Response) {
+    if responses_tx.send(wire::ResponseFrame { request_id, response, }).await.is_err() {
+        log::debug!("connection is closed before response is sent");
+    }
+}
+
+fn wire_cell(value_cell: kv::ValueCell<kv::Value>) -> wire::Cell {
+    wire::Cell {
+        version: value_cell.version,
+        value: match value_cell.cell {
+            kv::Cell::Value(value) =>
+                Some(value.value_bytes.to_vec()),
+            kv::Cell::Tombstone =>
+                None,
+        },
+    }
+}
+
+fn map_bound<F>(bound: Bound<Vec<u8>>, key_from: F) -> Bound<kv::Key> where F: Fn(&[u8]) -> kv::Key {
+    match bound {
+        Bound::Included(key) =>
+            Bound::Included(key_from(&key)),
+        Bound::Excluded(key) =>
+            Bound::Excluded(key_from(&key)),
+        Bound::Unbounded =>
+            Bound::Unbounded,
+    }
+}
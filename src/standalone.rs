@@ -0,0 +1,306 @@
+//! Config file and bootstrap shared by the bundled binaries: starts a thread
+//! pool, an ero supervisor and a [`GenServer`] over the configured wheels.
+
+use std::{
+    io,
+    fs,
+    path::{
+        Path,
+        PathBuf,
+    },
+    time::{
+        Duration,
+    },
+};
+
+use serde::{
+    Deserialize,
+};
+
+use alloc_pool::{
+    bytes::{
+        BytesPool,
+    },
+};
+
+use crate::{
+    job,
+    wheels,
+    version,
+    Pid,
+    GenServer,
+    GenServerParams,
+};
+
+#[derive(Debug)]
+pub enum Error {
+    ReadConfig { path: PathBuf, error: io::Error, },
+    ParseConfig { path: PathBuf, error: toml::de::Error, },
+    Wheels(wheels::Error),
+    ThreadPool(edeltraud::BuildError),
+}
+
+/// Example:
+///
+/// ```toml
+/// [server]
+/// listen_addr = "127.0.0.1:4890"
+///
+/// [http]
+/// listen_addr = "127.0.0.1:4891"
+///
+/// [gen_server.restart_strategy]
+/// kind = "delay"
+/// restart_after_ms = 5000
+///
+/// [[wheels]]
+/// filename = "/var/lib/bkv/wheel_a"
+/// init_wheel_size_bytes = 1073741824
+///
+/// [[wheels]]
+/// filename = "/var/lib/bkv/wheel_b"
+/// ```
+#[derive(Clone, Debug, Deserialize)]
+pub struct Config {
+    pub wheels: Vec<WheelConfig>,
+    #[serde(default)]
+    pub gen_server: GenServerConfig,
+    #[serde(default)]
+    pub server: ServerConfig,
+    /// The HTTP gateway is started only if this section is present.
+    pub http: Option<HttpConfig>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct WheelConfig {
+    pub filename: PathBuf,
+    /// Size of a newly created wheel file, unused when the file exists.
+    #[serde(default = "default_init_wheel_size_bytes")]
+    pub init_wheel_size_bytes: usize,
+    pub work_block_size_bytes: Option<usize>,
+    pub lru_cache_size_bytes: Option<usize>,
+}
+
+fn default_init_wheel_size_bytes() -> usize {
+    64 * 1024 * 1024
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct GenServerConfig {
+    #[serde(default = "default_lookup_range_buffer")]
+    pub lookup_range_buffer: usize,
+    #[serde(default = "default_watch_buffer")]
+    pub watch_buffer: usize,
+    /// [`GenServerParams::restart_strategy`], its default if not present.
+    pub restart_strategy: Option<RestartStrategyConfig>,
+}
+
+fn default_lookup_range_buffer() -> usize {
+    GenServerParams::default().lookup_range_buffer
+}
+
+fn default_watch_buffer() -> usize {
+    GenServerParams::default().watch_buffer
+}
+
+impl Default for GenServerConfig {
+    fn default() -> Self {
+        Self {
+            lookup_range_buffer: default_lookup_range_buffer(),
+            watch_buffer: default_watch_buffer(),
+            restart_strategy: None,
+        }
+    }
+}
+
+impl GenServerConfig {
+    pub fn gen_server_params(&self) -> GenServerParams {
+        let defaults = GenServerParams::default();
+        GenServerParams {
+            lookup_range_buffer: self.lookup_range_buffer,
+            watch_buffer: self.watch_buffer,
+            restart_strategy: match &self.restart_strategy {
+                None =>
+                    defaults.restart_strategy,
+                Some(RestartStrategyConfig::InstantCrash) =>
+                    ero::RestartStrategy::InstantCrash,
+                Some(RestartStrategyConfig::Delay { restart_after_ms, }) =>
+                    ero::RestartStrategy::Delay {
+                        restart_after: Duration::from_millis(*restart_after_ms),
+                    },
+            },
+        }
+    }
+}
+
+/// Deserializable form of `ero::RestartStrategy`.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum RestartStrategyConfig {
+    /// Any failure terminates the gen_server for good.
+    InstantCrash,
+    /// Restart after a fixed delay on every failure.
+    Delay { restart_after_ms: u64, },
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ServerConfig {
+    #[serde(default = "default_listen_addr")]
+    pub listen_addr: String,
+}
+
+fn default_listen_addr() -> String {
+    "127.0.0.1:4890".to_string()
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self { listen_addr: default_listen_addr(), }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct HttpConfig {
+    pub listen_addr: String,
+}
+
+impl Config {
+    /// Config with default settings for the given wheel files. Files that do
+    /// not exist are created with the default initial size.
+    pub fn with_wheel_files<I>(filenames: I) -> Config where I: IntoIterator<Item = PathBuf> {
+        Config {
+            wheels: filenames
+                .into_iter()
+                .map(|filename| WheelConfig {
+                    filename,
+                    init_wheel_size_bytes: default_init_wheel_size_bytes(),
+                    work_block_size_bytes: None,
+                    lru_cache_size_bytes: None,
+                })
+                .collect(),
+            gen_server: GenServerConfig::default(),
+            server: ServerConfig::default(),
+            http: None,
+        }
+    }
+}
+
+pub fn load_config<P>(path: P) -> Result<Config, Error> where P: AsRef<Path> {
+    let path = path.as_ref();
+    let contents = fs::read_to_string(path)
+        .map_err(|error| Error::ReadConfig { path: path.to_path_buf(), error, })?;
+    toml::from_str(&contents)
+        .map_err(|error| Error::ParseConfig { path: path.to_path_buf(), error, })
+}
+
+impl WheelConfig {
+    pub fn wheel_ref(&self, blocks_pool: &BytesPool) -> wheels::WheelRef {
+        let mut blockwheel_fs_params = blockwheel_fs::Params {
+            interpreter: blockwheel_fs::InterpreterParams::FixedFile(
+                blockwheel_fs::FixedFileInterpreterParams {
+                    wheel_filename: self.filename.clone(),
+                    init_wheel_size_bytes: self.init_wheel_size_bytes,
+                },
+            ),
+            ..Default::default()
+        };
+        if let Some(work_block_size_bytes) = self.work_block_size_bytes {
+            blockwheel_fs_params.work_block_size_bytes = work_block_size_bytes;
+        }
+        if let Some(lru_cache_size_bytes) = self.lru_cache_size_bytes {
+            blockwheel_fs_params.lru_cache_size_bytes = lru_cache_size_bytes;
+        }
+        wheels::WheelRef {
+            blockwheel_filename: wheels::WheelFilename::from_path(&self.filename, blocks_pool),
+            blockwheel_fs_params,
+        }
+    }
+}
+
+/// Running gen_server together with the thread pool and supervisor it needs.
+pub struct Node {
+    pid: Pid,
+    blocks_pool: BytesPool,
+    _thread_pool: edeltraud::Edeltraud<job::Job>,
+}
+
+impl Node {
+    /// Starts everything described by `config`. Must be called within a tokio
+    /// runtime.
+    pub fn start(config: &Config) -> Result<Node, Error> {
+        let blocks_pool = BytesPool::new();
+
+        let mut wheels_builder = wheels::WheelsBuilder::new();
+        for wheel_config in &config.wheels {
+            wheels_builder.add_wheel_ref(wheel_config.wheel_ref(&blocks_pool));
+        }
+        let wheels = wheels_builder.build()
+            .map_err(Error::Wheels)?;
+
+        let thread_pool: edeltraud::Edeltraud<job::Job> = edeltraud::Builder::new()
+            .build::<_, job::JobUnit<_>>()
+            .map_err(Error::ThreadPool)?;
+
+        let supervisor_gen_server = ero::supervisor::SupervisorGenServer::new();
+        let mut supervisor_pid = supervisor_gen_server.pid();
+        tokio::spawn(supervisor_gen_server.run());
+
+        let gen_server = GenServer::with_params(config.gen_server.gen_server_params());
+        let pid = gen_server.pid();
+        supervisor_pid.spawn_link_permanent(
+            gen_server.run(
+                supervisor_pid.clone(),
+                blockwheel_kv::Params::default(),
+                blocks_pool.clone(),
+                version::Provider::from_unix_epoch_seed(),
+                wheels,
+                thread_pool.handle(),
+            ),
+        );
+
+        Ok(Node { pid, blocks_pool, _thread_pool: thread_pool, })
+    }
+
+    pub fn pid(&self) -> Pid {
+        self.pid.clone()
+    }
+
+    pub fn blocks_pool(&self) -> &BytesPool {
+        &self.blocks_pool
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        Config,
+        RestartStrategyConfig,
+    };
+
+    #[test]
+    fn restart_strategy_is_read_from_config() {
+        let config: Config = toml::from_str(r#"
+            [[wheels]]
+            filename = "wheel"
+
+            [gen_server]
+            watch_buffer = 16
+
+            [gen_server.restart_strategy]
+            kind = "delay"
+            restart_after_ms = 5000
+        "#).unwrap();
+        assert!(matches!(config.gen_server.restart_strategy, Some(RestartStrategyConfig::Delay { restart_after_ms: 5000, })));
+        assert_eq!(config.gen_server.gen_server_params().watch_buffer, 16);
+
+        let config: Config = toml::from_str(r#"
+            [[wheels]]
+            filename = "wheel"
+
+            [gen_server.restart_strategy]
+            kind = "instant_crash"
+        "#).unwrap();
+        assert!(matches!(config.gen_server.restart_strategy, Some(RestartStrategyConfig::InstantCrash)));
+        assert!(matches!(config.gen_server.gen_server_params().restart_strategy, ero::RestartStrategy::InstantCrash));
+    }
+}
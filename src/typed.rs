@@ -0,0 +1,528 @@
+//! Typed access on top of [`Pid`]: keys and values are converted from user
+//! types with [`KeyCodec`] and [`ValueCodec`].
+//!
+//! Key encodings preserve order: comparing encoded keys bytewise gives the
+//! same result as comparing the original values, so range lookups over
+//! integers, strings and tuples of them sort naturally. Integers are encoded
+//! big-endian (signed ones with the sign bit flipped), byte strings are
+//! escaped (`0x00` becomes `0x00 0xff`) and terminated with `0x00 0x01`, and
+//! tuples are the concatenation of their elements.
+
+use std::{
+    pin::{
+        Pin,
+    },
+    task::{
+        Poll,
+        Context,
+    },
+    ops::{
+        Bound,
+        RangeBounds,
+    },
+    marker::{
+        PhantomData,
+    },
+};
+
+use futures::{
+    stream::{
+        FusedStream,
+    },
+    ready,
+    Stream,
+    StreamExt,
+};
+
+use alloc_pool::{
+    bytes::{
+        BytesPool,
+    },
+};
+
+use crate::{
+    kv,
+    kv_util,
+    Pid,
+    Inserted,
+    Removed,
+    InsertError,
+    LookupError,
+    LookupRange,
+    LookupRangeError,
+    RemoveError,
+};
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum DecodeError {
+    UnexpectedEnd,
+    InvalidEscape,
+    TrailingBytes,
+    InvalidUtf8,
+}
+
+/// Order preserving key encoding.
+pub trait KeyCodec: Sized {
+    fn encode_key(&self, out: &mut Vec<u8>);
+
+    /// Decodes a key from the beginning of `input` advancing it past the
+    /// consumed bytes.
+    fn decode_key(input: &mut &[u8]) -> Result<Self, DecodeError>;
+}
+
+pub trait ValueCodec: Sized {
+    fn encode_value(&self, out: &mut Vec<u8>);
+
+    fn decode_value(input: &[u8]) -> Result<Self, DecodeError>;
+}
+
+const ESCAPE: u8 = 0x00;
+const ESCAPED_ZERO: u8 = 0xff;
+const TERMINATOR: u8 = 0x01;
+
+fn encode_escaped(bytes: &[u8], out: &mut Vec<u8>) {
+    for &byte in bytes {
+        out.push(byte);
+        if byte == ESCAPE {
+            out.push(ESCAPED_ZERO);
+        }
+    }
+    out.push(ESCAPE);
+    out.push(TERMINATOR);
+}
+
+fn decode_escaped(input: &mut &[u8]) -> Result<Vec<u8>, DecodeError> {
+    let mut bytes = Vec::new();
+    let mut offset = 0;
+    loop {
+        match input.get(offset ..) {
+            Some([ESCAPE, ESCAPED_ZERO, ..]) => {
+                bytes.push(ESCAPE);
+                offset += 2;
+            },
+            Some([ESCAPE, TERMINATOR, ..]) => {
+                *input = &input[offset + 2 ..];
+                return Ok(bytes);
+            },
+            Some([ESCAPE, ..]) =>
+                return Err(DecodeError::InvalidEscape),
+            Some([byte, ..]) => {
+                bytes.push(*byte);
+                offset += 1;
+            },
+            Some([]) | None =>
+                return Err(DecodeError::UnexpectedEnd),
+        }
+    }
+}
+
+fn take_array<const N: usize>(input: &mut &[u8]) -> Result<[u8; N], DecodeError> {
+    if input.len() < N {
+        return Err(DecodeError::UnexpectedEnd);
+    }
+    let (head, tail) = input.split_at(N);
+    *input = tail;
+    Ok(head.try_into().unwrap())
+}
+
+macro_rules! impl_unsigned {
+    ($($ty:ty),*) => {
+        $(
+            impl KeyCodec for $ty {
+                fn encode_key(&self, out: &mut Vec<u8>) {
+                    out.extend_from_slice(&self.to_be_bytes());
+                }
+
+                fn decode_key(input: &mut &[u8]) -> Result<Self, DecodeError> {
+                    Ok(<$ty>::from_be_bytes(take_array(input)?))
+                }
+            }
+
+            impl ValueCodec for $ty {
+                fn encode_value(&self, out: &mut Vec<u8>) {
+                    out.extend_from_slice(&self.to_be_bytes());
+                }
+
+                fn decode_value(mut input: &[u8]) -> Result<Self, DecodeError> {
+                    let value = <$ty>::from_be_bytes(take_array(&mut input)?);
+                    if !input.is_empty() {
+                        return Err(DecodeError::TrailingBytes);
+                    }
+                    Ok(value)
+                }
+            }
+        )*
+    };
+}
+
+impl_unsigned!(u8, u16, u32, u64, u128);
+
+macro_rules! impl_signed {
+    ($($ty:ty => $unsigned:ty),*) => {
+        $(
+            impl KeyCodec for $ty {
+                fn encode_key(&self, out: &mut Vec<u8>) {
+                    // flipping the sign bit moves negative numbers below positive ones
+                    let biased = (*self as $unsigned) ^ (1 << (<$unsigned>::BITS - 1));
+                    out.extend_from_slice(&biased.to_be_bytes());
+                }
+
+                fn decode_key(input: &mut &[u8]) -> Result<Self, DecodeError> {
+                    let biased = <$unsigned>::from_be_bytes(take_array(input)?);
+                    Ok((biased ^ (1 << (<$unsigned>::BITS - 1))) as $ty)
+                }
+            }
+
+            impl ValueCodec for $ty {
+                fn encode_value(&self, out: &mut Vec<u8>) {
+                    out.extend_from_slice(&self.to_be_bytes());
+                }
+
+                fn decode_value(mut input: &[u8]) -> Result<Self, DecodeError> {
+                    let value = <$ty>::from_be_bytes(take_array(&mut input)?);
+                    if !input.is_empty() {
+                        return Err(DecodeError::TrailingBytes);
+                    }
+                    Ok(value)
+                }
+            }
+        )*
+    };
+}
+
+impl_signed!(i8 => u8, i16 => u16, i32 => u32, i64 => u64, i128 => u128);
+
+impl KeyCodec for Vec<u8> {
+    fn encode_key(&self, out: &mut Vec<u8>) {
+        encode_escaped(self, out);
+    }
+
+    fn decode_key(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        decode_escaped(input)
+    }
+}
+
+impl ValueCodec for Vec<u8> {
+    fn encode_value(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(self);
+    }
+
+    fn decode_value(input: &[u8]) -> Result<Self, DecodeError> {
+        Ok(input.to_vec())
+    }
+}
+
+impl KeyCodec for String {
+    fn encode_key(&self, out: &mut Vec<u8>) {
+        encode_escaped(self.as_bytes(), out);
+    }
+
+    fn decode_key(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        String::from_utf8(decode_escaped(input)?)
+            .map_err(|_error| DecodeError::InvalidUtf8)
+    }
+}
+
+impl ValueCodec for String {
+    fn encode_value(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(self.as_bytes());
+    }
+
+    fn decode_value(input: &[u8]) -> Result<Self, DecodeError> {
+        String::from_utf8(input.to_vec())
+            .map_err(|_error| DecodeError::InvalidUtf8)
+    }
+}
+
+macro_rules! impl_tuple {
+    ($($name:ident $var:ident),+) => {
+        impl<$($name),+> KeyCodec for ($($name,)+) where $($name: KeyCodec),+ {
+            fn encode_key(&self, out: &mut Vec<u8>) {
+                let ($($var,)+) = self;
+                $($var.encode_key(out);)+
+            }
+
+            fn decode_key(input: &mut &[u8]) -> Result<Self, DecodeError> {
+                Ok(($($name::decode_key(input)?,)+))
+            }
+        }
+    };
+}
+
+impl_tuple!(A a);
+impl_tuple!(A a, B b);
+impl_tuple!(A a, B b, C c);
+impl_tuple!(A a, B b, C c, D d);
+impl_tuple!(A a, B b, C c, D d, E e);
+
+#[derive(Debug)]
+pub enum TypedError {
+    Insert(InsertError),
+    Lookup(LookupError),
+    LookupRange(LookupRangeError),
+    Remove(RemoveError),
+    DecodeKey(DecodeError),
+    DecodeValue(DecodeError),
+}
+
+/// [`Pid`] wrapper working with `K` keys and `V` values.
+pub struct TypedPid<K, V> {
+    pid: Pid,
+    blocks_pool: BytesPool,
+    _marker: PhantomData<fn() -> (K, V)>,
+}
+
+impl<K, V> Clone for TypedPid<K, V> {
+    fn clone(&self) -> Self {
+        TypedPid {
+            pid: self.pid.clone(),
+            blocks_pool: self.blocks_pool.clone(),
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<K, V> TypedPid<K, V> where K: KeyCodec, V: ValueCodec {
+    pub fn new(pid: Pid, blocks_pool: BytesPool) -> Self {
+        TypedPid { pid, blocks_pool, _marker: PhantomData, }
+    }
+
+    pub fn pid(&mut self) -> &mut Pid {
+        &mut self.pid
+    }
+
+    pub fn into_pid(self) -> Pid {
+        self.pid
+    }
+
+    pub fn encode_key(&self, key: &K) -> kv::Key {
+        let mut key_bytes = Vec::new();
+        key.encode_key(&mut key_bytes);
+        kv_util::key_from_slice(&self.blocks_pool, &key_bytes)
+    }
+
+    pub fn encode_value(&self, value: &V) -> kv::Value {
+        let mut value_bytes = Vec::new();
+        value.encode_value(&mut value_bytes);
+        kv_util::value_from_slice(&self.blocks_pool, &value_bytes)
+    }
+
+    pub async fn insert(&mut self, key: &K, value: &V) -> Result<Inserted, TypedError> {
+        let key = self.encode_key(key);
+        let value = self.encode_value(value);
+        self.pid.insert(key, value).await
+            .map_err(TypedError::Insert)
+    }
+
+    pub async fn lookup(&mut self, key: &K) -> Result<Option<V>, TypedError> {
+        let key = self.encode_key(key);
+        let maybe_value_cell = self.pid.lookup(key).await
+            .map_err(TypedError::Lookup)?;
+        match maybe_value_cell {
+            Some(kv::ValueCell { cell: kv::Cell::Value(value), .. }) =>
+                V::decode_value(&value.value_bytes)
+                    .map(Some)
+                    .map_err(TypedError::DecodeValue),
+            Some(kv::ValueCell { cell: kv::Cell::Tombstone, .. }) | None =>
+                Ok(None),
+        }
+    }
+
+    pub async fn remove(&mut self, key: &K) -> Result<Removed, TypedError> {
+        let key = self.encode_key(key);
+        self.pid.remove(key).await
+            .map_err(TypedError::Remove)
+    }
+
+    /// Looks up all live entries with keys within `range`, in key order.
+    pub async fn lookup_range<R>(&mut self, range: R) -> Result<TypedLookupRange<K, V>, TypedError> where R: RangeBounds<K> {
+        let range_from = self.encode_bound(range.start_bound());
+        let range_to = self.encode_bound(range.end_bound());
+        let lookup_range = self.pid.lookup_range((range_from, range_to)).await
+            .map_err(TypedError::LookupRange)?;
+        Ok(TypedLookupRange { lookup_range, _marker: PhantomData, })
+    }
+
+    fn encode_bound(&self, bound: Bound<&K>) -> Bound<kv::Key> {
+        match bound {
+            Bound::Included(key) =>
+                Bound::Included(self.encode_key(key)),
+            Bound::Excluded(key) =>
+                Bound::Excluded(self.encode_key(key)),
+            Bound::Unbounded =>
+                Bound::Unbounded,
+        }
+    }
+}
+
+/// Typed range lookup stream, tombstones are skipped.
+pub struct TypedLookupRange<K, V> {
+    lookup_range: LookupRange,
+    _marker: PhantomData<fn() -> (K, V)>,
+}
+
+impl<K, V> TypedLookupRange<K, V> {
+    pub fn into_inner(self) -> LookupRange {
+        self.lookup_range
+    }
+}
+
+impl<K, V> Stream for TypedLookupRange<K, V> where K: KeyCodec, V: ValueCodec {
+    type Item = Result<(K, V), TypedError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            match ready!(self.lookup_range.poll_next_unpin(cx)) {
+                Some(Ok(kv::KeyValuePair { key, value_cell: kv::ValueCell { cell: kv::Cell::Value(value), .. }, })) => {
+                    let mut key_bytes: &[u8] = &key.key_bytes;
+                    let item = K::decode_key(&mut key_bytes)
+                        .map_err(TypedError::DecodeKey)
+                        .and_then(|key| if key_bytes.is_empty() {
+                            Ok(key)
+                        } else {
+                            Err(TypedError::DecodeKey(DecodeError::TrailingBytes))
+                        })
+                        .and_then(|key| {
+                            V::decode_value(&value.value_bytes)
+                                .map(|value| (key, value))
+                                .map_err(TypedError::DecodeValue)
+                        });
+                    return Poll::Ready(Some(item));
+                },
+                Some(Ok(kv::KeyValuePair { value_cell: kv::ValueCell { cell: kv::Cell::Tombstone, .. }, .. })) =>
+                    continue,
+                Some(Err(error)) =>
+                    return Poll::Ready(Some(Err(TypedError::LookupRange(error)))),
+                None =>
+                    return Poll::Ready(None),
+            }
+        }
+    }
+}
+
+impl<K, V> FusedStream for TypedLookupRange<K, V> where K: KeyCodec, V: ValueCodec {
+    fn is_terminated(&self) -> bool {
+        self.lookup_range.is_terminated()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        DecodeError,
+        KeyCodec,
+        ValueCodec,
+    };
+
+    fn encode<K>(key: &K) -> Vec<u8> where K: KeyCodec {
+        let mut out = Vec::new();
+        key.encode_key(&mut out);
+        out
+    }
+
+    fn roundtrip<K>(key: K) where K: KeyCodec + PartialEq + std::fmt::Debug {
+        let encoded = encode(&key);
+        let mut input = &encoded[..];
+        assert_eq!(K::decode_key(&mut input), Ok(key));
+        assert!(input.is_empty());
+    }
+
+    /// Encodings of `keys`, which are in ascending order, are in ascending order too.
+    fn assert_ordered<K>(keys: &[K]) where K: KeyCodec + std::fmt::Debug {
+        for pair in keys.windows(2) {
+            assert!(encode(&pair[0]) < encode(&pair[1]), "{:?} should sort before {:?}", pair[0], pair[1]);
+        }
+    }
+
+    #[test]
+    fn byte_strings_are_escaped_and_terminated() {
+        assert_eq!(encode(&b"ab".to_vec()), vec![b'a', b'b', 0x00, 0x01]);
+        assert_eq!(encode(&vec![0x00]), vec![0x00, 0xff, 0x00, 0x01]);
+        assert_eq!(encode(&vec![0xff, 0x00, 0x01]), vec![0xff, 0x00, 0xff, 0x01, 0x00, 0x01]);
+        assert_eq!(encode(&Vec::<u8>::new()), vec![0x00, 0x01]);
+    }
+
+    #[test]
+    fn byte_strings_roundtrip() {
+        roundtrip(Vec::<u8>::new());
+        roundtrip(vec![0x00]);
+        roundtrip(vec![0x00, 0x00, 0x01]);
+        roundtrip(vec![0xff, 0x00, 0xff]);
+        roundtrip(vec![0x01, 0x00, 0x01, 0x00]);
+        roundtrip("k\u{0}ey".to_string());
+    }
+
+    #[test]
+    fn byte_strings_keep_order() {
+        assert_ordered(&[
+            vec![],
+            vec![0x00],
+            vec![0x00, 0x00],
+            vec![0x00, 0x01],
+            vec![0x00, 0xff],
+            vec![0x01],
+            vec![b'a'],
+            vec![b'a', 0x00],
+            vec![b'a', 0x00, 0xff],
+            vec![b'a', 0x01],
+            vec![b'a', 0xff],
+            vec![b'b'],
+            vec![0xff],
+            vec![0xff, 0x00],
+            vec![0xff, 0xff],
+        ]);
+    }
+
+    #[test]
+    fn signed_integers_flip_the_sign_bit() {
+        assert_eq!(encode(&0_i16), vec![0x80, 0x00]);
+        assert_eq!(encode(&-1_i16), vec![0x7f, 0xff]);
+        assert_eq!(encode(&i16::MIN), vec![0x00, 0x00]);
+        assert_eq!(encode(&i16::MAX), vec![0xff, 0xff]);
+        assert_eq!(encode(&258_u16), vec![0x01, 0x02]);
+    }
+
+    #[test]
+    fn integers_roundtrip() {
+        for value in [i64::MIN, i64::MIN + 1, -256, -1, 0, 1, 255, i64::MAX - 1, i64::MAX] {
+            roundtrip(value);
+        }
+        for value in [i8::MIN, -1, 0, 1, i8::MAX] {
+            roundtrip(value);
+        }
+        for value in [0_u32, 1, 0xff, 0x100, u32::MAX] {
+            roundtrip(value);
+        }
+        roundtrip(i128::MIN);
+        roundtrip(u128::MAX);
+    }
+
+    #[test]
+    fn integers_keep_order() {
+        assert_ordered(&[i64::MIN, i64::MIN + 1, -65536, -256, -255, -1, 0, 1, 255, 256, i64::MAX - 1, i64::MAX]);
+        assert_ordered(&[i8::MIN, -1, 0, 1, i8::MAX]);
+        assert_ordered(&[0_u32, 1, 0xff, 0x100, 0xffff, u32::MAX]);
+    }
+
+    #[test]
+    fn tuples_keep_order_and_roundtrip() {
+        assert_ordered(&[
+            (b"a".to_vec(), -1_i32),
+            (b"a".to_vec(), 0),
+            (b"a".to_vec(), i32::MAX),
+            (b"a\x00".to_vec(), i32::MIN),
+            (b"b".to_vec(), i32::MIN),
+        ]);
+        roundtrip((b"a\x00\xff".to_vec(), -7_i32, "x".to_string()));
+    }
+
+    #[test]
+    fn malformed_keys_are_rejected() {
+        assert_eq!(Vec::<u8>::decode_key(&mut &[b'a'][..]), Err(DecodeError::UnexpectedEnd));
+        assert_eq!(Vec::<u8>::decode_key(&mut &[b'a', 0x00][..]), Err(DecodeError::UnexpectedEnd));
+        assert_eq!(Vec::<u8>::decode_key(&mut &[0x00, 0x02][..]), Err(DecodeError::InvalidEscape));
+        assert_eq!(u32::decode_key(&mut &[0x00, 0x01][..]), Err(DecodeError::UnexpectedEnd));
+        assert_eq!(String::decode_key(&mut &[0xc3, 0x00, 0x01][..]), Err(DecodeError::InvalidUtf8));
+        assert_eq!(u16::decode_value(&[0x00, 0x01, 0x02]), Err(DecodeError::TrailingBytes));
+    }
+}
@@ -0,0 +1,134 @@
+use std::{
+    pin::{
+        Pin,
+    },
+    sync::{
+        atomic::{
+            AtomicBool,
+            AtomicUsize,
+            Ordering,
+        },
+        Arc,
+    },
+    task::{
+        Poll,
+        Context,
+    },
+};
+
+use futures::{
+    channel::{
+        mpsc,
+    },
+    stream::{
+        FusedStream,
+    },
+    ready,
+    Stream,
+    StreamExt,
+};
+
+use crate::{
+    kv,
+    Inserted,
+    Removed,
+    WatchError,
+};
+
+/// Change made to a key within a watched range.
+#[derive(Clone, Debug)]
+pub enum WatchEvent {
+    Inserted { key: kv::Key, value: kv::Value, inserted: Inserted, },
+    Removed { key: kv::Key, removed: Removed, },
+}
+
+/// Stream of changes within a range, see [`crate::Pid::watch`].
+///
+/// Events are emitted once `blockwheel_kv` confirms the write, in the order
+/// confirmations arrive. Subscriptions live in the gen_server only: if it
+/// restarts the stream yields [`WatchError::GenServer`] and terminates, so a
+/// client has to watch again (and may have missed changes in between).
+///
+/// At most [`GenServerParams::watch_buffer`](crate::GenServerParams::watch_buffer)
+/// events wait for the subscriber. A subscriber falling further behind is
+/// dropped: the stream yields the events already queued, then
+/// [`WatchError::Lagged`], then terminates.
+pub struct Watch {
+    events_rx: mpsc::UnboundedReceiver<WatchEvent>,
+    shared: Arc<Shared>,
+    finished: bool,
+}
+
+/// Sending half of a [`Watch`], held by the gen_server.
+#[derive(Clone)]
+pub(crate) struct WatchSender {
+    events_tx: mpsc::UnboundedSender<WatchEvent>,
+    shared: Arc<Shared>,
+    capacity: usize,
+}
+
+struct Shared {
+    pending: AtomicUsize,
+    lagged: AtomicBool,
+}
+
+pub(crate) fn channel(capacity: usize) -> (WatchSender, Watch) {
+    let (events_tx, events_rx) = mpsc::unbounded();
+    let shared = Arc::new(Shared {
+        pending: AtomicUsize::new(0),
+        lagged: AtomicBool::new(false),
+    });
+    (
+        WatchSender { events_tx, shared: shared.clone(), capacity, },
+        Watch { events_rx, shared, finished: false, },
+    )
+}
+
+impl WatchSender {
+    /// Queues `event`, or drops the subscriber if it has fallen behind.
+    pub(crate) fn send(&self, event: WatchEvent) {
+        if self.shared.pending.fetch_add(1, Ordering::AcqRel) >= self.capacity {
+            log::debug!("watch subscriber lags behind, dropping it");
+            self.shared.lagged.store(true, Ordering::Release);
+            self.events_tx.close_channel();
+            return;
+        }
+        if let Err(_send_error) = self.events_tx.unbounded_send(event) {
+            log::debug!("watch subscriber is gone");
+        }
+    }
+
+    pub(crate) fn is_closed(&self) -> bool {
+        self.events_tx.is_closed()
+    }
+}
+
+impl Stream for Watch {
+    type Item = Result<WatchEvent, WatchError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.finished {
+            return Poll::Ready(None);
+        }
+        match ready!(self.events_rx.poll_next_unpin(cx)) {
+            Some(event) => {
+                self.shared.pending.fetch_sub(1, Ordering::AcqRel);
+                Poll::Ready(Some(Ok(event)))
+            },
+            None => {
+                self.finished = true;
+                if self.shared.lagged.load(Ordering::Acquire) {
+                    Poll::Ready(Some(Err(WatchError::Lagged)))
+                } else {
+                    Poll::Ready(Some(Err(WatchError::GenServer(ero::NoProcError))))
+                }
+            },
+        }
+    }
+}
+
+impl FusedStream for Watch {
+    fn is_terminated(&self) -> bool {
+        self.finished
+    }
+}
@@ -24,6 +24,7 @@ pub enum Error {
     BlockwheelFsVersklaven(blockwheel_fs::Error),
 }
 
+#[derive(Clone)]
 pub struct WheelRef {
     pub blockwheel_filename: WheelFilename,
     pub blockwheel_fs_params: blockwheel_fs::Params,
@@ -33,6 +34,7 @@ pub struct WheelsBuilder {
     wheels: Vec<WheelRef>,
 }
 
+#[derive(Clone)]
 pub struct Wheels {
     wheels: Vec<WheelRef>,
 }
@@ -65,8 +67,10 @@ impl WheelsBuilder {
 }
 
 impl Wheels {
+    /// Starts the `blockwheel_fs` instances for all wheel refs. Leaves the
+    /// refs intact, so the wheels may be created again after a restart.
     pub(crate) fn create<J>(
-        self,
+        &self,
         blocks_pool: &BytesPool,
         thread_pool: &edeltraud::Handle<J>,
     )
@@ -76,17 +80,17 @@ impl Wheels {
     {
         let mut wheels_builder = blockwheel_kv::wheels::WheelsBuilder::new();
 
-        for WheelRef { blockwheel_filename, blockwheel_fs_params, } in self.wheels {
+        for WheelRef { blockwheel_filename, blockwheel_fs_params, } in &self.wheels {
             let meister =
                 blockwheel_fs::Meister::versklaven(
-                    blockwheel_fs_params,
+                    blockwheel_fs_params.clone(),
                     blocks_pool.clone(),
                     thread_pool,
                 )
                 .map_err(Error::BlockwheelFsVersklaven)?;
             wheels_builder = wheels_builder
                 .add_wheel_ref(blockwheel_kv::wheels::WheelRef {
-                    blockwheel_filename,
+                    blockwheel_filename: blockwheel_filename.clone(),
                     meister,
                 });
         }
@@ -0,0 +1,556 @@
+//! Length-prefixed binary protocol spoken by [`crate::server`].
+//!
+//! Every frame is a big-endian `u32` payload length followed by the payload.
+//! A payload starts with a big-endian `u64` request id chosen by the client
+//! and a one byte tag, responses carry the id of the request they answer, so
+//! a client may pipeline requests on one connection and the server may answer
+//! them out of order. A range lookup is answered with any number of
+//! [`Response::RangeItem`] frames terminated by [`Response::RangeEnd`] or
+//! [`Response::Error`].
+//!
+//! Byte strings are encoded as a big-endian `u32` length followed by the
+//! bytes, range bounds as a tag (`0` unbounded, `1` included, `2` excluded)
+//! followed by the key unless unbounded.
+
+use std::{
+    io,
+    ops::{
+        Bound,
+    },
+};
+
+use tokio::{
+    io::{
+        AsyncRead,
+        AsyncWrite,
+        AsyncReadExt,
+        AsyncWriteExt,
+    },
+};
+
+/// Frames bigger than this are rejected as malformed.
+pub const MAX_FRAME_SIZE: usize = 64 * 1024 * 1024;
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Request {
+    Info,
+    Insert { key: Vec<u8>, value: Vec<u8>, },
+    Lookup { key: Vec<u8>, },
+    LookupRange { range_from: Bound<Vec<u8>>, range_to: Bound<Vec<u8>>, },
+    Remove { key: Vec<u8>, },
+    FlushAll,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct RequestFrame {
+    pub request_id: u64,
+    pub request: Request,
+}
+
+/// Stored cell: `value` is `None` for a tombstone.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Cell {
+    pub version: u64,
+    pub value: Option<Vec<u8>>,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Response {
+    /// [`crate::StoreInfo::description`].
+    Info { description: String, },
+    Inserted { version: u64, },
+    Lookup { cell: Option<Cell>, },
+    RangeItem { key: Vec<u8>, cell: Cell, },
+    RangeEnd,
+    Removed { version: u64, },
+    Flushed,
+    Error(ErrorReply),
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum ErrorReply {
+    GenServer,
+    /// `blockwheel_kv::Error` in its debug representation.
+    Backend(String),
+    Timeout,
+    Dropped { attempts: u64, },
+    /// The request writes a key within [`crate::RESERVED_KEY_PREFIX`].
+    ReservedKey,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct ResponseFrame {
+    pub request_id: u64,
+    pub response: Response,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum DecodeError {
+    UnexpectedEnd,
+    TrailingBytes,
+    InvalidTag(u8),
+    InvalidBoundTag(u8),
+    InvalidUtf8,
+}
+
+const TAG_INFO: u8 = 1;
+const TAG_INSERT: u8 = 2;
+const TAG_LOOKUP: u8 = 3;
+const TAG_LOOKUP_RANGE: u8 = 4;
+const TAG_REMOVE: u8 = 5;
+const TAG_FLUSH_ALL: u8 = 6;
+
+const TAG_REPLY_INFO: u8 = 1;
+const TAG_REPLY_INSERTED: u8 = 2;
+const TAG_REPLY_LOOKUP: u8 = 3;
+const TAG_REPLY_RANGE_ITEM: u8 = 4;
+const TAG_REPLY_RANGE_END: u8 = 5;
+const TAG_REPLY_REMOVED: u8 = 6;
+const TAG_REPLY_FLUSHED: u8 = 7;
+const TAG_REPLY_ERROR_GEN_SERVER: u8 = 128;
+const TAG_REPLY_ERROR_BACKEND: u8 = 129;
+const TAG_REPLY_ERROR_TIMEOUT: u8 = 130;
+const TAG_REPLY_ERROR_DROPPED: u8 = 131;
+const TAG_REPLY_ERROR_RESERVED_KEY: u8 = 132;
+
+const BOUND_UNBOUNDED: u8 = 0;
+const BOUND_INCLUDED: u8 = 1;
+const BOUND_EXCLUDED: u8 = 2;
+
+impl RequestFrame {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut encoder = Encoder::new(self.request_id);
+        match &self.request {
+            Request::Info =>
+                encoder.put_u8(TAG_INFO),
+            Request::Insert { key, value, } => {
+                encoder.put_u8(TAG_INSERT);
+                encoder.put_bytes(key);
+                encoder.put_bytes(value);
+            },
+            Request::Lookup { key, } => {
+                encoder.put_u8(TAG_LOOKUP);
+                encoder.put_bytes(key);
+            },
+            Request::LookupRange { range_from, range_to, } => {
+                encoder.put_u8(TAG_LOOKUP_RANGE);
+                encoder.put_bound(range_from);
+                encoder.put_bound(range_to);
+            },
+            Request::Remove { key, } => {
+                encoder.put_u8(TAG_REMOVE);
+                encoder.put_bytes(key);
+            },
+            Request::FlushAll =>
+                encoder.put_u8(TAG_FLUSH_ALL),
+        }
+        encoder.payload
+    }
+
+    pub fn decode(payload: &[u8]) -> Result<RequestFrame, DecodeError> {
+        let mut decoder = Decoder { input: payload, };
+        let request_id = decoder.get_u64()?;
+        let request = match decoder.get_u8()? {
+            TAG_INFO =>
+                Request::Info,
+            TAG_INSERT => {
+                let key = decoder.get_bytes()?;
+                let value = decoder.get_bytes()?;
+                Request::Insert { key, value, }
+            },
+            TAG_LOOKUP =>
+                Request::Lookup { key: decoder.get_bytes()?, },
+            TAG_LOOKUP_RANGE => {
+                let range_from = decoder.get_bound()?;
+                let range_to = decoder.get_bound()?;
+                Request::LookupRange { range_from, range_to, }
+            },
+            TAG_REMOVE =>
+                Request::Remove { key: decoder.get_bytes()?, },
+            TAG_FLUSH_ALL =>
+                Request::FlushAll,
+            tag =>
+                return Err(DecodeError::InvalidTag(tag)),
+        };
+        decoder.finish()?;
+        Ok(RequestFrame { request_id, request, })
+    }
+}
+
+impl ResponseFrame {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut encoder = Encoder::new(self.request_id);
+        match &self.response {
+            Response::Info { description, } => {
+                encoder.put_u8(TAG_REPLY_INFO);
+                encoder.put_bytes(description.as_bytes());
+            },
+            Response::Inserted { version, } => {
+                encoder.put_u8(TAG_REPLY_INSERTED);
+                encoder.put_u64(*version);
+            },
+            Response::Lookup { cell, } => {
+                encoder.put_u8(TAG_REPLY_LOOKUP);
+                match cell {
+                    None =>
+                        encoder.put_u8(0),
+                    Some(cell) => {
+                        encoder.put_u8(1);
+                        encoder.put_cell(cell);
+                    },
+                }
+            },
+            Response::RangeItem { key, cell, } => {
+                encoder.put_u8(TAG_REPLY_RANGE_ITEM);
+                encoder.put_bytes(key);
+                encoder.put_cell(cell);
+            },
+            Response::RangeEnd =>
+                encoder.put_u8(TAG_REPLY_RANGE_END),
+            Response::Removed { version, } => {
+                encoder.put_u8(TAG_REPLY_REMOVED);
+                encoder.put_u64(*version);
+            },
+            Response::Flushed =>
+                encoder.put_u8(TAG_REPLY_FLUSHED),
+            Response::Error(ErrorReply::GenServer) =>
+                encoder.put_u8(TAG_REPLY_ERROR_GEN_SERVER),
+            Response::Error(ErrorReply::Backend(description)) => {
+                encoder.put_u8(TAG_REPLY_ERROR_BACKEND);
+                encoder.put_bytes(description.as_bytes());
+            },
+            Response::Error(ErrorReply::Timeout) =>
+                encoder.put_u8(TAG_REPLY_ERROR_TIMEOUT),
+            Response::Error(ErrorReply::Dropped { attempts, }) => {
+                encoder.put_u8(TAG_REPLY_ERROR_DROPPED);
+                encoder.put_u64(*attempts);
+            },
+            Response::Error(ErrorReply::ReservedKey) =>
+                encoder.put_u8(TAG_REPLY_ERROR_RESERVED_KEY),
+        }
+        encoder.payload
+    }
+
+    pub fn decode(payload: &[u8]) -> Result<ResponseFrame, DecodeError> {
+        let mut decoder = Decoder { input: payload, };
+        let request_id = decoder.get_u64()?;
+        let response = match decoder.get_u8()? {
+            TAG_REPLY_INFO =>
+                Response::Info { description: decoder.get_string()?, },
+            TAG_REPLY_INSERTED =>
+                Response::Inserted { version: decoder.get_u64()?, },
+            TAG_REPLY_LOOKUP =>
+                match decoder.get_u8()? {
+                    0 =>
+                        Response::Lookup { cell: None, },
+                    1 =>
+                        Response::Lookup { cell: Some(decoder.get_cell()?), },
+                    tag =>
+                        return Err(DecodeError::InvalidTag(tag)),
+                },
+            TAG_REPLY_RANGE_ITEM => {
+                let key = decoder.get_bytes()?;
+                let cell = decoder.get_cell()?;
+                Response::RangeItem { key, cell, }
+            },
+            TAG_REPLY_RANGE_END =>
+                Response::RangeEnd,
+            TAG_REPLY_REMOVED =>
+                Response::Removed { version: decoder.get_u64()?, },
+            TAG_REPLY_FLUSHED =>
+                Response::Flushed,
+            TAG_REPLY_ERROR_GEN_SERVER =>
+                Response::Error(ErrorReply::GenServer),
+            TAG_REPLY_ERROR_BACKEND =>
+                Response::Error(ErrorReply::Backend(decoder.get_string()?)),
+            TAG_REPLY_ERROR_TIMEOUT =>
+                Response::Error(ErrorReply::Timeout),
+            TAG_REPLY_ERROR_DROPPED =>
+                Response::Error(ErrorReply::Dropped { attempts: decoder.get_u64()?, }),
+            TAG_REPLY_ERROR_RESERVED_KEY =>
+                Response::Error(ErrorReply::ReservedKey),
+            tag =>
+                return Err(DecodeError::InvalidTag(tag)),
+        };
+        decoder.finish()?;
+        Ok(ResponseFrame { request_id, response, })
+    }
+}
+
+/// Reads one frame payload, `None` on a clean end of stream. A stream ending
+/// within a frame, its length prefix included, is an error.
+pub async fn read_frame<R>(reader: &mut R) -> io::Result<Option<Vec<u8>>> where R: AsyncRead + Unpin {
+    let mut length_bytes = [0; 4];
+    match reader.read_exact(&mut length_bytes[.. 1]).await {
+        Ok(_) =>
+            (),
+        Err(error) if error.kind() == io::ErrorKind::UnexpectedEof =>
+            return Ok(None),
+        Err(error) =>
+            return Err(error),
+    }
+    reader.read_exact(&mut length_bytes[1 ..]).await?;
+    let length = u32::from_be_bytes(length_bytes) as usize;
+    if length > MAX_FRAME_SIZE {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("frame of {length} bytes is too large")));
+    }
+    let mut payload = vec![0; length];
+    reader.read_exact(&mut payload).await?;
+    Ok(Some(payload))
+}
+
+pub async fn write_frame<W>(writer: &mut W, payload: &[u8]) -> io::Result<()> where W: AsyncWrite + Unpin {
+    if payload.len() > MAX_FRAME_SIZE {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("frame of {} bytes is too large", payload.len())));
+    }
+    writer.write_all(&(payload.len() as u32).to_be_bytes()).await?;
+    writer.write_all(payload).await
+}
+
+struct Encoder {
+    payload: Vec<u8>,
+}
+
+impl Encoder {
+    fn new(request_id: u64) -> Encoder {
+        let mut encoder = Encoder { payload: Vec::new(), };
+        encoder.put_u64(request_id);
+        encoder
+    }
+
+    fn put_u8(&mut self, value: u8) {
+        self.payload.push(value);
+    }
+
+    fn put_u64(&mut self, value: u64) {
+        self.payload.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn put_bytes(&mut self, bytes: &[u8]) {
+        self.payload.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+        self.payload.extend_from_slice(bytes);
+    }
+
+    fn put_bound(&mut self, bound: &Bound<Vec<u8>>) {
+        match bound {
+            Bound::Unbounded =>
+                self.put_u8(BOUND_UNBOUNDED),
+            Bound::Included(key) => {
+                self.put_u8(BOUND_INCLUDED);
+                self.put_bytes(key);
+            },
+            Bound::Excluded(key) => {
+                self.put_u8(BOUND_EXCLUDED);
+                self.put_bytes(key);
+            },
+        }
+    }
+
+    fn put_cell(&mut self, cell: &Cell) {
+        self.put_u64(cell.version);
+        match &cell.value {
+            None =>
+                self.put_u8(0),
+            Some(value) => {
+                self.put_u8(1);
+                self.put_bytes(value);
+            },
+        }
+    }
+}
+
+struct Decoder<'a> {
+    input: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+    fn take(&mut self, length: usize) -> Result<&'a [u8], DecodeError> {
+        if self.input.len() < length {
+            return Err(DecodeError::UnexpectedEnd);
+        }
+        let (head, tail) = self.input.split_at(length);
+        self.input = tail;
+        Ok(head)
+    }
+
+    fn get_u8(&mut self) -> Result<u8, DecodeError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn get_u32(&mut self) -> Result<u32, DecodeError> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn get_u64(&mut self) -> Result<u64, DecodeError> {
+        Ok(u64::from_be_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn get_bytes(&mut self) -> Result<Vec<u8>, DecodeError> {
+        let length = self.get_u32()? as usize;
+        Ok(self.take(length)?.to_vec())
+    }
+
+    fn get_string(&mut self) -> Result<String, DecodeError> {
+        String::from_utf8(self.get_bytes()?)
+            .map_err(|_error| DecodeError::InvalidUtf8)
+    }
+
+    fn get_bound(&mut self) -> Result<Bound<Vec<u8>>, DecodeError> {
+        match self.get_u8()? {
+            BOUND_UNBOUNDED =>
+                Ok(Bound::Unbounded),
+            BOUND_INCLUDED =>
+                Ok(Bound::Included(self.get_bytes()?)),
+            BOUND_EXCLUDED =>
+                Ok(Bound::Excluded(self.get_bytes()?)),
+            tag =>
+                Err(DecodeError::InvalidBoundTag(tag)),
+        }
+    }
+
+    fn get_cell(&mut self) -> Result<Cell, DecodeError> {
+        let version = self.get_u64()?;
+        let value = match self.get_u8()? {
+            0 =>
+                None,
+            1 =>
+                Some(self.get_bytes()?),
+            tag =>
+                return Err(DecodeError::InvalidTag(tag)),
+        };
+        Ok(Cell { version, value, })
+    }
+
+    fn finish(self) -> Result<(), DecodeError> {
+        if self.input.is_empty() {
+            Ok(())
+        } else {
+            Err(DecodeError::TrailingBytes)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io,
+        ops::{
+            Bound,
+        },
+    };
+
+    use super::{
+        read_frame,
+        write_frame,
+        Cell,
+        DecodeError,
+        ErrorReply,
+        Request,
+        RequestFrame,
+        Response,
+        ResponseFrame,
+        MAX_FRAME_SIZE,
+    };
+
+    fn roundtrip_request(request: Request) {
+        let request_frame = RequestFrame { request_id: 0x0102_0304_0506_0708, request, };
+        assert_eq!(RequestFrame::decode(&request_frame.encode()), Ok(request_frame));
+    }
+
+    fn roundtrip_response(response: Response) {
+        let response_frame = ResponseFrame { request_id: u64::MAX, response, };
+        assert_eq!(ResponseFrame::decode(&response_frame.encode()), Ok(response_frame));
+    }
+
+    #[test]
+    fn requests_roundtrip() {
+        roundtrip_request(Request::Info);
+        roundtrip_request(Request::Insert { key: b"key".to_vec(), value: vec![0x00, 0xff], });
+        roundtrip_request(Request::Insert { key: vec![], value: vec![], });
+        roundtrip_request(Request::Lookup { key: b"key".to_vec(), });
+        roundtrip_request(Request::LookupRange { range_from: Bound::Unbounded, range_to: Bound::Unbounded, });
+        roundtrip_request(Request::LookupRange {
+            range_from: Bound::Included(b"a".to_vec()),
+            range_to: Bound::Excluded(b"b".to_vec()),
+        });
+        roundtrip_request(Request::Remove { key: b"key".to_vec(), });
+        roundtrip_request(Request::FlushAll);
+    }
+
+    #[test]
+    fn responses_roundtrip() {
+        roundtrip_response(Response::Info { description: "Info { .. }".to_string(), });
+        roundtrip_response(Response::Inserted { version: 17, });
+        roundtrip_response(Response::Lookup { cell: None, });
+        roundtrip_response(Response::Lookup { cell: Some(Cell { version: 1, value: Some(b"value".to_vec()), }), });
+        roundtrip_response(Response::Lookup { cell: Some(Cell { version: 2, value: None, }), });
+        roundtrip_response(Response::RangeItem { key: b"key".to_vec(), cell: Cell { version: 3, value: Some(vec![]), }, });
+        roundtrip_response(Response::RangeEnd);
+        roundtrip_response(Response::Removed { version: 4, });
+        roundtrip_response(Response::Flushed);
+        roundtrip_response(Response::Error(ErrorReply::GenServer));
+        roundtrip_response(Response::Error(ErrorReply::Backend("Error".to_string())));
+        roundtrip_response(Response::Error(ErrorReply::Timeout));
+        roundtrip_response(Response::Error(ErrorReply::Dropped { attempts: 3, }));
+        roundtrip_response(Response::Error(ErrorReply::ReservedKey));
+    }
+
+    #[test]
+    fn malformed_frames_are_rejected() {
+        let payload = RequestFrame { request_id: 1, request: Request::Lookup { key: b"key".to_vec(), }, }.encode();
+        assert_eq!(RequestFrame::decode(&payload[.. payload.len() - 1]), Err(DecodeError::UnexpectedEnd));
+        assert_eq!(RequestFrame::decode(&payload[.. 4]), Err(DecodeError::UnexpectedEnd));
+
+        let mut trailing = payload.clone();
+        trailing.push(0);
+        assert_eq!(RequestFrame::decode(&trailing), Err(DecodeError::TrailingBytes));
+
+        let mut invalid_tag = payload;
+        invalid_tag[8] = 0xee;
+        assert_eq!(RequestFrame::decode(&invalid_tag), Err(DecodeError::InvalidTag(0xee)));
+
+        let mut invalid_bound = RequestFrame {
+            request_id: 1,
+            request: Request::LookupRange { range_from: Bound::Unbounded, range_to: Bound::Unbounded, },
+        }.encode();
+        invalid_bound[9] = 3;
+        assert_eq!(RequestFrame::decode(&invalid_bound), Err(DecodeError::InvalidBoundTag(3)));
+
+        let mut invalid_utf8 = ResponseFrame { request_id: 1, response: Response::Info { description: "a".to_string(), }, }.encode();
+        *invalid_utf8.last_mut().unwrap() = 0xff;
+        assert_eq!(ResponseFrame::decode(&invalid_utf8), Err(DecodeError::InvalidUtf8));
+    }
+
+    #[tokio::test]
+    async fn frames_roundtrip() {
+        let mut stream = Vec::new();
+        write_frame(&mut stream, b"first").await.unwrap();
+        write_frame(&mut stream, b"").await.unwrap();
+        assert_eq!(&stream[.. 4], &5_u32.to_be_bytes());
+
+        let mut reader = &stream[..];
+        assert_eq!(read_frame(&mut reader).await.unwrap(), Some(b"first".to_vec()));
+        assert_eq!(read_frame(&mut reader).await.unwrap(), Some(vec![]));
+        assert_eq!(read_frame(&mut reader).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn oversized_frames_are_rejected() {
+        let length = (MAX_FRAME_SIZE as u32 + 1).to_be_bytes();
+        let mut reader = &length[..];
+        assert!(read_frame(&mut reader).await.is_err());
+
+        let mut truncated = &[0, 0, 0, 8, 1, 2][..];
+        assert!(read_frame(&mut truncated).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn truncated_length_prefix_is_an_error() {
+        let mut truncated = &[0, 0][..];
+        let error = read_frame(&mut truncated).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+
+        let mut empty = &[][..];
+        assert_eq!(read_frame(&mut empty).await.unwrap(), None);
+    }
+}